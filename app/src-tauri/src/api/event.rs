@@ -1,22 +1,36 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use async_openai::{
-    Client,
-    config::OpenAIConfig,
-    types::{ChatCompletionRequestSystemMessageArgs, CreateChatCompletionRequestArgs},
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionToolChoiceOption, CreateChatCompletionRequestArgs,
 };
 
+use serde::Deserialize;
 use serde_json::json;
 use tokio::sync::{RwLock, mpsc, watch};
 
-use store::ChatMessage;
+use store::{ChatMessage, MessageStatus};
 
 use crate::{
-    AppState, error,
+    AppState,
+    api::{
+        queue::{QueueEntry, QueueSlot},
+        reasoning, title,
+    },
+    error,
     openai::{
         self,
         chat::{self, MessageEvent},
+        metrics::ReplyMetrics,
+        prompt,
+        structured::{self, Structured},
         tool::{Search, Tool},
+        tool_prompt,
     },
 };
 
@@ -26,34 +40,35 @@ pub struct MessageTask {
 
 pub type MessageTasks = RwLock<HashMap<u64, MessageTask>>;
 
-pub async fn event(
-    app: tauri::State<'_, AppState>, message: ChatMessage, search: bool, time: bool, stream: bool,
-    on_event: tauri::ipc::Channel<MessageEvent>,
-) -> Result<serde_json::Value, error::Error> {
-    let session = app.store.get_chat_session(message.session_id)?.ok_or_else(|| {
-        error::Error::InvalidData(format!("Session with id {} not found", message.id))
-    })?;
-
-    let agent = app.get_agent(session.agent_id).await?;
+/// 自动生成会话标题的超时
+const TITLE_TIMEOUT: Duration = Duration::from_secs(60);
 
-    let Some(model) = agent.model.as_ref() else {
-        return Err(error::Error::InvalidData(format!("Model with {:?} not found", agent.model)));
-    };
+/// 要求模型继续被截断的回复
+const CONTINUE_PROMPT: &str = "Your previous reply was cut off. Continue exactly where it stopped, \
+                               without repeating what you have already written.";
 
-    let provider = app.get_provider(model.id).await?;
+/// 会话输入选项
+#[derive(Debug, Clone, Copy)]
+pub struct EventOptions {
+    pub search: bool,
+    pub time: bool,
+    pub stream: bool,
+}
 
+/// 接收消息事件, 保存助手消息并转发给前端
+fn spawn_event_task(
+    task_store: store::Store, on_event: tauri::ipc::Channel<MessageEvent>,
+) -> mpsc::Sender<MessageEvent> {
     let (sender_event, mut receiver_event) = mpsc::channel::<MessageEvent>(32);
-    let task_store = app.store.clone();
     tokio::spawn(async move {
         let mut assistant: Option<ChatMessage> = None;
         while let Some(event) = receiver_event.recv().await {
             // tracing::info!("Event: {:?}", event);
 
             match &event {
-                MessageEvent::AssistantMessage { message } => {
-                    assistant = task_store.add_chat_message(message.clone()).ok();
-                }
-                MessageEvent::RetryAssistantMessage { message } => {
+                MessageEvent::AssistantMessage { message }
+                | MessageEvent::RetryAssistantMessage { message }
+                | MessageEvent::ContinueAssistantMessage { message } => {
                     assistant = Some(message.clone());
                 }
                 MessageEvent::ReasoningContent { content } => {
@@ -73,33 +88,81 @@ pub async fn event(
                         assistant.content.push_str(content.as_str());
                     }
                 }
-                MessageEvent::Tool { id, name, arguments, result } => {
+                MessageEvent::ContentReset { .. } => {
+                    if let Some(assistant) = assistant.as_mut() {
+                        assistant.content.clear();
+                    }
+                }
+                MessageEvent::Truncated { reason } => {
+                    if let Some(assistant) = assistant.as_mut() {
+                        assistant.finish_reason = Some(*reason);
+                    }
+                }
+                MessageEvent::Structured { output } => {
+                    if let Some(assistant) = assistant.as_mut() {
+                        assistant.structured = Some(output.clone());
+                    }
+                }
+                MessageEvent::Tool { id, name, arguments, result, cost } => {
                     if let Some(assistant) = assistant.as_mut() {
                         let tool = store::ToolResult {
                             id: id.clone(),
                             name: name.clone(),
                             arguments: arguments.clone(),
                             result: result.clone(),
+                            status: store::ToolStatus::Success,
+                            error: None,
+                            cost: Some(*cost),
                         };
-
-                        match assistant.tools {
-                            Some(ref mut tools) => {
-                                tools.push(tool);
-                            }
-                            None => {
-                                assistant.tools = Some(vec![tool]);
-                            }
-                        }
+                        assistant.tools.get_or_insert_default().push(tool);
                     }
                 }
-                MessageEvent::Finished { cost, prompt_tokens, completion_tokens, total_tokens } => {
+                MessageEvent::ToolError { id, name, arguments, error, cost } => {
                     if let Some(assistant) = assistant.as_mut() {
-                        assistant.cost = Some(*cost);
-                        assistant.prompt_tokens = Some(*prompt_tokens);
-                        assistant.completion_tokens = Some(*completion_tokens);
-                        assistant.total_tokens = Some(*total_tokens);
-                        assistant.status = store::MessageStatus::Success;
-                        if let Err(e) = task_store.update_chat_message(assistant.clone()) {
+                        let tool = store::ToolResult {
+                            id: id.clone(),
+                            name: name.clone(),
+                            arguments: arguments.clone(),
+                            result: String::new(),
+                            status: store::ToolStatus::Failed,
+                            error: Some(error.clone()),
+                            cost: Some(*cost),
+                        };
+                        assistant.tools.get_or_insert_default().push(tool);
+                    }
+                }
+                MessageEvent::Finished {
+                    cost,
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens,
+                    fee,
+                    first_token,
+                    first_content,
+                    tokens_per_second,
+                    status,
+                    error,
+                } => {
+                    // 继续生成时在已有的统计上累加
+                    if let Some(mut assistant) = assistant.take() {
+                        assistant.fee = match (assistant.fee, *fee) {
+                            (Some(previous), Some(fee)) => Some(previous + fee),
+                            (previous, fee) => fee.or(previous),
+                        };
+                        assistant.first_token = assistant.first_token.or(*first_token);
+                        assistant.first_content = assistant.first_content.or(*first_content);
+                        assistant.tokens_per_second = *tokens_per_second;
+                        assistant.cost = Some(assistant.cost.unwrap_or_default() + cost);
+                        assistant.prompt_tokens =
+                            Some(assistant.prompt_tokens.unwrap_or_default() + prompt_tokens);
+                        assistant.completion_tokens = Some(
+                            assistant.completion_tokens.unwrap_or_default() + completion_tokens,
+                        );
+                        assistant.total_tokens =
+                            Some(assistant.total_tokens.unwrap_or_default() + total_tokens);
+                        assistant.status = status.clone();
+                        assistant.error = error.clone();
+                        if let Err(e) = task_store.update_chat_message(assistant) {
                             tracing::error!("Error updating message: {:?}", e);
                         }
                     }
@@ -111,21 +174,78 @@ pub async fn event(
                 tracing::error!("Error sending event: {:?}", e);
             }
         }
+
+        // 没有收到结束事件, 保存已经生成的内容
+        if let Some(mut assistant) = assistant {
+            assistant.status = MessageStatus::Failed;
+            assistant.error = Some("reply interrupted".to_string());
+            if let Err(e) = task_store.update_chat_message(assistant) {
+                tracing::error!("Error updating message: {:?}", e);
+            }
+        }
         // tracing::info!("Event task {} exit", message_id);
     });
+    sender_event
+}
+
+pub async fn event(
+    app: tauri::State<'_, AppState>, message: ChatMessage, options: EventOptions,
+    on_event: tauri::ipc::Channel<MessageEvent>,
+) -> Result<serde_json::Value, error::Error> {
+    let session = app.store.get_chat_session(message.session_id)?.ok_or_else(|| {
+        error::Error::InvalidData(format!("Session with id {} not found", message.id))
+    })?;
+
+    let agent = app.get_agent(session.agent_id).await?;
 
-    let (message, histroy) = if message.id == 0 {
-        let histroy = app
-            .store
-            .get_latest_messages_by_session(message.session_id, agent.context_size as usize * 2)?;
+    let Some(model) = agent.model.as_ref() else {
+        return Err(error::Error::InvalidData(format!("Model with {:?} not found", agent.model)));
+    };
+
+    let provider = app.get_provider(model.id).await?;
+
+    let sender_event = spawn_event_task(app.store.clone(), on_event);
+
+    let is_new = message.id == 0;
+    // 新消息先保存, 排队和取消都以用户消息ID标识
+    let message = if is_new { app.store.add_chat_message(message)? } else { message };
+
+    let mut slot = match app.queues.enter(QueueEntry::new(&message), queue_policy(&app)) {
+        Ok(slot) => slot,
+        Err(e) => {
+            if is_new {
+                app.store.delete_chat_message(message.id)?;
+            }
+            return Err(e);
+        }
+    };
 
-        let message = app.store.add_chat_message(message)?;
+    if is_new {
         sender_event
             .send(MessageEvent::UserMessage { message: message.clone() })
             .await
             .map_err(|_| error::Error::Unknown)?;
+    }
+
+    if !wait_queue(&mut slot, &sender_event).await? {
+        if is_new {
+            app.store.delete_chat_message(message.id)?;
+        }
+        return Ok(serde_json::json!({ "status": "cancelled" }));
+    }
 
-        let assistant = ChatMessage::new_assistant(message.id + 1, message.session_id);
+    let (message, histroy) = if is_new {
+        // 等待完成后再接到当前分支并读取上下文, 包括前面刚生成的回复
+        let (message, histroy) =
+            app.store.attach_chat_message(message.id, agent.context_size as usize * 2)?;
+
+        // 回复的ID由存储分配, 避免与其他会话同时保存的消息冲突
+        let mut assistant = ChatMessage::new_assistant(0, message.session_id);
+        assistant.parent_id = Some(message.id);
+        assistant.agent_id = Some(agent.id);
+        assistant.model = agent.model.clone();
+        let assistant = app.store.add_chat_message(assistant)?;
+        app.store.set_active_leaf(message.session_id, assistant.id)?;
         sender_event
             .send(MessageEvent::AssistantMessage { message: assistant })
             .await
@@ -133,23 +253,32 @@ pub async fn event(
 
         (message, histroy)
     } else {
-        let mut histroy = app.store.get_latest_messages_by_session_and_message(
-            message.session_id,
-            message.id + 1,
-            agent.context_size as usize * 2 + 2,
-        )?;
+        // 重新生成的回复所在的分支作为上下文, 并切换到该分支
+        let Some(reply) = app.store.get_message_reply(message.id)? else {
+            return Err(error::Error::InvalidData(format!(
+                "Message Assistant replying to {} not found",
+                message.id
+            )));
+        };
+        let mut histroy = app.store.get_message_path(message.session_id, reply.id)?;
+        histroy.drain(..histroy.len().saturating_sub(agent.context_size as usize * 2 + 2));
+        app.store.set_active_leaf(message.session_id, reply.id)?;
         // tracing::info!("histroy: {:?}", histroy);
 
         let Some(assistant) = histroy.pop() else {
             return Err(error::Error::InvalidData(format!(
                 "Message Assistant {} with id {} not found",
                 histroy.len(),
-                message.id - 1
+                reply.id
             )));
         };
-        let assistant = ChatMessage::new_assistant(assistant.id, assistant.session_id);
+        let mut retry = ChatMessage::new_assistant(assistant.id, assistant.session_id);
+        retry.parent_id = assistant.parent_id;
+        retry.selected = assistant.selected;
+        retry.agent_id = Some(agent.id);
+        retry.model = agent.model.clone();
         sender_event
-            .send(MessageEvent::RetryAssistantMessage { message: assistant })
+            .send(MessageEvent::RetryAssistantMessage { message: retry })
             .await
             .map_err(|_| error::Error::Unknown)?;
 
@@ -157,22 +286,462 @@ pub async fn event(
             return Err(error::Error::InvalidData(format!(
                 "Message User {} with id {} not found",
                 histroy.len(),
-                message.id - 1
+                message.id
             )));
         };
         (message, histroy)
     };
 
     let message_id = message.id;
+    let first_exchange = is_new && histroy.is_empty();
+
+    let (sender_exit, receiver_exit) = watch::channel(());
+    app.tasks.write().await.insert(message_id, MessageTask { exit: sender_exit });
+
+    let result = run_reply(
+        &app,
+        &session,
+        &agent,
+        &provider,
+        message,
+        histroy,
+        options,
+        None,
+        sender_event.clone(),
+        receiver_exit,
+    )
+    .await;
+
+    app.tasks.write().await.remove(&message_id);
+    drop(slot);
+
+    // 第一轮对话完成后在后台生成会话标题, 回复已经结束, 事件通道不会占满
+    if first_exchange && result.is_ok() {
+        auto_title(&app, session.id, move |session| {
+            let _ = sender_event.try_send(MessageEvent::SessionUpdated { session });
+        })
+        .await;
+    }
+
+    result.map(|_| {
+        serde_json::json!({
+            "status": "success"
+        })
+    })
+}
+
+/// 继续生成被截断的助手回复, 新内容追加到同一条消息
+pub async fn event_continue(
+    app: tauri::State<'_, AppState>, message: u64, options: EventOptions,
+    on_event: tauri::ipc::Channel<MessageEvent>,
+) -> Result<serde_json::Value, error::Error> {
+    let mut assistant = app.store.get_chat_message(message)?.ok_or_else(|| {
+        error::Error::InvalidData(format!("Message with id {} not found", message))
+    })?;
+    if assistant.role != store::Role::Assistant {
+        return Err(error::Error::InvalidData(format!(
+            "Message with id {} is not an assistant message",
+            message
+        )));
+    }
+
+    let session = app.store.get_chat_session(assistant.session_id)?.ok_or_else(|| {
+        error::Error::InvalidData(format!("Session with id {} not found", assistant.session_id))
+    })?;
+
+    // 使用生成这条回复的智能体和模型
+    let mut agent =
+        store::Agent::clone(&*app.get_agent(assistant.agent_id.unwrap_or(session.agent_id)).await?);
+    if let Some(model) = assistant.model.clone() {
+        agent.model = Some(model);
+    }
+    let Some(model) = agent.model.as_ref() else {
+        return Err(error::Error::InvalidData(format!("Model with {:?} not found", agent.model)));
+    };
+    let provider = app.get_provider(model.id).await?;
+
+    let user_id = assistant.parent_id.unwrap_or(assistant.id - 1);
+    let mut histroy = app.store.get_message_path(session.id, user_id)?;
+    histroy.drain(..histroy.len().saturating_sub(agent.context_size as usize * 2 + 1));
+
+    let Some(user) = histroy.pop().filter(|m| m.id == user_id) else {
+        return Err(error::Error::InvalidData(format!(
+            "Message User with id {} not found",
+            user_id
+        )));
+    };
+
+    let mut slot = app.queues.enter(QueueEntry::new(&user), queue_policy(&app))?;
+    let sender_event = spawn_event_task(app.store.clone(), on_event);
+    if !wait_queue(&mut slot, &sender_event).await? {
+        return Ok(serde_json::json!({ "status": "cancelled" }));
+    }
+
+    let resume = assistant.content.clone();
+    assistant.status = MessageStatus::Sending;
+    assistant.error = None;
+    assistant.finish_reason = None;
+    sender_event
+        .send(MessageEvent::ContinueAssistantMessage { message: assistant })
+        .await
+        .map_err(|_| error::Error::Unknown)?;
+
+    let (sender_exit, receiver_exit) = watch::channel(());
+    app.tasks.write().await.insert(user_id, MessageTask { exit: sender_exit });
+
+    // 不重复联网搜索, 继续时只需要已有的上下文
+    let options = EventOptions { search: false, ..options };
+    let result = run_reply(
+        &app,
+        &session,
+        &agent,
+        &provider,
+        user,
+        histroy,
+        options,
+        Some(resume),
+        sender_event,
+        receiver_exit,
+    )
+    .await;
+
+    app.tasks.write().await.remove(&user_id);
+    drop(slot);
+
+    result.map(|_| {
+        serde_json::json!({
+            "status": "success"
+        })
+    })
+}
+
+/// 对比回复的目标, 指定智能体或者模型, 都不指定时使用会话的智能体
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompareTarget {
+    #[serde(rename = "agentId")]
+    pub agent_id: Option<u64>,
+    pub model: Option<store::ProviderModel>,
+}
+
+/// 同一个问题同时发给多个智能体或模型, 每条回复通过各自的通道返回
+pub async fn event_compare(
+    app: tauri::State<'_, AppState>, message: ChatMessage, targets: Vec<CompareTarget>,
+    options: EventOptions, on_event: tauri::ipc::Channel<MessageEvent>,
+    on_replies: Vec<tauri::ipc::Channel<MessageEvent>>,
+) -> Result<serde_json::Value, error::Error> {
+    if message.id != 0 {
+        return Err(error::Error::InvalidData("compare only supports new messages".to_string()));
+    }
+    if targets.is_empty() || targets.len() != on_replies.len() {
+        return Err(error::Error::InvalidData(format!(
+            "compare needs one channel per target, got {} targets and {} channels",
+            targets.len(),
+            on_replies.len()
+        )));
+    }
+
+    let session = app.store.get_chat_session(message.session_id)?.ok_or_else(|| {
+        error::Error::InvalidData(format!("Session with id {} not found", message.session_id))
+    })?;
+
+    // 先确认所有目标都可用, 再保存用户消息
+    let mut replies = Vec::with_capacity(targets.len());
+    for target in targets {
+        let mut agent = store::Agent::clone(
+            &*app.get_agent(target.agent_id.unwrap_or(session.agent_id)).await?,
+        );
+        if let Some(model) = target.model {
+            agent.model = Some(model);
+        }
+
+        let Some(model) = agent.model.as_ref() else {
+            return Err(error::Error::InvalidData(format!(
+                "Model with {:?} not found",
+                agent.model
+            )));
+        };
+        let provider = app.get_provider(model.id).await?;
+        replies.push((agent, provider));
+    }
+
+    let message = app.store.add_chat_message(message)?;
+    let mut slot = match app.queues.enter(QueueEntry::new(&message), queue_policy(&app)) {
+        Ok(slot) => slot,
+        Err(e) => {
+            app.store.delete_chat_message(message.id)?;
+            return Err(e);
+        }
+    };
+
+    on_event
+        .send(MessageEvent::UserMessage { message: message.clone() })
+        .map_err(|_| error::Error::Unknown)?;
+
+    if slot.position() > 0 {
+        on_event
+            .send(MessageEvent::Queued { position: slot.position() })
+            .map_err(|_| error::Error::Unknown)?;
+        if slot.wait().await.is_err() {
+            app.store.delete_chat_message(message.id)?;
+            return Ok(serde_json::json!({ "status": "cancelled" }));
+        }
+    }
+
+    // 等待完成后再接到当前分支并读取上下文, 继续对话默认接在第一条回复之后
+    let limit = replies.iter().map(|(agent, _)| agent.context_size as usize * 2).max();
+    let (message, path) = app.store.attach_chat_message(message.id, limit.unwrap_or_default())?;
+
+    // 回复的ID由存储分配, 默认选中第一条继续对话
+    let mut assistants: Vec<ChatMessage> = Vec::with_capacity(replies.len());
+    for (index, (agent, _)) in replies.iter().enumerate() {
+        let mut assistant = ChatMessage::new_assistant(0, message.session_id);
+        assistant.parent_id = Some(message.id);
+        assistant.agent_id = Some(agent.id);
+        assistant.model = agent.model.clone();
+        assistant.selected = Some(index == 0);
+        match app.store.add_chat_message(assistant) {
+            Ok(assistant) => assistants.push(assistant),
+            Err(e) => {
+                for assistant in &assistants {
+                    app.store.delete_chat_message(assistant.id)?;
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    app.store.set_active_leaf(message.session_id, assistants[0].id)?;
+
+    let first_exchange = path.is_empty();
+    let contexts = replies.into_iter().map(|(agent, provider)| {
+        let limit = agent.context_size as usize * 2;
+        let histroy = path[path.len().saturating_sub(limit)..].to_vec();
+        (agent, provider, histroy)
+    });
+
+    let message_id = message.id;
+
+    let (sender_exit, receiver_exit) = watch::channel(());
+    app.tasks.write().await.insert(message_id, MessageTask { exit: sender_exit });
+
+    let app = &*app;
+    let session = &session;
+    let tasks = contexts.zip(on_replies).zip(assistants).map(
+        |(((agent, provider, histroy), on_reply), assistant)| {
+            let message = message.clone();
+            let receiver_exit = receiver_exit.clone();
+            async move {
+                let sender_event = spawn_event_task(app.store.clone(), on_reply);
+                sender_event
+                    .send(MessageEvent::AssistantMessage { message: assistant })
+                    .await
+                    .map_err(|_| error::Error::Unknown)?;
+
+                run_reply(
+                    app,
+                    session,
+                    &agent,
+                    &provider,
+                    message,
+                    histroy,
+                    options,
+                    None,
+                    sender_event,
+                    receiver_exit,
+                )
+                .await
+            }
+        },
+    );
+
+    let results = futures::future::join_all(tasks).await;
+
+    app.tasks.write().await.remove(&message_id);
+    drop(slot);
+
+    if first_exchange && results.iter().any(|r| r.is_ok()) {
+        auto_title(app, session.id, move |session| {
+            let _ = on_event.send(MessageEvent::SessionUpdated { session });
+        })
+        .await;
+    }
+
+    // 每条回复的错误已经通过各自的结束事件返回, 全部失败时才返回错误
+    results.into_iter().reduce(Result::or).unwrap_or(Ok(())).map(|_| {
+        serde_json::json!({
+            "status": "success"
+        })
+    })
+}
+
+/// 读取设置中的排队策略, 没有设置时排队等待
+fn queue_policy(app: &AppState) -> store::QueuePolicy {
+    app.store.get_settings().map(|settings| settings.queue_policy).unwrap_or_default()
+}
+
+/// 排队等待前面的回复完成, 排队中被取消时返回 false
+async fn wait_queue(
+    slot: &mut QueueSlot<'_>, sender_event: &mpsc::Sender<MessageEvent>,
+) -> Result<bool, error::Error> {
+    if slot.position() == 0 {
+        return Ok(true);
+    }
+    sender_event
+        .send(MessageEvent::Queued { position: slot.position() })
+        .await
+        .map_err(|_| error::Error::Unknown)?;
+    Ok(slot.wait().await.is_ok())
+}
+
+/// 第一轮对话完成后, 使用设置的模型在后台生成会话标题, 更新后通过 on_updated 通知
+///
+/// 没有设置模型或者用户已经修改过标题时跳过, 生成期间用户修改的标题也不会被覆盖
+async fn auto_title(
+    app: &AppState, session_id: u64, on_updated: impl FnOnce(store::ChatSession) + Send + 'static,
+) {
+    let Ok(model) = title::title_model(app) else {
+        return;
+    };
+    let edited =
+        app.store.get_chat_session(session_id).ok().flatten().is_none_or(|s| s.topic_edited);
+    if edited {
+        return;
+    }
+    let provider = match app.get_provider(model.id).await {
+        Ok(provider) => provider,
+        Err(e) => {
+            tracing::error!("Generate title for session {session_id} error: {e}");
+            return;
+        }
+    };
+
+    let store = app.store.clone();
+    tokio::spawn(async move {
+        let generate = title::generate(&store, &provider, &model, session_id);
+        let result = match tokio::time::timeout(TITLE_TIMEOUT, generate).await {
+            Ok(title) => {
+                title.and_then(|title| Ok(store.set_generated_topic(session_id, title, true)?))
+            }
+            Err(_) => Err(error::Error::Timeout(format!(
+                "title not generated within {}s",
+                TITLE_TIMEOUT.as_secs()
+            ))),
+        };
+        match result {
+            Ok(Some(session)) => on_updated(session),
+            Ok(None) => {}
+            Err(e) => tracing::error!("Generate title for session {session_id} error: {e}"),
+        }
+    });
+}
+
+/// 运行一条回复直到完成或被取消, 最后发送带状态的结束事件
+///
+/// resume 为被截断的回复内容, 设置时要求模型从截断处继续
+#[allow(clippy::too_many_arguments)]
+async fn run_reply(
+    app: &AppState, session: &store::ChatSession, agent: &store::Agent, provider: &store::Provider,
+    message: ChatMessage, histroy: Vec<ChatMessage>, options: EventOptions, resume: Option<String>,
+    sender_event: mpsc::Sender<MessageEvent>, mut receiver_exit: watch::Receiver<()>,
+) -> Result<(), error::Error> {
+    let message_id = message.id;
+
+    let mut metrics = ReplyMetrics::default();
+    let cost = std::time::Instant::now();
+
+    let task = reply(
+        app,
+        session,
+        agent,
+        provider,
+        message,
+        histroy,
+        options,
+        resume,
+        &sender_event,
+        &mut metrics,
+    );
+
+    // None 表示被用户取消
+    let result = tokio::select! {
+        _ = receiver_exit.changed() => {
+            tracing::info!("Message task {} exit", message_id);
+            None
+        }
+        result = task => {
+            tracing::info!("Message task {} finished {result:?}", message_id);
+            Some(result)
+        }
+    };
+
+    let (prompt_tokens, cached_tokens, completion_tokens, total_tokens) =
+        metrics.usages.iter().fold((0, 0, 0, 0), |(prompt, cached, completion, total), usage| {
+            let cached_tokens = usage
+                .prompt_tokens_details
+                .as_ref()
+                .and_then(|details| details.cached_tokens)
+                .unwrap_or_default();
+            (
+                prompt + usage.prompt_tokens,
+                cached + cached_tokens,
+                completion + usage.completion_tokens,
+                total + usage.total_tokens,
+            )
+        });
+
+    // 按模型价格计算费用, 包括工具调用的各轮请求
+    let fee = agent
+        .model
+        .as_ref()
+        .and_then(|model| provider.model(&model.name))
+        .and_then(|model| model.price.as_ref())
+        .map(|price| price.fee(prompt_tokens, cached_tokens, completion_tokens));
+
+    let (status, error) = match &result {
+        None => (MessageStatus::Cancelled, None),
+        Some(Ok(())) => (MessageStatus::Success, None),
+        Some(Err(e)) if e.is_timeout() => (MessageStatus::Timeout, Some(e.to_string())),
+        Some(Err(e)) => (MessageStatus::Failed, Some(e.to_string())),
+    };
+
+    let finised = MessageEvent::Finished {
+        cost: cost.elapsed().as_millis() as i64,
+        prompt_tokens,
+        completion_tokens,
+        total_tokens,
+        fee,
+        first_token: metrics.first_token_ms(),
+        first_content: metrics.first_content_ms(),
+        tokens_per_second: metrics.tokens_per_second(completion_tokens),
+        status,
+        error,
+    };
+
+    sender_event.send(finised).await.map_err(|_| error::Error::Unknown)?;
+
+    result.unwrap_or(Ok(()))
+}
+
+/// 生成一条助手回复, 包括联网搜索和多轮工具调用
+#[allow(clippy::too_many_arguments)]
+async fn reply(
+    app: &AppState, session: &store::ChatSession, agent: &store::Agent, provider: &store::Provider,
+    message: ChatMessage, histroy: Vec<ChatMessage>, options: EventOptions, resume: Option<String>,
+    sender_event: &mpsc::Sender<MessageEvent>, metrics: &mut ReplyMetrics,
+) -> Result<(), error::Error> {
+    let Some(model) = agent.model.as_ref() else {
+        return Err(error::Error::InvalidData(format!("Model with {:?} not found", agent.model)));
+    };
+
     let mut message = openai::Message::new_user(message)?;
 
-    let mut search = if search { Some(app.store.get_settings()?.search) } else { None };
+    let mut search = if options.search { Some(app.store.get_settings()?.search) } else { None };
 
     // 先联网搜索
     if let Some(search) = search.take_if(|v| v.mode == 1) {
         let search = app.get_search_tool_object(search).await?;
 
         let query = json!({"query": message.content.clone()});
+        let start = std::time::Instant::now();
         let search = search.call("search", query.clone()).await?;
 
         sender_event
@@ -181,6 +750,7 @@ pub async fn event(
                 name: "web search".to_string(),
                 arguments: query.to_string(),
                 result: search.to_string(),
+                cost: start.elapsed().as_millis() as i64,
             })
             .await
             .map_err(|_| error::Error::Unknown)?;
@@ -189,19 +759,14 @@ pub async fn event(
     }
 
     // 上下文附加当前时间
-    if time {
+    if options.time {
         message.content =
             format!("{}\n\ncurrent utc time:{}\n\n", message.content, time::UtcDateTime::now());
         // tracing::info!("Message: {:?}", message.content);
     }
 
-    let config = OpenAIConfig::new()
-        .with_api_base(provider.url.clone())
-        .with_api_key(provider.api_key.as_ref().map(|key| key.to_string()).unwrap_or_default());
-    let client = Client::with_config(config);
-
     let mut tool_objects = Vec::new();
-    for id in agent.tools.clone().into_iter().map(|tool| tool.into_iter()).flatten() {
+    for id in agent.tools.clone().into_iter().flatten() {
         tool_objects.push(app.get_tool_object(id).await?);
     }
 
@@ -220,113 +785,226 @@ pub async fn event(
             .collect::<Result<Vec<_>, _>>()?;
         tools.extend(tool);
     }
-    let tool_objects = Arc::new(tool_objects);
 
-    let mut messages = vec![
-        ChatCompletionRequestSystemMessageArgs::default()
-            .content(agent.prompt.clone())
-            .build()?
-            .into(),
-    ];
+    let structured = agent.response_schema.as_ref().map(Structured::new).transpose()?;
+
+    // 模型支持时通过 response_format 约束, 否则通过系统提示词约束
+    let response_format = structured
+        .as_ref()
+        .filter(|_| {
+            provider
+                .model(&model.name)
+                .is_some_and(|m| m.has_tag(store::MODEL_TAG_STRUCTURED_OUTPUT))
+        })
+        .map(Structured::response_format);
+
+    // 模型不支持原生函数调用时, 通过系统提示词描述工具
+    let prompt_tools = !tools.is_empty()
+        && provider.model(&model.name).is_some_and(|m| m.has_tag(store::MODEL_TAG_PROMPT_TOOLS));
+
+    let mut prompt = prompt::render(agent, session)?;
+    if let (Some(structured), None) = (&structured, &response_format) {
+        prompt = format!("{prompt}\n\n{}", structured.prompt());
+    }
+    // 达到工具调用限制或修正结构化输出时, 换回不含工具描述的系统提示词
+    let base_system: ChatCompletionRequestMessage =
+        ChatCompletionRequestSystemMessageArgs::default().content(prompt.clone()).build()?.into();
+    if prompt_tools {
+        let descriptions =
+            tool_objects.iter().flat_map(|tool| tool.description()).collect::<Vec<_>>();
+        prompt = format!("{prompt}\n\n{}", tool_prompt::prompt(&descriptions));
+    }
+
+    let mut ctx = chat::ChatContext {
+        client: openai::client(provider)?,
+        http: openai::http_client(provider)?,
+        tool_objects: Arc::new(tool_objects),
+        on_event: sender_event.clone(),
+        timeouts: provider.into(),
+        extra_body: openai::reasoning::extra_body(agent, provider),
+        think_tags: provider.think_tags(&model.name).cloned(),
+        prompt_tools,
+        tool_limit: false,
+        tool_guard: Mutex::new(chat::ToolGuard::new(agent.max_tool_rounds)),
+    };
+
+    let mut messages =
+        vec![ChatCompletionRequestSystemMessageArgs::default().content(prompt).build()?.into()];
+
+    let mut histroy = histroy;
+    reasoning::prepare_history(app, agent.reasoning_history, &mut histroy).await?;
 
     for message in histroy.into_iter() {
         messages.push(openai::Message::new(message, agent.context_extend).try_into()?);
     }
     messages.push(message.try_into()?);
 
+    // 继续被截断的回复时, 新内容接在已有内容之后
+    let mut content = String::new();
+    let mut continued = false;
+    if let Some(resume) = resume {
+        content = resume.clone();
+        continued = true;
+        messages.push(
+            ChatCompletionRequestAssistantMessageArgs::default().content(resume).build()?.into(),
+        );
+        messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(CONTINUE_PROMPT)
+                .build()?
+                .into(),
+        );
+    }
+
     // on_event.send(MessageEvent::Started).map_err(|_| error::Error::Unknown)?;
 
-    let mut usages = Vec::new();
-    let cost = std::time::Instant::now();
+    let new_request = |messages: &Vec<ChatCompletionRequestMessage>| {
+        let mut request = CreateChatCompletionRequestArgs::default()
+            .model(model.name.clone())
+            .temperature(agent.temperature as f32)
+            .top_p(agent.top_p as f32)
+            .messages(messages.clone())
+            .build()?;
 
-    let (sender_exit, mut receiver_exit) = watch::channel(());
-    app.tasks.write().await.insert(message_id, MessageTask { exit: sender_exit });
+        if agent.max_tokens > 0 {
+            request.max_completion_tokens = Some(agent.max_tokens);
+        }
+        request.response_format = response_format.clone();
+        Ok::<_, error::Error>(request)
+    };
 
-    let mut event_result = Ok(serde_json::json!({
-        "status": "success"
-    }));
+    let mut continues = 0;
+    let mut truncated = None;
 
     loop {
-        let task = async {
-            let mut request = CreateChatCompletionRequestArgs::default()
-                .model(model.name.clone())
-                .temperature(agent.temperature as f32)
-                .top_p(agent.top_p as f32)
-                .messages(messages.clone())
-                .build()?;
-
-            if !tools.is_empty() {
-                request.tools = Some(tools.clone());
-            }
+        let mut request = new_request(&messages)?;
 
-            if agent.max_tokens > 0 {
-                request.max_completion_tokens = Some(agent.max_tokens);
+        if !tools.is_empty() && !prompt_tools {
+            request.tools = Some(tools.clone());
+            // 达到限制后禁用工具, 要求模型直接回答
+            if ctx.tool_limit {
+                request.tool_choice = Some(ChatCompletionToolChoiceOption::None);
             }
+        }
 
-            let (is_continue, usage) = if stream {
-                chat::chat_stream(
-                    &client,
-                    tool_objects.clone(),
-                    request,
-                    &mut messages,
-                    &sender_event,
-                )
-                .await?
-            } else {
-                chat::chat(&client, tool_objects.clone(), request, &mut messages, &sender_event)
-                    .await?
-            };
-            Ok::<_, error::Error>((is_continue, usage))
+        let round = if options.stream {
+            chat::chat_stream(&ctx, request, &mut messages).await?
+        } else {
+            chat::chat(&ctx, request, &mut messages).await?
         };
 
-        let is_continue = tokio::select! {
-            _ = receiver_exit.changed() => {
-                tracing::info!("Message task {} exit", message_id);
-                false
-            }
-            result = task => {
-                tracing::info!("Message task {} finished {result:?}", message_id);
-                match result {
-                    Ok((is_continue, usage)) => {
-                        if !is_continue {
-                             app.tasks.write().await.remove(&message_id);
-                        }
-                        if let Some(usage) = usage {
-                            usages.push(usage);
-                        }
-                        is_continue
-                    },
-                    Err(e) => {
-                         app.tasks.write().await.remove(&message_id);
-                        event_result = Err(e);
-                        false
-                    }
-                }
-            }
-        };
+        metrics.add(&round);
+        truncated = round.truncated;
+        if continued {
+            content.push_str(&round.content);
+        } else {
+            content = round.content.clone();
+        }
+        continued = false;
+
+        // 达到最大长度被截断时, 按智能体设置自动继续
+        if truncated == Some(store::FinishReason::Length) && continues < agent.auto_continue {
+            continues += 1;
+            continued = true;
+            tracing::info!("Reply truncated, continue {continues}/{}", agent.auto_continue);
+
+            messages.push(
+                ChatCompletionRequestAssistantMessageArgs::default()
+                    .content(round.content)
+                    .build()?
+                    .into(),
+            );
+            messages.push(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(CONTINUE_PROMPT)
+                    .build()?
+                    .into(),
+            );
+            continue;
+        }
 
-        if !is_continue {
+        // 已经禁用工具的最后一轮, 无论结果如何都结束
+        if !round.is_continue || ctx.tool_limit {
             break;
         }
+
+        let tool_guard = ctx.tool_guard.get_mut().unwrap();
+        if let Some((reason, detail)) = tool_guard.finish_round() {
+            let rounds = tool_guard.rounds();
+            tracing::info!("Tool limit: {detail}");
+            ctx.tool_limit = true;
+            // 提示词方式无法禁用工具, 去掉工具描述, 之后的调用标签只隐藏不执行
+            if prompt_tools {
+                messages[0] = base_system.clone();
+            }
+
+            messages.push(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(format!(
+                        "Tool calls stopped: {detail}. Do not call any more tools, \
+                         answer with the information gathered so far."
+                    ))
+                    .build()?
+                    .into(),
+            );
+
+            sender_event
+                .send(MessageEvent::ToolLimit { reason, rounds, message: detail })
+                .await
+                .map_err(|_| error::Error::Unknown)?;
+        }
     }
 
-    let (prompt_tokens, completion_tokens, total_tokens) =
-        usages.into_iter().fold((0, 0, 0), |(prompt, completion, total), usage| {
-            (
-                prompt + usage.prompt_tokens,
-                completion + usage.completion_tokens,
-                total + usage.total_tokens,
-            )
-        });
+    if let Some(reason) = truncated {
+        sender_event
+            .send(MessageEvent::Truncated { reason })
+            .await
+            .map_err(|_| error::Error::Unknown)?;
+    }
 
-    let finised = MessageEvent::Finished {
-        cost: cost.elapsed().as_millis() as i64,
-        prompt_tokens,
-        completion_tokens,
-        total_tokens,
+    let Some(structured) = structured else {
+        return Ok(());
     };
 
-    sender_event.send(finised).await.map_err(|_| error::Error::Unknown)?;
+    let mut output = structured.check(&content, truncated);
+
+    // 校验失败时要求模型修正一次, 被截断的回复修正也无法补全
+    if let Some(error) = output.error.clone().filter(|_| !output.valid && truncated.is_none()) {
+        tracing::info!("Structured output invalid: {error}");
+
+        sender_event
+            .send(MessageEvent::ContentReset { reason: error.clone() })
+            .await
+            .map_err(|_| error::Error::Unknown)?;
+
+        if prompt_tools {
+            messages[0] = base_system;
+        }
+        let (round, repaired) = structured::repair(
+            &mut ctx,
+            &structured,
+            content,
+            &error,
+            &mut messages,
+            options.stream,
+            &new_request,
+        )
+        .await?;
+
+        metrics.add(&round);
+        if let Some(reason) = round.truncated {
+            sender_event
+                .send(MessageEvent::Truncated { reason })
+                .await
+                .map_err(|_| error::Error::Unknown)?;
+        }
+        output = repaired;
+    }
+
+    sender_event
+        .send(MessageEvent::Structured { output })
+        .await
+        .map_err(|_| error::Error::Unknown)?;
 
-    event_result
+    Ok(())
 }
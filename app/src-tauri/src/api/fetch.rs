@@ -1,7 +1,11 @@
 use base64::prelude::*;
 
-use crate::openai::tool::{McpTool, ToolObject};
-use crate::{AppState, error};
+use crate::openai::{
+    self, prompt,
+    structured::Structured,
+    tool::{McpTool, ToolObject, oauth},
+};
+use crate::{AppState, api::title, error};
 
 pub async fn ftech(
     app: tauri::State<'_, AppState>, name: &str, data: &str,
@@ -54,11 +58,19 @@ pub async fn ftech(
         }
         "agent.add" => {
             let agent: store::Agent = serde_json::from_str(data)?;
+            if let Some(schema) = agent.response_schema.as_ref() {
+                Structured::new(schema)?;
+            }
+            prompt::check(&agent)?;
             app.store.add_agent(agent)?;
             Ok(serde_json::json!({ "status": "success" }))
         }
         "agent.update" => {
             let agent: store::Agent = serde_json::from_str(data)?;
+            if let Some(schema) = agent.response_schema.as_ref() {
+                Structured::new(schema)?;
+            }
+            prompt::check(&agent)?;
             let _ = app.agents.write().await.remove(&agent.id);
             app.store.update_agent(agent)?;
             Ok(serde_json::json!({ "status": "success" }))
@@ -187,15 +199,73 @@ pub async fn ftech(
             Ok(serde_json::json!({ "status": "success" }))
         }
         "tool.mcp.sse.tools" => {
-            let url: String = serde_json::from_str(data)?;
-            let tool = McpTool::try_new_sse(url).await?;
+            let sse: store::ToolMcpSse = serde_json::from_str(data)?;
+            let tool = McpTool::try_from_sse(&app.store, sse).await?;
+            Ok(serde_json::json!({ "status": "success", "data": tool.description() }))
+        }
+        "tool.mcp.http.tools" => {
+            let http: store::ToolMcpHttp = serde_json::from_str(data)?;
+            let tool = McpTool::try_from_http(&app.store, http).await?;
             Ok(serde_json::json!({ "status": "success", "data": tool.description() }))
         }
+        "tool.mcp.oauth.authorize" => {
+            #[derive(serde::Deserialize)]
+            struct Options {
+                /// MCP 服务地址, 令牌按地址保存
+                url: String,
+                #[serde(default)]
+                oauth: store::McpOAuth,
+            }
+            let opt: Options = serde_json::from_str(data)?;
+            let token = oauth::authorize(&app.store, &opt.url, &opt.oauth, |url| {
+                tauri_plugin_opener::open_url(url, None::<&str>)
+                    .map_err(|e| error::Error::Mcp(format!("Open browser: {e}")))
+            })
+            .await?;
+            app.remove_tool_objects_by_url(&opt.url).await?;
+            // 只返回过期时间, 令牌不离开后端
+            let data = serde_json::json!({ "expiresAt": token.expires_at });
+            Ok(serde_json::json!({ "status": "success", "data": data }))
+        }
+        "tool.mcp.oauth.revoke" => {
+            let url: String = serde_json::from_str(data)?;
+            app.store.delete_mcp_oauth_token(&url)?;
+            app.remove_tool_objects_by_url(&url).await?;
+            Ok(serde_json::json!({ "status": "success" }))
+        }
         "tool.mcp.io.tools" => {
             let io: store::ToolMcpIo = serde_json::from_str(data)?;
             let tool = McpTool::try_new_io(io.command, io.args, io.env).await?;
             Ok(serde_json::json!({ "status": "success", "data": tool.description() }))
         }
+        "tool.invoke" => {
+            #[derive(serde::Deserialize)]
+            struct Options {
+                /// 已保存的工具
+                id: Option<u64>,
+                /// 未保存的工具, 编辑时测试
+                tool: Option<store::Tool>,
+                /// 函数名称, 不指定时执行第一个函数
+                name: Option<String>,
+                /// 参数, 未传入的使用测试值
+                #[serde(default)]
+                arguments: serde_json::Value,
+            }
+            let opt: Options = serde_json::from_str(data)?;
+            let tool = match (opt.tool, opt.id) {
+                (Some(tool), _) => tool,
+                (None, Some(id)) => app
+                    .store
+                    .get_tool(id)?
+                    .ok_or(error::Error::InvalidData("Tool not found".to_string()))?,
+                (None, None) => {
+                    return Err(error::Error::InvalidData("Tool not specified".to_string()));
+                }
+            };
+            let invocation =
+                openai::tool::Tool::new(tool).invoke(&app.store, opt.name, opt.arguments).await;
+            Ok(serde_json::json!({ "status": "success", "data": invocation }))
+        }
         "chat.session.add" => {
             let session: store::ChatSession = serde_json::from_str(data)?;
             let session = app.store.add_chat_session(session)?;
@@ -221,6 +291,51 @@ pub async fn ftech(
             let parsed_data: serde_json::Value = serde_json::to_value(session)?;
             Ok(serde_json::json!({ "status": "success", "data": parsed_data }))
         }
+        "chat.session.retitle" => {
+            #[derive(serde::Deserialize)]
+            struct Options {
+                /// 指定会话, 不指定时按 all 批量处理
+                session: Option<u64>,
+                /// 批量重新生成全部会话的标题
+                #[serde(default)]
+                all: bool,
+            }
+            let opt: Options = serde_json::from_str(data)?;
+            let model = title::title_model(&app)?;
+
+            if let Some(id) = opt.session {
+                let session = title::retitle(&app, &model, id).await?;
+                return Ok(serde_json::json!({ "status": "success", "data": session }));
+            }
+
+            if !opt.all {
+                return Err(error::Error::InvalidData("No session to retitle".to_string()));
+            }
+
+            // 批量处理时跳过失败的会话, 例如还没有消息
+            let mut sessions = Vec::new();
+            for session in app.store.get_all_chat_sessions()? {
+                match title::retitle(&app, &model, session.id).await {
+                    Ok(session) => sessions.push(session),
+                    Err(e) => tracing::warn!("Retitle session {} error: {e}", session.id),
+                }
+            }
+            Ok(serde_json::json!({ "status": "success", "data": sessions }))
+        }
+        "chat.session.fork" => {
+            #[derive(serde::Deserialize)]
+            struct Options {
+                session: u64,
+                /// 复制到的最后一条消息
+                message: u64,
+                /// 新会话的智能体, 不指定时与原会话相同
+                #[serde(rename = "agentId")]
+                agent_id: Option<u64>,
+            }
+            let opt: Options = serde_json::from_str(data)?;
+            let session = app.store.fork_chat_session(opt.session, opt.message, opt.agent_id)?;
+            Ok(serde_json::json!({ "status": "success", "data": session }))
+        }
         "chat.session.list" => {
             let list = app.store.get_all_chat_sessions()?;
             let parsed_data: serde_json::Value = serde_json::to_value(list)?;
@@ -241,6 +356,11 @@ pub async fn ftech(
             app.store.delete_chat_message(id)?;
             Ok(serde_json::json!({ "status": "success" }))
         }
+        "chat.message.select" => {
+            let id: u64 = serde_json::from_str(data)?;
+            app.store.select_chat_message(id)?;
+            Ok(serde_json::json!({ "status": "success" }))
+        }
         "chat.message.list.by.session" => {
             #[derive(serde::Deserialize)]
             struct Options {
@@ -257,11 +377,52 @@ pub async fn ftech(
             let parsed_data: serde_json::Value = serde_json::to_value(list)?;
             Ok(serde_json::json!({ "status": "success", "data": parsed_data }))
         }
+        "chat.message.path" => {
+            #[derive(serde::Deserialize)]
+            struct Options {
+                session: u64,
+                /// 路径末端的消息, 不指定时为会话当前分支
+                message: Option<u64>,
+            }
+            let opt: Options = serde_json::from_str(data)?;
+            let list = match opt.message {
+                Some(message) => app.store.get_message_path(opt.session, message)?,
+                None => app.store.get_active_path(opt.session)?,
+            };
+            Ok(serde_json::json!({ "status": "success", "data": list }))
+        }
+        "chat.message.branches" => {
+            let id: u64 = serde_json::from_str(data)?;
+            let list = app.store.get_message_branches(id)?;
+            Ok(serde_json::json!({ "status": "success", "data": list }))
+        }
+        "chat.message.switch" => {
+            let id: u64 = serde_json::from_str(data)?;
+            let list = app.store.switch_message_branch(id)?;
+            Ok(serde_json::json!({ "status": "success", "data": list }))
+        }
         "chat.message.delete.by.session" => {
             let id: u64 = serde_json::from_str(data)?;
             app.store.delete_messages_by_session(id)?;
             Ok(serde_json::json!({ "status": "success" }))
         }
+        "chat.queue.list" => {
+            #[derive(serde::Deserialize)]
+            struct Options {
+                /// 指定会话, 不指定时列出所有会话
+                session: Option<u64>,
+            }
+            let opt: Options = serde_json::from_str(data)?;
+            let list = app.queues.list(opt.session);
+            Ok(serde_json::json!({ "status": "success", "data": list }))
+        }
+        "chat.queue.cancel" => {
+            let id: u64 = serde_json::from_str(data)?;
+            if !app.queues.cancel(id) {
+                return Err(error::Error::InvalidData(format!("Message {} is not queued", id)));
+            }
+            Ok(serde_json::json!({ "status": "success" }))
+        }
         "chat.message.audio.transcriptions" => {
             let audio = serde_json::from_str::<String>(data)?;
             let audio = BASE64_STANDARD.decode(audio)?;
@@ -275,10 +436,7 @@ pub async fn ftech(
                 .get_provider(model.id)?
                 .ok_or(error::Error::InvalidData("Provider not found".to_string()))?;
 
-            let config = async_openai::config::OpenAIConfig::new()
-                .with_api_base(provider.url)
-                .with_api_key(provider.api_key.clone().unwrap_or_default());
-            let client = async_openai::Client::with_config(config);
+            let client = openai::client(&provider)?;
 
             let audio_input =
                 async_openai::types::AudioInput::from_vec_u8("input.wav".to_string(), audio);
@@ -290,6 +448,11 @@ pub async fn ftech(
             let response = client.audio().transcribe(request).await?;
             Ok(serde_json::json!({ "status": "success", "data": response.text }))
         }
+        "stats.usage" => {
+            let query: store::UsageQuery = serde_json::from_str(data)?;
+            let rows = app.store.get_usage_stats(&query)?;
+            Ok(serde_json::json!({ "status": "success", "data": rows }))
+        }
         "file.convert" => {
             #[derive(serde::Deserialize)]
             struct Convert {
@@ -1,2 +1,5 @@
 pub mod event;
 pub mod fetch;
+pub mod queue;
+pub mod reasoning;
+pub mod title;
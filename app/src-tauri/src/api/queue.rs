@@ -0,0 +1,264 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::error;
+
+/// 排队条目显示的最多字符数
+const MAX_PREVIEW_CHARS: usize = 100;
+
+/// 会话中正在生成或排队等待的消息
+#[derive(Debug, Serialize, Clone)]
+pub struct QueueEntry {
+    #[serde(rename = "sessionId")]
+    pub session_id: u64,
+    /// 用户消息ID
+    #[serde(rename = "messageId")]
+    pub message_id: u64,
+    /// 消息内容预览
+    pub content: String,
+    /// 是否正在生成回复
+    pub running: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: i64,
+}
+
+impl QueueEntry {
+    pub fn new(message: &store::ChatMessage) -> Self {
+        Self {
+            session_id: message.session_id,
+            message_id: message.id,
+            content: message.content.chars().take(MAX_PREVIEW_CHARS).collect(),
+            running: false,
+            created_at: chrono::Utc::now().timestamp_millis(),
+        }
+    }
+}
+
+struct Waiter {
+    entry: QueueEntry,
+    ready: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct SessionQueue {
+    running: Option<QueueEntry>,
+    waiting: VecDeque<Waiter>,
+}
+
+impl SessionQueue {
+    /// 当前回复结束, 轮到下一个仍在等待的消息
+    fn advance(&mut self) {
+        self.running = None;
+        while let Some(mut waiter) = self.waiting.pop_front() {
+            waiter.entry.running = true;
+            let entry = waiter.entry.clone();
+            // 等待方已经退出时跳过
+            if waiter.ready.send(()).is_ok() {
+                self.running = Some(entry);
+                break;
+            }
+        }
+    }
+}
+
+/// 按会话串行生成回复, 同一会话同时只有一条回复在生成
+#[derive(Default)]
+pub struct MessageQueues(Mutex<HashMap<u64, SessionQueue>>);
+
+impl MessageQueues {
+    /// 进入会话队列, 会话空闲时立即开始, 否则按策略排队或拒绝
+    pub fn enter(
+        &self, mut entry: QueueEntry, policy: store::QueuePolicy,
+    ) -> Result<QueueSlot<'_>, error::Error> {
+        let mut sessions = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let queue = sessions.entry(entry.session_id).or_default();
+
+        let mut slot = QueueSlot {
+            queues: self,
+            session_id: entry.session_id,
+            message_id: entry.message_id,
+            position: 0,
+            ready: None,
+        };
+
+        if queue.running.is_none() {
+            entry.running = true;
+            queue.running = Some(entry);
+            return Ok(slot);
+        }
+
+        if policy == store::QueuePolicy::Reject {
+            return Err(error::Error::InvalidData(format!(
+                "Session {} is busy with another reply",
+                entry.session_id
+            )));
+        }
+
+        let (ready, receiver) = oneshot::channel();
+        queue.waiting.push_back(Waiter { entry, ready });
+        slot.position = queue.waiting.len();
+        slot.ready = Some(receiver);
+        Ok(slot)
+    }
+
+    /// 取消排队中的消息, 正在生成的消息通过退出任务取消
+    pub fn cancel(&self, message_id: u64) -> bool {
+        let mut sessions = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.values_mut().any(|queue| {
+            let len = queue.waiting.len();
+            // 丢弃发送端, 等待方收到取消
+            queue.waiting.retain(|waiter| waiter.entry.message_id != message_id);
+            queue.waiting.len() != len
+        })
+    }
+
+    /// 列出正在生成和排队中的消息, 不指定会话时列出所有会话
+    pub fn list(&self, session_id: Option<u64>) -> Vec<QueueEntry> {
+        let sessions = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        sessions
+            .iter()
+            .filter(|(id, _)| session_id.is_none_or(|session_id| **id == session_id))
+            .flat_map(|(_, queue)| {
+                queue.running.iter().cloned().chain(queue.waiting.iter().map(|w| w.entry.clone()))
+            })
+            .collect()
+    }
+
+    fn leave(&self, session_id: u64, message_id: u64) {
+        let mut sessions = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(queue) = sessions.get_mut(&session_id) else {
+            return;
+        };
+
+        if queue.running.as_ref().is_some_and(|entry| entry.message_id == message_id) {
+            queue.advance();
+        } else {
+            queue.waiting.retain(|waiter| waiter.entry.message_id != message_id);
+        }
+
+        if queue.running.is_none() && queue.waiting.is_empty() {
+            sessions.remove(&session_id);
+        }
+    }
+}
+
+/// 队列中的位置, 释放时轮到下一个消息
+pub struct QueueSlot<'a> {
+    queues: &'a MessageQueues,
+    session_id: u64,
+    message_id: u64,
+    position: usize,
+    ready: Option<oneshot::Receiver<()>>,
+}
+
+impl QueueSlot<'_> {
+    /// 前面等待的消息数, 0 表示已经开始
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// 等待轮到当前消息, 排队中被取消时返回错误
+    pub async fn wait(&mut self) -> Result<(), error::Error> {
+        if let Some(ready) = self.ready.take() {
+            ready.await.map_err(|_| {
+                error::Error::InvalidData(format!("Message {} cancelled in queue", self.message_id))
+            })?;
+            self.position = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.queues.leave(self.session_id, self.message_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(session_id: u64, message_id: u64) -> QueueEntry {
+        QueueEntry {
+            session_id,
+            message_id,
+            content: format!("message {message_id}"),
+            running: false,
+            created_at: 0,
+        }
+    }
+
+    /// 列出会话中的(消息ID, 是否正在生成)
+    fn list(queues: &MessageQueues, session_id: u64) -> Vec<(u64, bool)> {
+        queues.list(Some(session_id)).iter().map(|e| (e.message_id, e.running)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_wait_policy() {
+        let queues = MessageQueues::default();
+        let first = queues.enter(entry(1, 1), store::QueuePolicy::Wait).unwrap();
+        let mut second = queues.enter(entry(1, 3), store::QueuePolicy::Wait).unwrap();
+        let mut third = queues.enter(entry(1, 5), store::QueuePolicy::Wait).unwrap();
+        assert_eq!((first.position(), second.position(), third.position()), (0, 1, 2));
+        assert_eq!(list(&queues, 1), vec![(1, true), (3, false), (5, false)]);
+
+        // 其它会话不受影响
+        let other = queues.enter(entry(2, 7), store::QueuePolicy::Wait).unwrap();
+        assert_eq!(other.position(), 0);
+        drop(other);
+
+        // 按顺序轮到排队的消息
+        drop(first);
+        second.wait().await.unwrap();
+        assert_eq!(second.position(), 0);
+        assert_eq!(list(&queues, 1), vec![(3, true), (5, false)]);
+
+        drop(second);
+        third.wait().await.unwrap();
+        assert_eq!(list(&queues, 1), vec![(5, true)]);
+
+        // 全部完成后移除会话
+        drop(third);
+        assert!(queues.list(None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reject_policy() {
+        let queues = MessageQueues::default();
+        let first = queues.enter(entry(1, 1), store::QueuePolicy::Reject).unwrap();
+        assert!(queues.enter(entry(1, 3), store::QueuePolicy::Reject).is_err());
+        assert_eq!(list(&queues, 1), vec![(1, true)]);
+
+        drop(first);
+        let second = queues.enter(entry(1, 3), store::QueuePolicy::Reject).unwrap();
+        assert_eq!(second.position(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_exit_queued() {
+        let queues = MessageQueues::default();
+        let first = queues.enter(entry(1, 1), store::QueuePolicy::Wait).unwrap();
+        let mut second = queues.enter(entry(1, 3), store::QueuePolicy::Wait).unwrap();
+        let mut third = queues.enter(entry(1, 5), store::QueuePolicy::Wait).unwrap();
+
+        // 退出排队中的消息时移出队列, 等待方收到取消
+        assert!(queues.cancel(3));
+        assert!(second.wait().await.is_err());
+        assert_eq!(list(&queues, 1), vec![(1, true), (5, false)]);
+        drop(second);
+
+        // 正在生成的消息通过退出任务取消, 不在队列中处理
+        assert!(!queues.cancel(1));
+        assert!(!queues.cancel(99));
+
+        drop(first);
+        third.wait().await.unwrap();
+        assert_eq!(list(&queues, 1), vec![(5, true)]);
+    }
+}
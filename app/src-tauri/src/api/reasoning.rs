@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use async_openai::types::{
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    CreateChatCompletionRequestArgs,
+};
+
+use store::{ChatMessage, ReasoningHistory, Role};
+
+use crate::{AppState, api::title, error, openai};
+
+/// 摘要失败时保留的最多字符数
+const MAX_FALLBACK_CHARS: usize = 500;
+
+/// 生成摘要时输入的最多字符数
+const MAX_REASONING_CHARS: usize = 8000;
+
+/// 助手消息中非空的思考内容
+fn reasoning_of(message: &ChatMessage) -> Option<&str> {
+    (message.role == Role::Assistant)
+        .then_some(message.reasoning_content.as_deref())
+        .flatten()
+        .filter(|r| !r.trim().is_empty())
+}
+
+/// 按智能体设置处理历史消息中的思考内容
+///
+/// 丢弃时不做处理, 保留时以 `<think>` 标签拼接在回复前, 摘要时优先使用已保存的摘要,
+/// 缺少的摘要并发生成
+pub async fn prepare_history(
+    app: &AppState, mode: ReasoningHistory, histroy: &mut [ChatMessage],
+) -> Result<(), error::Error> {
+    if mode == ReasoningHistory::Drop {
+        return Ok(());
+    }
+
+    if mode == ReasoningHistory::Summarize {
+        let pending = histroy
+            .iter()
+            .filter(|m| m.reasoning_summary.is_none())
+            .filter_map(|m| Some((m, reasoning_of(m)?)))
+            .map(|(message, reasoning)| async move {
+                (message.id, summarize(app, message, reasoning).await)
+            });
+        let mut summaries =
+            futures::future::join_all(pending).await.into_iter().collect::<HashMap<_, _>>();
+        for message in histroy.iter_mut() {
+            if let Some(summary) = summaries.remove(&message.id) {
+                message.reasoning_summary = Some(summary);
+            }
+        }
+    }
+
+    for message in histroy.iter_mut() {
+        let Some(reasoning) = reasoning_of(message) else {
+            continue;
+        };
+
+        let reasoning = match (mode, message.reasoning_summary.as_deref()) {
+            (ReasoningHistory::Summarize, Some(summary)) => summary,
+            _ => reasoning,
+        };
+
+        message.content = format!("<think>\n{}\n</think>\n\n{}", reasoning.trim(), message.content);
+    }
+
+    Ok(())
+}
+
+/// 生成思考摘要并保存到消息
+///
+/// 失败时保存截断的原文作为摘要, 之后的回复不再重试
+async fn summarize(app: &AppState, message: &ChatMessage, reasoning: &str) -> String {
+    let summary = match generate(app, reasoning).await {
+        Ok(summary) if !summary.is_empty() => summary,
+        Ok(_) => title::truncate(reasoning, MAX_FALLBACK_CHARS),
+        Err(e) => {
+            tracing::warn!("Error summarizing reasoning of message {}: {:?}", message.id, e);
+            title::truncate(reasoning, MAX_FALLBACK_CHARS)
+        }
+    };
+
+    // 保存到存储中的消息, 避免后续重复生成
+    let result = app.store.get_chat_message(message.id).and_then(|stored| match stored {
+        Some(mut stored) => {
+            stored.reasoning_summary = Some(summary.clone());
+            app.store.update_chat_message(stored)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        tracing::error!("Error saving reasoning summary: {:?}", e);
+    }
+
+    summary
+}
+
+async fn generate(app: &AppState, reasoning: &str) -> Result<String, error::Error> {
+    let model = title::title_model(app)?;
+    let provider = app.get_provider(model.id).await?;
+    let client = openai::client(&provider)?;
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model.name.clone())
+        .messages(vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(
+                    "Summarize the following reasoning in a few sentences. \
+                     Keep the key steps and conclusions, use the language of the reasoning, \
+                     reply with the summary only.",
+                )
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(title::truncate(reasoning, MAX_REASONING_CHARS))
+                .build()?
+                .into(),
+        ])
+        .build()?;
+
+    let response = client.chat().create(request).await?;
+    let content =
+        response.choices.into_iter().find_map(|choice| choice.message.content).unwrap_or_default();
+
+    // 去掉摘要模型自身的思考过程
+    let content = content.rsplit_once("</think>").map_or(content.as_str(), |(_, summary)| summary);
+    Ok(content.trim().to_string())
+}
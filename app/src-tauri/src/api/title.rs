@@ -0,0 +1,101 @@
+use async_openai::types::{
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    CreateChatCompletionRequestArgs,
+};
+
+use store::{ChatSession, MessageStatus, Role};
+
+use crate::{AppState, error, openai};
+
+/// 标题最多字符数
+const MAX_TITLE_CHARS: usize = 50;
+
+/// 每条消息参与生成标题的最多字符数
+const MAX_CONTENT_CHARS: usize = 2000;
+
+/// 读取设置中生成标题的模型
+pub fn title_model(app: &AppState) -> Result<store::ProviderModel, error::Error> {
+    app.store
+        .get_settings()?
+        .title_model
+        .ok_or(error::Error::InvalidData("Title model settings not found".to_string()))
+}
+
+/// 重新生成会话标题, 覆盖用户修改过的标题, 保存后返回
+pub async fn retitle(
+    app: &AppState, model: &store::ProviderModel, session_id: u64,
+) -> Result<ChatSession, error::Error> {
+    let provider = app.get_provider(model.id).await?;
+    let title = generate(&app.store, &provider, model, session_id).await?;
+    app.store.set_generated_topic(session_id, title, false)?.ok_or_else(|| {
+        error::Error::InvalidData(format!("Session with id {} not found", session_id))
+    })
+}
+
+/// 根据会话的第一轮对话生成标题
+pub async fn generate(
+    store: &store::Store, provider: &store::Provider, model: &store::ProviderModel, session_id: u64,
+) -> Result<String, error::Error> {
+    let mut messages = store.get_messages_by_session(session_id)?;
+    messages.sort_by_key(|m| m.id);
+
+    let Some(question) = messages.iter().find(|m| m.role == Role::User) else {
+        return Err(error::Error::InvalidData(format!("Session {} has no messages", session_id)));
+    };
+
+    let mut conversation = format!("User: {}", truncate(&question.content, MAX_CONTENT_CHARS));
+    if let Some(answer) = messages.iter().find(|m| {
+        m.role == Role::Assistant
+            && m.parent_id.is_none_or(|id| id == question.id)
+            && m.status == MessageStatus::Success
+            && m.selected != Some(false)
+    }) {
+        conversation
+            .push_str(&format!("\n\nAssistant: {}", truncate(&answer.content, MAX_CONTENT_CHARS)));
+    }
+
+    let client = openai::client(provider)?;
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model.name.clone())
+        .messages(vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(
+                    "Write a short title for the following conversation. \
+                     Use the language of the conversation, at most 20 words, \
+                     reply with the title only, without quotes or punctuation at the end.",
+                )
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default().content(conversation).build()?.into(),
+        ])
+        .build()?;
+
+    let response = client.chat().create(request).await?;
+    let content =
+        response.choices.into_iter().find_map(|choice| choice.message.content).unwrap_or_default();
+
+    let title = clean_title(&content);
+    if title.is_empty() {
+        return Err(error::Error::InvalidData("Empty title generated".to_string()));
+    }
+    Ok(title)
+}
+
+/// 去掉思考过程、引号等多余内容, 只保留第一行
+fn clean_title(content: &str) -> String {
+    let content = content.rsplit_once("</think>").map_or(content, |(_, title)| title);
+    let title = content.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+    let title = title
+        .trim_start_matches('#')
+        .trim()
+        .trim_matches(|c| matches!(c, '"' | '\'' | '“' | '”' | '《' | '》' | '*' | '`'))
+        .trim_end_matches(['.', '。'])
+        .trim();
+    truncate(title, MAX_TITLE_CHARS)
+}
+
+/// 截取前 max_chars 个字符, 不会截断多字节字符
+pub fn truncate(content: &str, max_chars: usize) -> String {
+    content.chars().take(max_chars).collect()
+}
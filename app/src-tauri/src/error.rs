@@ -18,9 +18,24 @@ pub enum Error {
     #[error("Io error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Http error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    #[error("Template error: {0}")]
+    Template(String),
+
+    #[error("Schema error: {0}")]
+    Schema(String),
+
     #[error("Mcp error: {0}")]
     Mcp(String),
 
+    #[error("Tool error: {0}")]
+    Tool(#[from] tools::Error),
+
     #[error("Tavily error: {0}")]
     Tavily(#[from] tavily::TavilyError),
 
@@ -28,6 +43,18 @@ pub enum Error {
     Unknown,
 }
 
+impl Error {
+    /// 是否为超时错误, 包括连接超时
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout(_) => true,
+            Error::Http(e) => e.is_timeout(),
+            Error::OpenAI(async_openai::error::OpenAIError::Reqwest(e)) => e.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
 unsafe impl Send for Error {}
 unsafe impl Sync for Error {}
 
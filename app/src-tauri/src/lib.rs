@@ -15,6 +15,7 @@ struct AppState {
     causal_dir: String,
     store: store::Store,
     tasks: api::event::MessageTasks,
+    queues: api::queue::MessageQueues,
 
     providers: RwLock<HashMap<u64, Arc<store::Provider>>>,
     agents: RwLock<HashMap<u64, Arc<store::Agent>>>,
@@ -57,12 +58,23 @@ impl AppState {
             .store
             .get_tool(id)?
             .ok_or(error::Error::InvalidData(format!("Tool with id {} not found", id)))?;
-        let tool_object = openai::tool::Tool::new(tool).into_tool_object().await?;
+        let tool_object = openai::tool::Tool::new(tool).into_tool_object(&self.store).await?;
         let tool_object = Arc::new(tool_object);
         self.tools.write().await.insert(id, tool_object.clone());
         Ok(tool_object)
     }
 
+    /// 移除使用该地址的远程 MCP 工具缓存, 授权变化后重新连接
+    pub async fn remove_tool_objects_by_url(&self, url: &str) -> Result<(), error::Error> {
+        let mut tools = self.tools.write().await;
+        for tool in self.store.get_all_tools()? {
+            if tool.url() == Some(url) {
+                tools.remove(&tool.id);
+            }
+        }
+        Ok(())
+    }
+
     pub async fn get_search_tool_object(
         &self, search: Search,
     ) -> Result<Arc<Box<dyn ToolObject>>, error::Error> {
@@ -106,12 +118,43 @@ async fn event(
     app: tauri::State<'_, AppState>, message: store::ChatMessage, search: bool, time: bool,
     stream: bool, on_event: tauri::ipc::Channel<openai::chat::MessageEvent>,
 ) -> Result<serde_json::Value, serde_json::Value> {
-    api::event::event(app, message, search, time, stream, on_event).await.map_err(|e| {
+    let options = api::event::EventOptions { search, time, stream };
+    api::event::event(app, message, options, on_event).await.map_err(|e| {
         tracing::error!("event error: {}", e.to_string());
         e.into()
     })
 }
 
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn event_compare(
+    app: tauri::State<'_, AppState>, webview: tauri::Webview, message: store::ChatMessage,
+    targets: Vec<api::event::CompareTarget>, search: bool, time: bool, stream: bool,
+    on_event: tauri::ipc::Channel<openai::chat::MessageEvent>,
+    on_replies: Vec<tauri::ipc::JavaScriptChannelId>,
+) -> Result<serde_json::Value, serde_json::Value> {
+    let options = api::event::EventOptions { search, time, stream };
+    let on_replies = on_replies.into_iter().map(|id| id.channel_on(webview.clone())).collect();
+    api::event::event_compare(app, message, targets, options, on_event, on_replies).await.map_err(
+        |e| {
+            tracing::error!("event compare error: {}", e.to_string());
+            e.into()
+        },
+    )
+}
+
+#[tauri::command]
+async fn event_continue(
+    app: tauri::State<'_, AppState>, message: u64, search: bool, time: bool, stream: bool,
+    on_event: tauri::ipc::Channel<openai::chat::MessageEvent>,
+) -> Result<serde_json::Value, serde_json::Value> {
+    let options = api::event::EventOptions { search, time, stream };
+    api::event::event_continue(app, message, options, on_event).await.map_err(|e| {
+        tracing::error!("event continue error: {}", e.to_string());
+        e.into()
+    })
+}
+
 #[tauri::command]
 async fn event_exit(
     app: tauri::State<'_, AppState>, message: u64,
@@ -119,6 +162,9 @@ async fn event_exit(
     let mut tasks = app.tasks.write().await;
     if let Some(task) = tasks.remove(&message) {
         let _ = task.exit.send(());
+    } else {
+        // 还在排队的消息直接移出队列
+        app.queues.cancel(message);
     }
     Ok(serde_json::json!({
         "status": "success"
@@ -173,6 +219,7 @@ pub async fn run() {
         causal_dir: causal_dir.clone(),
         store: store::Store::open(format!("{}/store", causal_dir)).unwrap(),
         tasks: api::event::MessageTasks::default(),
+        queues: api::queue::MessageQueues::default(),
 
         providers: RwLock::new(HashMap::new()),
         agents: RwLock::new(HashMap::new()),
@@ -198,6 +245,8 @@ pub async fn run() {
             app_date,
             fetch,
             event,
+            event_compare,
+            event_continue,
             event_exit
         ])
         .manage(app)
@@ -1,25 +1,38 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, btree_map::Entry},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use async_openai::{
     Client,
-    config::OpenAIConfig,
+    config::{Config, OpenAIConfig},
+    error::OpenAIError,
     types::{
         ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
-        ChatCompletionRequestMessage, ChatCompletionRequestToolMessageArgs, ChatCompletionToolType,
-        CompletionUsage, CreateChatCompletionRequest, FinishReason, FunctionCall,
+        ChatCompletionRequestMessage, ChatCompletionRequestToolMessageArgs,
+        ChatCompletionRequestUserMessageArgs, ChatCompletionResponseStream,
+        ChatCompletionStreamOptions, ChatCompletionToolType, CompletionUsage,
+        CreateChatCompletionRequest, CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse, FinishReason, FunctionCall,
     },
 };
 
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use serde::Serialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 
-use tokio::{
-    sync::{Mutex, mpsc},
-    task::JoinSet,
-};
+use tokio::{sync::mpsc, task::JoinSet};
 
-use crate::{error, openai::tool::ToolObject};
+use crate::{
+    error,
+    openai::{
+        metrics::{TokenEstimator, estimate_usage},
+        tag::{Segment, TagSplitter},
+        tool::{ToolObject, validate_arguments},
+        tool_prompt,
+    },
+};
 
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase", tag = "event", content = "data")]
@@ -33,17 +46,67 @@ pub enum MessageEvent {
     RetryAssistantMessage {
         message: store::ChatMessage,
     },
+    /// 会话中已有回复在生成, 排队等待, position 为前面的消息数
+    Queued {
+        position: usize,
+    },
     ReasoningContent {
         content: String,
     },
     Content {
         content: String,
     },
+    ToolCallStarted {
+        id: String,
+        name: String,
+    },
+    ToolCallArguments {
+        id: String,
+        delta: String,
+    },
+    ToolRunning {
+        id: String,
+        name: String,
+        arguments: String,
+    },
     Tool {
         id: String,
         name: String,
         arguments: String,
         result: String,
+        cost: i64,
+    },
+    ToolError {
+        id: String,
+        name: String,
+        arguments: String,
+        error: String,
+        cost: i64,
+    },
+    ToolLimit {
+        reason: ToolLimitReason,
+        rounds: u32,
+        message: String,
+    },
+    /// 清空已经生成的回复内容, 之后重新输出
+    ContentReset {
+        reason: String,
+    },
+    /// 结构化输出的校验结果
+    Structured {
+        output: store::StructuredOutput,
+    },
+    /// 继续生成被截断的回复, 新内容追加到已有的消息
+    ContinueAssistantMessage {
+        message: store::ChatMessage,
+    },
+    /// 回复因长度或内容过滤被截断
+    Truncated {
+        reason: store::FinishReason,
+    },
+    /// 会话信息已更新, 例如自动生成了标题
+    SessionUpdated {
+        session: store::ChatSession,
     },
     Finished {
         cost: i64,
@@ -53,9 +116,356 @@ pub enum MessageEvent {
         completion_tokens: u32,
         #[serde(rename = "totalTokens")]
         total_tokens: u32,
+        /// 按模型价格计算的费用, 没有设置价格时为空
+        fee: Option<f64>,
+        /// 首个token的耗时, 单位毫秒
+        #[serde(rename = "firstToken")]
+        first_token: Option<i64>,
+        /// 思考结束后首个正文内容的耗时, 单位毫秒
+        #[serde(rename = "firstContent")]
+        first_content: Option<i64>,
+        /// 每秒输出的tokens
+        #[serde(rename = "tokensPerSecond")]
+        tokens_per_second: Option<f64>,
+        status: store::MessageStatus,
+        error: Option<String>,
     },
 }
 
+/// 工具调用被终止的原因
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ToolLimitReason {
+    /// 超过最大工具调用轮数
+    MaxRounds,
+    /// 重复调用相同的函数和参数
+    RepeatedCall,
+}
+
+/// 默认最多工具调用轮数
+const DEFAULT_MAX_TOOL_ROUNDS: u32 = 10;
+
+/// 相同函数和参数允许调用的次数
+const MAX_REPEATED_TOOL_CALLS: u32 = 2;
+
+/// 工具调用轮数限制及重复调用检测
+pub struct ToolGuard {
+    max_rounds: u32,
+    rounds: u32,
+    calls: HashMap<(String, String), u32>,
+    /// 本轮被拒绝执行的重复调用
+    repeated: Option<String>,
+}
+
+impl ToolGuard {
+    pub fn new(max_rounds: u32) -> Self {
+        let max_rounds = if max_rounds > 0 { max_rounds } else { DEFAULT_MAX_TOOL_ROUNDS };
+        Self { max_rounds, rounds: 0, calls: HashMap::new(), repeated: None }
+    }
+
+    /// 已完成的工具调用轮数
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    /// 执行工具前记录调用, 相同函数和参数超过次数时返回说明, 不再执行
+    fn record(&mut self, name: &str, arguments: &str) -> Result<(), String> {
+        // 参数统一格式后再比较, 忽略字段顺序和空白的差异
+        let arguments = serde_json::from_str::<Value>(arguments)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| arguments.to_string());
+
+        let count = self.calls.entry((name.to_string(), arguments)).or_default();
+        *count += 1;
+        if *count > MAX_REPEATED_TOOL_CALLS {
+            let detail =
+                format!("function {name} was called {count} times with the same arguments");
+            self.repeated = Some(detail.clone());
+            return Err(detail);
+        }
+        Ok(())
+    }
+
+    /// 一轮工具调用完成, 触发限制时返回原因及说明
+    pub fn finish_round(&mut self) -> Option<(ToolLimitReason, String)> {
+        self.rounds += 1;
+
+        if let Some(detail) = self.repeated.take() {
+            return Some((ToolLimitReason::RepeatedCall, detail));
+        }
+        if self.rounds >= self.max_rounds {
+            return Some((
+                ToolLimitReason::MaxRounds,
+                format!("maximum of {} tool rounds reached", self.max_rounds),
+            ));
+        }
+        None
+    }
+}
+
+/// 单轮请求的结果
+#[derive(Debug, Default)]
+pub struct ChatRound {
+    /// 是否调用了工具, 需要继续请求
+    pub is_continue: bool,
+    pub usage: Option<CompletionUsage>,
+    /// 本轮调用的工具(函数名, 参数)
+    pub tool_calls: Vec<(String, String)>,
+    /// 本轮输出的内容
+    pub content: String,
+    /// 发送请求的时间
+    pub started: Option<Instant>,
+    /// 收到首个token的时间
+    pub first_token: Option<Instant>,
+    /// 收到首个正文内容的时间
+    pub first_content: Option<Instant>,
+    /// 从首个token到输出结束的时长
+    pub generation: Duration,
+    /// 本轮输出被截断的原因
+    pub truncated: Option<store::FinishReason>,
+}
+
+/// 长度和内容过滤的结束原因表示输出被截断
+fn truncated(reason: Option<&FinishReason>) -> Option<store::FinishReason> {
+    match reason? {
+        FinishReason::Length => Some(store::FinishReason::Length),
+        FinishReason::ContentFilter => Some(store::FinishReason::ContentFilter),
+        _ => None,
+    }
+}
+
+/// 等待模型响应的超时设置
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// 建立连接
+    pub connect: Option<Duration>,
+    /// 从发出请求到收到首个数据, 非流式请求为整个响应
+    pub first_token: Option<Duration>,
+    /// 流式输出中两次数据之间
+    pub idle: Option<Duration>,
+}
+
+impl From<&store::Provider> for Timeouts {
+    fn from(provider: &store::Provider) -> Self {
+        let seconds = |v: u64| (v > 0).then(|| Duration::from_secs(v));
+        Self {
+            connect: seconds(provider.connect_timeout),
+            first_token: seconds(provider.first_token_timeout),
+            idle: seconds(provider.idle_timeout),
+        }
+    }
+}
+
+/// 在限定时间内等待, 超时返回`error::Error::Timeout`
+async fn timeout<F: Future>(
+    duration: Option<Duration>, stage: &str, future: F,
+) -> Result<F::Output, error::Error> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, future).await.map_err(|_| {
+            error::Error::Timeout(format!("{stage} not received within {}s", duration.as_secs()))
+        }),
+        None => Ok(future.await),
+    }
+}
+
+/// 发送请求的错误, 只有建立连接时超时才视为连接超时
+fn connect_error(connect: Option<Duration>, error: reqwest::Error) -> error::Error {
+    match connect {
+        Some(connect) if error.is_connect() && error.is_timeout() => error::Error::Timeout(
+            format!("connection not established within {}s: {error}", connect.as_secs()),
+        ),
+        _ => OpenAIError::Reqwest(error).into(),
+    }
+}
+
+/// 发送流式请求, 不经过 async-openai 的事件流, 以保留连接错误的原始类型
+async fn open_stream(
+    http: &reqwest::Client, config: &OpenAIConfig, connect: Option<Duration>, body: &Value,
+) -> Result<ChatCompletionResponseStream, error::Error> {
+    let response = http
+        .post(config.url("/chat/completions"))
+        .query(&config.query())
+        .headers(config.headers())
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| connect_error(connect, e))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(OpenAIError::StreamError(format!("{status}: {text}")).into());
+    }
+    Ok(event_stream(response.bytes_stream()))
+}
+
+/// 解析服务端事件流中的数据块, 收到`[DONE]`时结束
+fn event_stream<S, B>(bytes: S) -> ChatCompletionResponseStream
+where
+    S: Stream<Item = reqwest::Result<B>> + Send + 'static,
+    B: AsRef<[u8]> + Send,
+{
+    let state = (Box::pin(bytes), Vec::new());
+    Box::pin(futures::stream::unfold(Some(state), |state| async move {
+        let (mut bytes, mut buffer) = state?;
+        loop {
+            // 事件之间以空行分隔, 没有数据的事件如心跳注释直接跳过
+            if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                let event = buffer.drain(..end + 2).collect::<Vec<u8>>();
+                let data = String::from_utf8_lossy(&event)
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if data.is_empty() {
+                    continue;
+                }
+                if data == "[DONE]" {
+                    return None;
+                }
+                let response = serde_json::from_str::<CreateChatCompletionStreamResponse>(&data)
+                    .map_err(|e| OpenAIError::StreamError(format!("{e}: {data}")));
+                return Some((response, Some((bytes, buffer))));
+            }
+            match bytes.next().await {
+                Some(Ok(chunk)) => {
+                    buffer.extend(chunk.as_ref().iter().filter(|&&b| b != b'\r'));
+                }
+                Some(Err(e)) => return Some((Err(OpenAIError::Reqwest(e)), None)),
+                None => return None,
+            }
+        }
+    }))
+}
+
+/// 一次回复中各轮请求共用的上下文
+pub struct ChatContext {
+    pub client: Client<OpenAIConfig>,
+    /// 流式请求使用的 HTTP 客户端, 与 client 的连接超时相同
+    pub http: reqwest::Client,
+    pub tool_objects: Arc<Vec<Arc<Box<dyn ToolObject>>>>,
+    pub on_event: mpsc::Sender<MessageEvent>,
+    pub timeouts: Timeouts,
+    /// 合并到请求体中的额外参数, 如思考强度
+    pub extra_body: Map<String, Value>,
+    /// 设置时从正文中拆分以标签包裹的思考内容
+    pub think_tags: Option<store::ThinkTags>,
+    /// 通过系统提示词描述工具并从正文中解析调用, 用于不支持原生函数调用的模型
+    pub prompt_tools: bool,
+    /// 已达到工具调用限制, 不再执行工具
+    pub tool_limit: bool,
+    /// 工具调用轮数限制及重复调用检测
+    pub tool_guard: Mutex<ToolGuard>,
+}
+
+/// 正文中拆分出的内容
+enum Part {
+    Reasoning(String),
+    Content(String),
+}
+
+/// 按上下文设置从正文中拆分思考内容, 以及隐藏提示词方式的工具调用
+struct ContentSplitter {
+    think: Option<TagSplitter>,
+    tool: Option<TagSplitter>,
+    /// 拆分思考内容后的完整正文, 包括工具调用, 用于解析工具调用
+    raw: String,
+}
+
+impl ContentSplitter {
+    fn new(ctx: &ChatContext) -> Self {
+        Self {
+            think: ctx.think_tags.as_ref().map(|tags| TagSplitter::new(&tags.open, &tags.close)),
+            tool: ctx.prompt_tools.then(|| {
+                TagSplitter::new(tool_prompt::TOOL_CALL_OPEN, tool_prompt::TOOL_CALL_CLOSE)
+            }),
+            raw: String::new(),
+        }
+    }
+
+    fn push(&mut self, chunk: &str) -> Vec<Part> {
+        let segments = match self.think.as_mut() {
+            Some(think) => think.push(chunk),
+            None => vec![Segment::Text(chunk.to_string())],
+        };
+        self.split_tools(segments)
+    }
+
+    /// 流结束时输出暂存的文本, 未闭合的工具调用不显示
+    fn finish(&mut self) -> Vec<Part> {
+        let segments = self.think.as_mut().map(TagSplitter::finish).unwrap_or_default();
+        let mut parts = self.split_tools(segments);
+        if let Some(tool) = self.tool.as_mut() {
+            parts.extend(tool.finish().into_iter().filter_map(text_part));
+        }
+        parts
+    }
+
+    fn split_tools(&mut self, segments: Vec<Segment>) -> Vec<Part> {
+        let mut parts = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Tagged(text) => parts.push(Part::Reasoning(text)),
+                Segment::Text(text) => {
+                    self.raw.push_str(&text);
+                    match self.tool.as_mut() {
+                        Some(tool) => {
+                            parts.extend(tool.push(&text).into_iter().filter_map(text_part))
+                        }
+                        None => parts.push(Part::Content(text)),
+                    }
+                }
+            }
+        }
+        parts
+    }
+}
+
+/// 工具调用标签外的文本作为正文
+fn text_part(segment: Segment) -> Option<Part> {
+    match segment {
+        Segment::Text(text) => Some(Part::Content(text)),
+        Segment::Tagged(_) => None,
+    }
+}
+
+/// 发送拆分后的内容, 正文追加到输出中
+async fn send_parts(
+    on_event: &mpsc::Sender<MessageEvent>, parts: Vec<Part>, output: &mut String,
+    first_content: &mut Option<Instant>, now: Instant,
+) -> Result<(), error::Error> {
+    for part in parts {
+        let event = match part {
+            Part::Reasoning(content) => MessageEvent::ReasoningContent { content },
+            Part::Content(content) => {
+                first_content.get_or_insert(now);
+                output.push_str(&content);
+                MessageEvent::Content { content }
+            }
+        };
+        on_event.send(event).await.map_err(|e| error::Error::InvalidData(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// 序列化请求并合并额外参数
+fn request_body(
+    ctx: &ChatContext, request: &CreateChatCompletionRequest,
+) -> Result<Value, error::Error> {
+    let mut body = serde_json::to_value(request)?;
+    if let Value::Object(body) = &mut body {
+        // 值为 null 的额外参数表示从请求中去掉
+        for (key, value) in ctx.extra_body.iter() {
+            match value {
+                Value::Null => body.remove(key),
+                value => body.insert(key.clone(), value.clone()),
+            };
+        }
+    }
+    Ok(body)
+}
+
 async fn call_tools(
     tools: Arc<Vec<Arc<Box<dyn ToolObject>>>>, name: String, args: String,
 ) -> Result<Value, error::Error> {
@@ -64,40 +474,262 @@ async fn call_tools(
     let function_args: serde_json::Value = args.parse()?;
 
     for tool in tools.iter() {
-        if tool.description().iter().any(|desc| desc.name == name) {
+        if let Some(description) = tool.description().into_iter().find(|desc| desc.name == name) {
+            validate_arguments(&description, &function_args)?;
             return tool.call(&name, function_args).await;
         }
     }
 
-    Ok(serde_json::json!({
-        "error": "not func",
-    }))
+    Err(error::Error::InvalidData(format!("function {name} not found")))
+}
+
+/// 单个工具的执行结果
+struct ToolOutput {
+    call: ChatCompletionMessageToolCall,
+    result: Result<Value, String>,
+    cost: i64,
+}
+
+impl ToolOutput {
+    /// 返回给模型的内容, 失败时为结构化的错误信息
+    fn content(&self) -> String {
+        match &self.result {
+            Ok(value) => value.to_string(),
+            Err(error) => serde_json::json!({
+                "status": "error",
+                "error": error,
+            })
+            .to_string(),
+        }
+    }
+
+    fn event(&self) -> MessageEvent {
+        let (id, name, arguments) = (
+            self.call.id.clone(),
+            self.call.function.name.clone(),
+            self.call.function.arguments.clone(),
+        );
+        match &self.result {
+            Ok(value) => MessageEvent::Tool {
+                id,
+                name,
+                arguments,
+                result: value.to_string(),
+                cost: self.cost,
+            },
+            Err(error) => MessageEvent::ToolError {
+                id,
+                name,
+                arguments,
+                error: error.clone(),
+                cost: self.cost,
+            },
+        }
+    }
+}
+
+/// 并发执行工具调用, 按调用顺序返回结果
+async fn execute_tools(
+    ctx: &ChatContext, tool_calls: Vec<ChatCompletionMessageToolCall>,
+) -> Result<Vec<ToolOutput>, error::Error> {
+    let on_event = &ctx.on_event;
+    let mut sets = JoinSet::new();
+    let mut outputs = Vec::new();
+
+    for (index, tool_call) in tool_calls.into_iter().enumerate() {
+        let recorded = ctx
+            .tool_guard
+            .lock()
+            .unwrap()
+            .record(&tool_call.function.name, &tool_call.function.arguments);
+
+        on_event
+            .send(MessageEvent::ToolRunning {
+                id: tool_call.id.clone(),
+                name: tool_call.function.name.clone(),
+                arguments: tool_call.function.arguments.clone(),
+            })
+            .await
+            .map_err(|e| error::Error::InvalidData(e.to_string()))?;
+
+        // 重复的调用不再执行, 直接把原因返回给模型
+        if let Err(detail) = recorded {
+            let result = Err(format!("not executed: {detail}"));
+            let output = ToolOutput { call: tool_call, result, cost: 0 };
+            on_event
+                .send(output.event())
+                .await
+                .map_err(|e| error::Error::InvalidData(e.to_string()))?;
+            outputs.push((index, output));
+            continue;
+        }
+
+        let tool_objects = ctx.tool_objects.clone();
+        let on_event = on_event.clone();
+        sets.spawn(async move {
+            let start = Instant::now();
+            let result = call_tools(
+                tool_objects,
+                tool_call.function.name.clone(),
+                tool_call.function.arguments.clone(),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Function {} error: {}", tool_call.function.name, e);
+                e.to_string()
+            });
+            let cost = start.elapsed().as_millis() as i64;
+            let output = ToolOutput { call: tool_call, result, cost };
+
+            // 每个工具完成后立即通知, 不等待同批次的其他工具
+            if let Err(e) = on_event.send(output.event()).await {
+                tracing::error!("Error sending tool event: {:?}", e);
+            }
+            (index, output)
+        });
+    }
+
+    outputs.extend(sets.join_all().await);
+    outputs.sort_by_key(|(index, _)| *index);
+    Ok(outputs.into_iter().map(|(_, output)| output).collect())
+}
+
+async fn run_tools(
+    ctx: &ChatContext, tool_calls: Vec<ChatCompletionMessageToolCall>,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+) -> Result<Vec<(String, String)>, error::Error> {
+    let outputs = execute_tools(ctx, tool_calls).await?;
+
+    let assistant_messages: ChatCompletionRequestMessage =
+        ChatCompletionRequestAssistantMessageArgs::default()
+            .tool_calls(outputs.iter().map(|output| output.call.clone()).collect::<Vec<_>>())
+            .build()?
+            .into();
+    messages.push(assistant_messages);
+
+    for output in outputs.iter() {
+        let tool_message = ChatCompletionRequestToolMessageArgs::default()
+            .content(output.content())
+            .tool_call_id(output.call.id.clone())
+            .build()?
+            .into();
+        messages.push(tool_message);
+    }
+
+    Ok(outputs
+        .into_iter()
+        .map(|output| (output.call.function.name, output.call.function.arguments))
+        .collect())
+}
+
+/// 从正文中解析提示词方式的工具调用
+fn parse_prompt_tools(
+    ctx: &ChatContext, content: &str, messages: &[ChatCompletionRequestMessage],
+) -> Vec<ChatCompletionMessageToolCall> {
+    if !ctx.prompt_tools || ctx.tool_limit {
+        return Vec::new();
+    }
+    tool_prompt::parse(content, &format!("call_{}", messages.len()))
+}
+
+/// 执行提示词方式的工具调用, 结果以用户消息返回给模型
+async fn run_prompt_tools(
+    ctx: &ChatContext, content: String, tool_calls: Vec<ChatCompletionMessageToolCall>,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+) -> Result<Vec<(String, String)>, error::Error> {
+    let outputs = execute_tools(ctx, tool_calls).await?;
+
+    messages.push(
+        ChatCompletionRequestAssistantMessageArgs::default().content(content).build()?.into(),
+    );
+
+    let results = outputs
+        .iter()
+        .map(|output| tool_prompt::result(&output.call.function.name, &output.content()))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    messages.push(ChatCompletionRequestUserMessageArgs::default().content(results).build()?.into());
+
+    Ok(outputs
+        .into_iter()
+        .map(|output| (output.call.function.name, output.call.function.arguments))
+        .collect())
+}
+
+/// 本地估算提示tokens, 服务商不返回用量时使用
+fn estimate_prompt(request: &CreateChatCompletionRequest) -> TokenEstimator {
+    let mut estimator = TokenEstimator::default();
+    estimator.push(&serde_json::to_string(&request.messages).unwrap_or_default());
+    if let Some(tools) = request.tools.as_ref() {
+        estimator.push(&serde_json::to_string(tools).unwrap_or_default());
+    }
+    estimator
 }
 
 pub async fn chat_stream(
-    client: &Client<OpenAIConfig>, tool_objects: Arc<Vec<Arc<Box<dyn ToolObject>>>>,
-    request: CreateChatCompletionRequest, messages: &mut Vec<ChatCompletionRequestMessage>,
-    on_event: &mpsc::Sender<MessageEvent>,
-) -> Result<(bool, Option<CompletionUsage>), error::Error> {
+    ctx: &ChatContext, mut request: CreateChatCompletionRequest,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+) -> Result<ChatRound, error::Error> {
     // let prompt = messages.pop().unwrap();
 
     // tracing::info!("messages: {:?}", messages);
 
-    let mut stream = client.chat().create_stream(request).await?;
+    let on_event = &ctx.on_event;
+    let start = Instant::now();
+
+    // 要求在最后一个数据块返回用量
+    request.stream_options = Some(ChatCompletionStreamOptions { include_usage: true });
+    request.stream = Some(true);
+    let prompt_estimate = estimate_prompt(&request);
+    let body = request_body(ctx, &request)?;
 
-    let mut tool_call_states: HashMap<(u32, u32), ChatCompletionMessageToolCall> = HashMap::new();
+    let mut stream: ChatCompletionResponseStream = timeout(
+        ctx.timeouts.first_token,
+        "first token",
+        open_stream(&ctx.http, ctx.client.config(), ctx.timeouts.connect, &body),
+    )
+    .await??;
 
-    let tool_call_responses: Arc<Mutex<Vec<(ChatCompletionMessageToolCall, Value)>>> =
-        Arc::new(Mutex::new(Vec::new()));
+    let mut tool_call_states: BTreeMap<(u32, u32), ChatCompletionMessageToolCall> = BTreeMap::new();
+    let mut output = String::new();
+    let mut completion_estimate = TokenEstimator::default();
 
-    while let Some(result) = stream.next().await {
+    let mut usage = None;
+    let mut finish_reason = None;
+    let mut first_token = None;
+    let mut first_content = None;
+
+    let mut splitter = ContentSplitter::new(ctx);
+
+    let mut received = false;
+    loop {
+        // 首个数据的超时从发出请求开始计算
+        let next = if received {
+            timeout(ctx.timeouts.idle, "next chunk", stream.next()).await?
+        } else {
+            let first_token =
+                ctx.timeouts.first_token.map(|duration| duration.saturating_sub(start.elapsed()));
+            timeout(first_token, "first token", stream.next()).await?
+        };
+        let Some(result) = next else {
+            break;
+        };
         let response = result?;
+        received = true;
+
         // tracing::info!("response: {:?}", response.usage);
 
+        // 用量在结束原因之后单独的数据块中返回
+        if response.usage.is_some() {
+            usage = response.usage;
+        }
+
         for chat_choice in response.choices {
             // tracing::info!("chat_choice: {:?}", chat_choice);
 
             if let Some(content) = chat_choice.delta.reasoning_content.filter(|v| !v.is_empty()) {
+                first_token.get_or_insert_with(Instant::now);
+                completion_estimate.push(&content);
                 on_event
                     .send(MessageEvent::ReasoningContent { content })
                     .await
@@ -105,176 +737,301 @@ pub async fn chat_stream(
             }
 
             if let Some(content) = chat_choice.delta.content.filter(|v| !v.is_empty()) {
-                on_event
-                    .send(MessageEvent::Content { content })
-                    .await
-                    .map_err(|e| error::Error::InvalidData(e.to_string()))?;
+                let now = Instant::now();
+                first_token.get_or_insert(now);
+                completion_estimate.push(&content);
+                let parts = splitter.push(&content);
+                send_parts(on_event, parts, &mut output, &mut first_content, now).await?;
             }
 
             if let Some(tool_calls) = chat_choice.delta.tool_calls {
+                first_token.get_or_insert_with(Instant::now);
                 for tool_call_chunk in tool_calls.into_iter() {
                     let key = (chat_choice.index, tool_call_chunk.index);
 
-                    let state = tool_call_states.entry(key).or_insert_with(|| {
-                        ChatCompletionMessageToolCall {
-                            id: tool_call_chunk.id.clone().unwrap_or_default(),
-                            r#type: ChatCompletionToolType::Function,
-                            function: FunctionCall {
-                                name: tool_call_chunk
-                                    .function
-                                    .as_ref()
-                                    .and_then(|f| f.name.clone())
-                                    .unwrap_or_default(),
-                                arguments: String::with_capacity(128),
-                            },
+                    let state = match tool_call_states.entry(key) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let state = entry.insert(ChatCompletionMessageToolCall {
+                                id: tool_call_chunk.id.clone().unwrap_or_default(),
+                                r#type: ChatCompletionToolType::Function,
+                                function: FunctionCall {
+                                    name: tool_call_chunk
+                                        .function
+                                        .as_ref()
+                                        .and_then(|f| f.name.clone())
+                                        .unwrap_or_default(),
+                                    arguments: String::with_capacity(128),
+                                },
+                            });
+                            completion_estimate.push(&state.function.name);
+
+                            on_event
+                                .send(MessageEvent::ToolCallStarted {
+                                    id: state.id.clone(),
+                                    name: state.function.name.clone(),
+                                })
+                                .await
+                                .map_err(|e| error::Error::InvalidData(e.to_string()))?;
+                            state
                         }
-                    });
+                    };
 
-                    if let Some(arguments) =
-                        tool_call_chunk.function.as_ref().and_then(|f| f.arguments.as_ref())
+                    if let Some(arguments) = tool_call_chunk
+                        .function
+                        .as_ref()
+                        .and_then(|f| f.arguments.as_ref())
+                        .filter(|v| !v.is_empty())
                     {
                         state.function.arguments.push_str(arguments);
-                    }
-                }
-            }
-
-            if let Some(finish_reason) = &chat_choice.finish_reason {
-                // tracing::info!("finish_reason: {:?} {:?}", finish_reason, tool_call_states);
-
-                if matches!(finish_reason, FinishReason::Stop) {
-                    return Ok((false, response.usage));
-                }
-
-                // on_event.send(MessageEvent::Finished).map_err(|_| error::Error::Unknown)?;
-                if matches!(finish_reason, FinishReason::ToolCalls) {
-                    let mut sets = JoinSet::new();
-
-                    for (_, tool_call) in tool_call_states.into_iter() {
-                        let tool_objects = tool_objects.clone();
-                        let tool_call_responses = tool_call_responses.clone();
-                        sets.spawn(async move {
-                            let response_content = call_tools(
-                                tool_objects,
-                                tool_call.function.name.clone(),
-                                tool_call.function.arguments.clone(),
-                            )
-                            .await
-                            .unwrap_or_default();
-                            tool_call_responses.lock().await.push((tool_call, response_content));
-                        });
-                    }
-
-                    sets.join_all().await;
-
-                    let tool_call_responses = tool_call_responses.lock().await;
-                    let tool_calls: Vec<ChatCompletionMessageToolCall> =
-                        tool_call_responses.iter().map(|tc| tc.0.clone()).collect();
+                        completion_estimate.push(arguments);
 
-                    for tc in tool_call_responses.iter() {
                         on_event
-                            .send(MessageEvent::Tool {
-                                id: tc.0.id.clone(),
-                                name: tc.0.function.name.clone(),
-                                arguments: tc.0.function.arguments.clone(),
-                                result: tc.1.to_string(),
+                            .send(MessageEvent::ToolCallArguments {
+                                id: state.id.clone(),
+                                delta: arguments.clone(),
                             })
                             .await
                             .map_err(|e| error::Error::InvalidData(e.to_string()))?;
                     }
-
-                    let assistant_messages: ChatCompletionRequestMessage =
-                        ChatCompletionRequestAssistantMessageArgs::default()
-                            .tool_calls(tool_calls)
-                            .build()?
-                            .into();
-                    messages.push(assistant_messages);
-
-                    for tc in tool_call_responses.iter() {
-                        let tool_message = ChatCompletionRequestToolMessageArgs::default()
-                            .content(tc.1.to_string())
-                            .tool_call_id(tc.0.id.clone())
-                            .build()?
-                            .into();
-                        messages.push(tool_message);
-                    }
-                    return Ok((true, response.usage));
                 }
             }
+
+            if let Some(reason) = chat_choice.finish_reason {
+                // tracing::info!("finish_reason: {:?} {:?}", reason, tool_call_states);
+                finish_reason = Some(reason);
+            }
         }
     }
 
-    Ok((false, None))
+    // 输出标签拆分时暂存的文本
+    let parts = splitter.finish();
+    send_parts(on_event, parts, &mut output, &mut first_content, Instant::now()).await?;
+
+    let mut round = ChatRound {
+        usage: usage.or_else(|| Some(estimate_usage(&prompt_estimate, &completion_estimate))),
+        content: output,
+        started: Some(start),
+        first_token,
+        first_content,
+        generation: first_token.map(|t| t.elapsed()).unwrap_or_default(),
+        truncated: truncated(finish_reason.as_ref()),
+        ..Default::default()
+    };
+
+    if matches!(finish_reason, Some(FinishReason::ToolCalls)) {
+        round.tool_calls =
+            run_tools(ctx, tool_call_states.into_values().collect(), messages).await?;
+        round.is_continue = true;
+    } else {
+        let tool_calls = parse_prompt_tools(ctx, &splitter.raw, messages);
+        if !tool_calls.is_empty() {
+            round.tool_calls = run_prompt_tools(ctx, splitter.raw, tool_calls, messages).await?;
+            round.is_continue = true;
+        }
+    }
+
+    Ok(round)
 }
 
 pub async fn chat(
-    client: &Client<OpenAIConfig>, tool_objects: Arc<Vec<Arc<Box<dyn ToolObject>>>>,
-    request: CreateChatCompletionRequest, messages: &mut Vec<ChatCompletionRequestMessage>,
-    on_event: &mpsc::Sender<MessageEvent>,
-) -> Result<(bool, Option<CompletionUsage>), error::Error> {
+    ctx: &ChatContext, request: CreateChatCompletionRequest,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+) -> Result<ChatRound, error::Error> {
     // tracing::info!("messages: {:?}", messages);
 
-    let response = client.chat().create(request).await?;
+    let on_event = &ctx.on_event;
+    let start = Instant::now();
+    let prompt_estimate = estimate_prompt(&request);
+    let body = request_body(ctx, &request)?;
+
+    let response: CreateChatCompletionResponse =
+        timeout(ctx.timeouts.first_token, "response", ctx.client.chat().create_byot(body))
+            .await??;
 
     // tracing::info!("response: {:?}", response.usage);
 
+    // 非流式请求收到响应时即为首个token
+    let received = Instant::now();
+    let mut round = ChatRound {
+        started: Some(start),
+        first_token: Some(received),
+        generation: start.elapsed(),
+        ..Default::default()
+    };
+    let mut completion_estimate = TokenEstimator::default();
+
     for choice in response.choices {
+        round.truncated = round.truncated.or(truncated(choice.finish_reason.as_ref()));
+
         if let Some(content) = choice.message.reasoning_content {
+            completion_estimate.push(&content);
             on_event
                 .send(MessageEvent::ReasoningContent { content })
                 .await
                 .map_err(|e| error::Error::InvalidData(e.to_string()))?;
         }
 
-        if let Some(content) = choice.message.content {
-            on_event
-                .send(MessageEvent::Content { content })
-                .await
-                .map_err(|e| error::Error::InvalidData(e.to_string()))?;
+        if let Some(content) = choice.message.content.filter(|v| !v.is_empty()) {
+            completion_estimate.push(&content);
+            let mut splitter = ContentSplitter::new(ctx);
+            let mut parts = splitter.push(&content);
+            parts.extend(splitter.finish());
+            send_parts(on_event, parts, &mut round.content, &mut round.first_content, received)
+                .await?;
+
+            let tool_calls = parse_prompt_tools(ctx, &splitter.raw, messages);
+            if !tool_calls.is_empty() {
+                round.usage = response
+                    .usage
+                    .or_else(|| Some(estimate_usage(&prompt_estimate, &completion_estimate)));
+                round.tool_calls =
+                    run_prompt_tools(ctx, splitter.raw, tool_calls, messages).await?;
+                round.is_continue = true;
+                return Ok(round);
+            }
         }
 
         if let Some(tool_calls) = choice.message.tool_calls {
-            let mut sets = JoinSet::new();
-
-            for tool_call in tool_calls {
-                let name = tool_call.function.name.clone();
-                let args = tool_call.function.arguments.clone();
+            for tool_call in tool_calls.iter() {
+                completion_estimate.push(&tool_call.function.name);
+                completion_estimate.push(&tool_call.function.arguments);
+            }
+            round.usage = response
+                .usage
+                .or_else(|| Some(estimate_usage(&prompt_estimate, &completion_estimate)));
+            round.tool_calls = run_tools(ctx, tool_calls, messages).await?;
+            round.is_continue = true;
+            return Ok(round);
+        }
+    }
 
-                let tool_objects = tool_objects.clone();
-                let tool_call_clone = tool_call.clone();
+    round.usage =
+        response.usage.or_else(|| Some(estimate_usage(&prompt_estimate, &completion_estimate)));
+    Ok(round)
+}
 
-                sets.spawn(async move {
-                    (
-                        tool_call_clone,
-                        call_tools(tool_objects, name, args).await.unwrap_or_default(),
-                    )
-                });
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let tool_responses = sets.join_all().await;
-
-            let tool_calls: Vec<ChatCompletionMessageToolCall> = tool_responses
-                .iter()
-                .map(|(tool_call, _response_content)| tool_call.clone())
-                .collect();
-
-            let assistant_messages: ChatCompletionRequestMessage =
-                ChatCompletionRequestAssistantMessageArgs::default()
-                    .tool_calls(tool_calls)
-                    .build()?
-                    .into();
-            messages.push(assistant_messages);
-
-            for (tool_call, response_content) in tool_responses {
-                let tool_message = ChatCompletionRequestToolMessageArgs::default()
-                    .content(response_content.to_string())
-                    .tool_call_id(tool_call.id.clone())
-                    .build()?
-                    .into();
-                messages.push(tool_message);
-            }
-            return Ok((true, response.usage));
+    #[test]
+    fn test_tool_guard_rounds() {
+        let mut guard = ToolGuard::new(3);
+        for round in 0..2 {
+            guard.record("search", &format!("{{\"q\": {round}}}")).unwrap();
+            assert!(guard.finish_round().is_none());
         }
+        let (reason, detail) = guard.finish_round().unwrap();
+        assert_eq!(reason, ToolLimitReason::MaxRounds);
+        assert_eq!(detail, "maximum of 3 tool rounds reached");
+        assert_eq!(guard.rounds(), 3);
+
+        // 未设置时使用默认轮数
+        let mut guard = ToolGuard::new(0);
+        assert!((1..DEFAULT_MAX_TOOL_ROUNDS).all(|_| guard.finish_round().is_none()));
+        assert!(guard.finish_round().is_some());
+    }
+
+    #[test]
+    fn test_tool_guard_repeated() {
+        let mut guard = ToolGuard::new(10);
+        // 忽略字段顺序和空白的差异
+        guard.record("search", r#"{"q": "rust", "n": 1}"#).unwrap();
+        guard.record("search", r#"{"n":1,"q":"rust"}"#).unwrap();
+        // 参数不同的调用仍然执行
+        guard.record("search", r#"{"q": "go"}"#).unwrap();
+        assert!(guard.finish_round().is_none());
+
+        // 超过次数的调用在执行前被拒绝, 本轮结束时停止工具调用
+        let detail = guard.record("search", r#"{"q":"rust","n":1}"#).unwrap_err();
+        assert_eq!(detail, "function search was called 3 times with the same arguments");
+        assert_eq!(guard.finish_round(), Some((ToolLimitReason::RepeatedCall, detail)));
+        assert!(guard.finish_round().is_none());
     }
 
-    Ok((false, response.usage))
+    /// 只处理一次请求的本地服务, 延迟后返回指定的响应
+    async fn serve_once(delay: Duration, response: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let _ = socket.read(&mut request).await;
+            tokio::time::sleep(delay).await;
+            socket.write_all(response.as_bytes()).await.unwrap();
+            // 读完剩余的请求再关闭, 避免客户端收到连接重置
+            let _ = socket.read_to_end(&mut Vec::new()).await;
+        });
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_errors() {
+        let connect = Duration::from_millis(200);
+        let http = reqwest::Client::builder().connect_timeout(connect).build().unwrap();
+        let body = serde_json::json!({ "model": "test" });
+
+        // 连接已建立, 超过连接超时后返回的 HTTP 错误不是超时
+        let url = serve_once(
+            Duration::from_millis(500),
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 5\r\nconnection: close\r\n\r\nerror",
+        )
+        .await;
+        let config = OpenAIConfig::new().with_api_base(url);
+        let e = open_stream(&http, &config, Some(connect), &body).await.err().unwrap();
+        assert!(!e.is_timeout());
+        assert!(e.to_string().contains("500 Internal Server Error: error"));
+
+        // 连接被拒绝
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let config = OpenAIConfig::new().with_api_base(format!("http://{addr}/v1"));
+        let e = open_stream(&http, &config, Some(connect), &body).await.err().unwrap();
+        assert!(!e.is_timeout());
+
+        // 正常的事件流
+        let url = serve_once(
+            Duration::ZERO,
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n\
+             data: {\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":0,\"model\":\"m\",\
+             \"choices\":[{\"index\":0,\"delta\":{\"content\":\"ok\"}}]}\n\ndata: [DONE]\n\n",
+        )
+        .await;
+        let config = OpenAIConfig::new().with_api_base(url);
+        let stream = open_stream(&http, &config, Some(connect), &body).await.unwrap();
+        let responses = stream.collect::<Vec<_>>().await;
+        assert_eq!(responses.len(), 1);
+        let response = responses.into_iter().next().unwrap().unwrap();
+        assert_eq!(response.choices[0].delta.content.as_deref(), Some("ok"));
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let chunk = |content: &str| {
+            format!(
+                "data: {{\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":0,\
+                 \"model\":\"m\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{content}\"}}}}]}}\r\n\r\n"
+            )
+        };
+        // 事件被拆在多个数据块中, 心跳注释跳过, [DONE] 之后的数据忽略
+        let text =
+            format!(": ping\n\n{}{}data: [DONE]\n\n{}", chunk("你好"), chunk("世界"), chunk("!"));
+        let bytes =
+            text.as_bytes().chunks(7).map(|b| Ok(b.to_vec())).collect::<Vec<reqwest::Result<_>>>();
+
+        let contents = event_stream(futures::stream::iter(bytes))
+            .map(|response| response.unwrap().choices[0].delta.content.clone().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(contents, vec!["你好", "世界"]);
+
+        // 无法解析的数据返回错误
+        let bytes = vec![Ok(b"data: {\n\n".to_vec())];
+        let results = event_stream(futures::stream::iter(bytes)).collect::<Vec<_>>().await;
+        assert!(matches!(results.as_slice(), [Err(OpenAIError::StreamError(_))]));
+    }
 }
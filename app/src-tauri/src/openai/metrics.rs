@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+use async_openai::types::CompletionUsage;
+
+use crate::openai::chat::ChatRound;
+
+/// 一次回复中各轮请求的用量和耗时
+#[derive(Debug, Default)]
+pub struct ReplyMetrics {
+    pub usages: Vec<CompletionUsage>,
+    /// 发送第一轮请求的时间, 不包括联网搜索和连接 MCP 服务等准备工作
+    pub started: Option<Instant>,
+    /// 收到首个token的时间, 包括思考内容和工具调用
+    pub first_token: Option<Instant>,
+    /// 收到首个正文内容的时间, 思考结束之后
+    pub first_content: Option<Instant>,
+    /// 模型输出的总时长, 用于计算输出速度
+    pub generation: Duration,
+}
+
+impl ReplyMetrics {
+    /// 记录一轮请求
+    pub fn add(&mut self, round: &ChatRound) {
+        if let Some(usage) = round.usage.as_ref() {
+            self.usages.push(usage.clone());
+        }
+        if self.started.is_none() {
+            self.started = round.started;
+        }
+        if self.first_token.is_none() {
+            self.first_token = round.first_token;
+        }
+        if self.first_content.is_none() {
+            self.first_content = round.first_content;
+        }
+        self.generation += round.generation;
+    }
+
+    /// 从发送第一轮请求到收到首个token的毫秒数
+    pub fn first_token_ms(&self) -> Option<i64> {
+        self.since_started(self.first_token)
+    }
+
+    /// 从发送第一轮请求到收到首个正文内容的毫秒数
+    pub fn first_content_ms(&self) -> Option<i64> {
+        self.since_started(self.first_content)
+    }
+
+    fn since_started(&self, instant: Option<Instant>) -> Option<i64> {
+        Some(instant?.saturating_duration_since(self.started?).as_millis() as i64)
+    }
+
+    /// 每秒输出的tokens
+    pub fn tokens_per_second(&self, completion_tokens: u32) -> Option<f64> {
+        let seconds = self.generation.as_secs_f64();
+        (seconds > 0.0 && completion_tokens > 0).then(|| completion_tokens as f64 / seconds)
+    }
+}
+
+/// 服务商不返回用量时, 在本地估算tokens
+///
+/// 中日韩文字大约每个字一个token, 其它文字大约每4个字符一个token
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokenEstimator {
+    cjk: usize,
+    other: usize,
+}
+
+impl TokenEstimator {
+    pub fn push(&mut self, text: &str) {
+        for c in text.chars() {
+            if is_cjk(c) {
+                self.cjk += 1;
+            } else {
+                self.other += 1;
+            }
+        }
+    }
+
+    pub fn tokens(&self) -> u32 {
+        (self.cjk + self.other.div_ceil(4)) as u32
+    }
+}
+
+/// 估算的用量
+pub fn estimate_usage(prompt: &TokenEstimator, completion: &TokenEstimator) -> CompletionUsage {
+    let prompt_tokens = prompt.tokens();
+    let completion_tokens = completion.tokens();
+    CompletionUsage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+        prompt_tokens_details: None,
+        completion_tokens_details: None,
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'   // 日文假名
+        | '\u{3400}'..='\u{4dbf}' // 扩展A
+        | '\u{4e00}'..='\u{9fff}' // 基本汉字
+        | '\u{ac00}'..='\u{d7af}' // 韩文
+        | '\u{f900}'..='\u{faff}' // 兼容汉字
+        | '\u{3000}'..='\u{303f}' // 中文标点
+        | '\u{ff00}'..='\u{ffef}' // 全角字符
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+
+    #[test]
+    fn test_reply_metrics() {
+        // 联网搜索等准备工作不计入首个token的时间
+        let prepared = Instant::now();
+        let started = prepared + Duration::from_millis(500);
+        let first = ChatRound {
+            usage: Some(usage(10, 20)),
+            started: Some(started),
+            first_token: Some(started + Duration::from_millis(100)),
+            generation: Duration::from_secs(2),
+            ..Default::default()
+        };
+        // 工具调用后的一轮才有正文
+        let second = ChatRound {
+            usage: Some(usage(30, 40)),
+            started: Some(started + Duration::from_secs(3)),
+            first_token: Some(started + Duration::from_secs(4)),
+            first_content: Some(started + Duration::from_secs(5)),
+            generation: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let mut metrics = ReplyMetrics::default();
+        assert_eq!(metrics.first_token_ms(), None);
+        metrics.add(&first);
+        metrics.add(&second);
+
+        assert_eq!(metrics.usages.len(), 2);
+        assert_eq!(metrics.first_token_ms(), Some(100));
+        assert_eq!(metrics.first_content_ms(), Some(5000));
+        assert_eq!(metrics.generation, Duration::from_secs(4));
+        assert_eq!(metrics.tokens_per_second(60), Some(15.0));
+        assert_eq!(ReplyMetrics::default().tokens_per_second(60), None);
+    }
+
+    #[test]
+    fn test_token_estimator() {
+        let mut prompt = TokenEstimator::default();
+        prompt.push("你好, ");
+        prompt.push("world");
+        // 2个汉字, 7个其它字符
+        assert_eq!(prompt.tokens(), 4);
+
+        let mut completion = TokenEstimator::default();
+        completion.push("こんにちは。");
+        let usage = estimate_usage(&prompt, &completion);
+        assert_eq!(usage.prompt_tokens, 4);
+        assert_eq!(usage.completion_tokens, 6);
+        assert_eq!(usage.total_tokens, 10);
+    }
+}
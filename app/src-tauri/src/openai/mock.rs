@@ -0,0 +1,506 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::error;
+
+/// 转发录制时不发送给上游的请求头
+const SKIP_HEADERS: [&str; 4] = ["host", "content-length", "connection", "accept-encoding"];
+
+/// 录制的请求, 用于匹配回放的响应
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    /// JSON 请求体, 不是 JSON 时为字符串
+    pub body: Value,
+}
+
+/// 录制的数据块
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MockChunk {
+    /// 距上一个数据块的间隔, 单位毫秒
+    #[serde(default)]
+    pub delay: u64,
+    pub data: String,
+}
+
+/// 录制的响应, 流式响应按收到的数据块保存
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+    pub chunks: Vec<MockChunk>,
+}
+
+/// 录制文件, 相同的请求按录制顺序依次回放, 用于测试重试
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Fixture {
+    pub request: MockRequest,
+    pub responses: Vec<MockResponse>,
+}
+
+impl MockRequest {
+    fn new(method: &str, path: &str, body: &[u8]) -> Self {
+        let body = serde_json::from_slice::<Value>(body)
+            .map(canonical)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+        Self { method: method.to_string(), path: path.to_string(), body }
+    }
+
+    /// 录制文件名, 由请求方法, 路径和请求体计算
+    pub fn key(&self) -> String {
+        // FNV-1a, 不同版本和平台的结果一致
+        let text = format!("{} {} {}", self.method, self.path, self.body);
+        let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{hash:016x}")
+    }
+}
+
+/// 对象的键按字母排序, 相同内容的请求得到相同的文件名
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries = object.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries.into_iter().map(|(k, v)| (k, canonical(v))).collect::<Map<_, _>>(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical).collect()),
+        value => value,
+    }
+}
+
+/// 进程内的模拟服务, 录制时转发到上游, 回放时从录制文件返回
+struct MockServer {
+    mode: store::MockMode,
+    dir: PathBuf,
+    upstream: String,
+    http: reqwest::Client,
+    /// 每个请求已经回放的次数
+    served: Mutex<HashMap<String, usize>>,
+    /// 串行写入录制文件
+    writing: Mutex<()>,
+}
+
+/// 已启动的模拟服务地址, 按模式, 目录和上游区分
+static SERVERS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// 启动模拟模型提供商的服务, 返回作为 api_base 的地址, 相同配置只启动一次
+pub fn serve(provider: &store::Provider) -> Result<String, error::Error> {
+    let config = provider.mock.clone().ok_or_else(|| {
+        error::Error::InvalidData(format!("Provider {} has no mock config", provider.name))
+    })?;
+    if config.dir.is_empty() {
+        return Err(error::Error::InvalidData("Mock fixture dir is empty".to_string()));
+    }
+
+    let key = format!("{:?}:{}:{}", config.mode, config.dir, provider.url);
+    let mut servers =
+        SERVERS.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(url) = servers.get(&key) {
+        return Ok(url.clone());
+    }
+
+    std::fs::create_dir_all(&config.dir)?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let url = format!("http://{}", listener.local_addr()?);
+
+    let server = Arc::new(MockServer {
+        mode: config.mode,
+        dir: PathBuf::from(&config.dir),
+        upstream: provider.url.trim_end_matches('/').to_string(),
+        http: reqwest::Client::new(),
+        served: Mutex::default(),
+        writing: Mutex::default(),
+    });
+    tauri::async_runtime::spawn(async move {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("Mock server listen error: {e}");
+                return;
+            }
+        };
+        while let Ok((stream, _)) = listener.accept().await {
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(stream).await {
+                    tracing::warn!("Mock server error: {e}");
+                }
+            });
+        }
+    });
+
+    tracing::info!("Mock provider {} ({:?}) listening on {url}", provider.name, config.mode);
+    servers.insert(key, url.clone());
+    Ok(url)
+}
+
+impl MockServer {
+    /// 每个连接处理一个请求, 响应后关闭连接
+    async fn handle(&self, stream: TcpStream) -> Result<(), error::Error> {
+        let mut reader = BufReader::new(stream);
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Err(error::Error::InvalidData(format!("Invalid request line {line:?}")));
+        };
+        let (method, path) = (method.to_string(), path.to_string());
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+            }
+        }
+
+        let length = headers
+            .iter()
+            .find(|(name, _)| name == "content-length")
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .unwrap_or_default();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await?;
+
+        let mut stream = reader.into_inner();
+        let request = MockRequest::new(&method, &path, &body);
+        match self.mode {
+            store::MockMode::Replay => self.replay(&mut stream, request).await,
+            store::MockMode::Record => self.record(&mut stream, request, headers, body).await,
+        }
+    }
+
+    fn fixture_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    async fn replay(
+        &self, stream: &mut TcpStream, request: MockRequest,
+    ) -> Result<(), error::Error> {
+        let key = request.key();
+        let fixture = read_fixture(&self.fixture_path(&key));
+        let Some(fixture) = fixture.filter(|fixture| !fixture.responses.is_empty()) else {
+            let message =
+                format!("Mock fixture {key} not found for {} {}", request.method, request.path);
+            let body = serde_json::json!({ "error": { "message": message, "type": "mock_error" } });
+            let response = MockResponse {
+                status: 404,
+                content_type: "application/json".to_string(),
+                chunks: vec![MockChunk { delay: 0, data: body.to_string() }],
+            };
+            return write_response(stream, &response).await;
+        };
+
+        // 超过录制次数时重复最后一个响应
+        let index = {
+            let mut served = self.served.lock().unwrap_or_else(|e| e.into_inner());
+            let count = served.entry(key).or_default();
+            *count += 1;
+            (*count - 1).min(fixture.responses.len() - 1)
+        };
+        write_response(stream, &fixture.responses[index]).await
+    }
+
+    async fn record(
+        &self, stream: &mut TcpStream, request: MockRequest, headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Result<(), error::Error> {
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .map_err(|e| error::Error::InvalidData(e.to_string()))?;
+        let mut builder =
+            self.http.request(method, format!("{}{}", self.upstream, request.path)).body(body);
+        for (name, value) in
+            headers.iter().filter(|(name, _)| !SKIP_HEADERS.contains(&name.as_str()))
+        {
+            builder = builder.header(name, value);
+        }
+
+        let upstream = builder.send().await?;
+        let mut response = MockResponse {
+            status: upstream.status().as_u16(),
+            content_type: upstream
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("application/json")
+                .to_string(),
+            chunks: Vec::new(),
+        };
+        write_head(stream, &response).await?;
+
+        // 边转发边录制, 保留数据块之间的间隔
+        let mut last = Instant::now();
+        let mut chunks = upstream.bytes_stream();
+        let mut pending = Vec::new();
+        let mut result = Ok(());
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            pending.extend_from_slice(&chunk);
+            let data = take_utf8(&mut pending);
+            if !data.is_empty() {
+                response.chunks.push(MockChunk { delay: last.elapsed().as_millis() as u64, data });
+                last = Instant::now();
+            }
+            // 客户端断开时继续录制完整的响应
+            if stream.write_all(&chunk).await.is_ok() {
+                let _ = stream.flush().await;
+            }
+        }
+        if !pending.is_empty() {
+            response.chunks.push(MockChunk {
+                delay: last.elapsed().as_millis() as u64,
+                data: String::from_utf8_lossy(&pending).into_owned(),
+            });
+        }
+        let _ = stream.shutdown().await;
+
+        self.save(request, response)?;
+        result
+    }
+
+    /// 追加到录制文件, 相同的请求按顺序保存多个响应
+    fn save(&self, request: MockRequest, response: MockResponse) -> Result<(), error::Error> {
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        let path = self.fixture_path(&request.key());
+
+        let mut fixture = read_fixture(&path)
+            .unwrap_or_else(|| Fixture { request: request.clone(), responses: Vec::new() });
+        fixture.responses.push(response);
+        std::fs::write(&path, serde_json::to_string_pretty(&fixture)?)?;
+        Ok(())
+    }
+}
+
+/// 取出缓冲中完整的 UTF-8 文本, 被数据块拆开的字符留在缓冲中等待下一块
+fn take_utf8(buffer: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(buffer) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        // 无效的字节无法补全, 按有损转换录制
+        _ => buffer.len(),
+    };
+    let rest = buffer.split_off(complete);
+    let text = String::from_utf8_lossy(buffer).into_owned();
+    *buffer = rest;
+    text
+}
+
+fn read_fixture(path: &Path) -> Option<Fixture> {
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+async fn write_head(
+    stream: &mut (impl AsyncWrite + Unpin), response: &MockResponse,
+) -> Result<(), error::Error> {
+    let reason = reqwest::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown");
+    // 不设置长度, 以关闭连接表示响应结束, 流式响应可以逐块发送
+    let head = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.status, response.content_type
+    );
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+/// 按录制的间隔发送响应, 可以测试超时和取消
+async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin), response: &MockResponse,
+) -> Result<(), error::Error> {
+    write_head(stream, response).await?;
+    for chunk in &response.chunks {
+        if chunk.delay > 0 {
+            tokio::time::sleep(Duration::from_millis(chunk.delay)).await;
+        }
+        stream.write_all(chunk.data.as_bytes()).await?;
+        stream.flush().await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(mode: store::MockMode, dir: &Path, url: &str) -> store::Provider {
+        store::Provider {
+            id: 1,
+            name: "mock".to_string(),
+            api_category: "mock".to_string(),
+            url: url.to_string(),
+            api_key: Some("sk-test".to_string()),
+            models: None,
+            connect_timeout: 0,
+            first_token_timeout: 0,
+            idle_timeout: 0,
+            think_tags: None,
+            mock: Some(store::MockConfig { mode, dir: dir.to_string_lossy().into_owned() }),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let root = std::env::temp_dir().join(format!("causal-mock-{}", std::process::id()));
+        let (upstream_dir, record_dir) = (root.join("upstream"), root.join("record"));
+        std::fs::create_dir_all(&upstream_dir).unwrap();
+
+        // 作为上游的回放服务, 同一请求第一次失败, 第二次返回流式响应
+        let body = serde_json::json!({ "stream": true, "model": "test", "messages": [] });
+        let request = MockRequest::new("POST", "/chat/completions", body.to_string().as_bytes());
+        let sse = |data: &str| MockChunk { delay: 0, data: format!("data: {data}\n\n") };
+        let fixture = Fixture {
+            request: request.clone(),
+            responses: vec![
+                MockResponse {
+                    status: 500,
+                    content_type: "application/json".to_string(),
+                    chunks: vec![MockChunk { delay: 0, data: "{}".to_string() }],
+                },
+                MockResponse {
+                    status: 200,
+                    content_type: "text/event-stream".to_string(),
+                    chunks: vec![sse("{\"id\":\"1\"}"), sse("[DONE]")],
+                },
+            ],
+        };
+        let fixture_path = upstream_dir.join(format!("{}.json", request.key()));
+        std::fs::write(&fixture_path, serde_json::to_string(&fixture).unwrap()).unwrap();
+
+        let upstream = serve(&provider(store::MockMode::Replay, &upstream_dir, "")).unwrap();
+        let recorder = serve(&provider(store::MockMode::Record, &record_dir, &upstream)).unwrap();
+
+        // 键的顺序不同也匹配同一个录制文件
+        let reordered = r#"{"messages":[],"model":"test","stream":true}"#;
+        let client = reqwest::Client::new();
+        let send = || {
+            client
+                .post(format!("{recorder}/chat/completions"))
+                .bearer_auth("sk-test")
+                .header("Content-Type", "application/json")
+                .body(reordered)
+                .send()
+        };
+        assert_eq!(send().await.unwrap().status(), 500);
+        let response = send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "data: {\"id\":\"1\"}\n\ndata: [DONE]\n\n");
+
+        // 录制的文件与上游的响应一致
+        let recorded = read_fixture(&record_dir.join(format!("{}.json", request.key()))).unwrap();
+        assert_eq!(recorded.responses.len(), 2);
+        assert_eq!(recorded.responses[1].content_type, "text/event-stream");
+        let data = recorded.responses[1].chunks.iter().map(|c| c.data.as_str()).collect::<String>();
+        assert_eq!(data, "data: {\"id\":\"1\"}\n\ndata: [DONE]\n\n");
+
+        // 没有录制的请求返回错误
+        let missing = client.get(format!("{upstream}/models")).send().await.unwrap();
+        assert_eq!(missing.status(), 404);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_take_utf8() {
+        let bytes = "你好".as_bytes();
+        let mut buffer = bytes[..4].to_vec();
+        assert_eq!(take_utf8(&mut buffer), "你");
+        assert_eq!(buffer, &bytes[3..4]);
+
+        buffer.extend_from_slice(&bytes[4..]);
+        assert_eq!(take_utf8(&mut buffer), "好");
+        assert!(buffer.is_empty());
+
+        // 无效的字节不等待后续数据
+        let mut buffer = vec![b'a', 0xff, b'b'];
+        assert_eq!(take_utf8(&mut buffer), "a\u{fffd}b");
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_record_split_characters() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // 上游把中文字符拆在两个数据块中发送
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        let data = "data: {\"content\":\"你好世界\"}\n\ndata: [DONE]\n\n".as_bytes();
+        let split = data.iter().position(|&b| b >= 0x80).unwrap() + 1;
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let _ = socket.read(&mut request).await;
+            let head =
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&data[..split]).await.unwrap();
+            socket.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            socket.write_all(&data[split..]).await.unwrap();
+            let _ = socket.shutdown().await;
+            let _ = socket.read_to_end(&mut Vec::new()).await;
+        });
+
+        let recorder = serve(&provider(store::MockMode::Record, dir.path(), &upstream)).unwrap();
+        let body = r#"{"model":"test","stream":true}"#;
+        let response = reqwest::Client::new()
+            .post(format!("{recorder}/chat/completions"))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.bytes().await.unwrap(), data);
+
+        // 录制的每个数据块都是完整的字符, 拼接后与上游一致
+        let request = MockRequest::new("POST", "/chat/completions", body.as_bytes());
+        let recorded = read_fixture(&dir.path().join(format!("{}.json", request.key()))).unwrap();
+        let chunks = &recorded.responses[0].chunks;
+        assert!(chunks.iter().all(|c| !c.data.contains('\u{fffd}')));
+        assert_eq!(
+            chunks.iter().map(|c| c.data.as_str()).collect::<String>(),
+            "data: {\"content\":\"你好世界\"}\n\ndata: [DONE]\n\n"
+        );
+
+        // 回放的响应与上游一致
+        let replayer = serve(&provider(store::MockMode::Replay, dir.path(), "")).unwrap();
+        let response = reqwest::Client::new()
+            .post(format!("{replayer}/chat/completions"))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.bytes().await.unwrap(), data);
+    }
+}
@@ -1,14 +1,52 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
 
-use async_openai::types::{
-    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
-    ChatCompletionRequestUserMessageArgs,
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestUserMessageArgs,
+    },
 };
 
 use crate::error;
 
 pub mod chat;
+pub mod metrics;
+pub mod mock;
+pub mod prompt;
+pub mod reasoning;
+pub mod structured;
+pub mod tag;
 pub mod tool;
+pub mod tool_prompt;
+
+/// 根据模型提供商的配置创建客户端
+pub fn client(provider: &store::Provider) -> Result<Client<OpenAIConfig>, error::Error> {
+    // 模拟模型提供商通过本地服务录制或回放
+    let api_base = if provider.api_category.eq_ignore_ascii_case("mock") {
+        mock::serve(provider)?
+    } else {
+        provider.url.clone()
+    };
+    let config = OpenAIConfig::new()
+        .with_api_base(api_base)
+        .with_api_key(provider.api_key.clone().unwrap_or_default());
+
+    Ok(Client::with_config(config).with_http_client(http_client(provider)?))
+}
+
+/// 按模型提供商的连接超时创建 HTTP 客户端
+pub fn http_client(provider: &store::Provider) -> Result<reqwest::Client, error::Error> {
+    let mut http_client = reqwest::Client::builder();
+    if provider.connect_timeout > 0 {
+        http_client = http_client.connect_timeout(Duration::from_secs(provider.connect_timeout));
+    }
+    Ok(http_client.build()?)
+}
 
 pub struct Message(store::ChatMessage);
 
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use minijinja::Environment;
+
+use crate::error;
+
+/// 内置变量, 自定义变量不能使用这些名称
+const BUILTIN_VARIABLES: &[&str] =
+    &["date", "time", "datetime", "weekday", "timezone", "topic", "agent", "user"];
+
+/// 检查智能体的提示词模板, 语法错误或引用了未定义的变量时返回错误, 未开启模板时不检查
+pub fn check(agent: &store::Agent) -> Result<(), error::Error> {
+    if !agent.prompt_template {
+        return Ok(());
+    }
+
+    for variable in agent.variables.iter() {
+        if BUILTIN_VARIABLES.contains(&variable.name.as_str()) {
+            return Err(error::Error::Template(format!(
+                "variable {} conflicts with a built-in variable",
+                variable.name
+            )));
+        }
+    }
+
+    let env = Environment::new();
+    let template =
+        env.template_from_str(&agent.prompt).map_err(|e| error::Error::Template(e.to_string()))?;
+
+    let mut unknown = template
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|name| {
+            !BUILTIN_VARIABLES.contains(&name.as_str())
+                && !agent.variables.iter().any(|v| &v.name == name)
+        })
+        .collect::<Vec<_>>();
+
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(error::Error::Template(format!("undefined variables: {}", unknown.join(", "))));
+    }
+    Ok(())
+}
+
+/// 渲染系统提示词, 自定义变量优先使用会话设置的值, 未开启模板时原样返回
+pub fn render(agent: &store::Agent, session: &store::ChatSession) -> Result<String, error::Error> {
+    if !agent.prompt_template {
+        return Ok(agent.prompt.clone());
+    }
+
+    let now = chrono::Local::now();
+
+    let mut context = HashMap::new();
+    for variable in agent.variables.iter() {
+        let value = session.variables.get(&variable.name).or(variable.default.as_ref());
+        context.insert(variable.name.as_str(), value.cloned().unwrap_or_default());
+    }
+
+    context.insert("date", now.format("%Y-%m-%d").to_string());
+    context.insert("time", now.format("%H:%M:%S").to_string());
+    context.insert("datetime", now.format("%Y-%m-%d %H:%M:%S").to_string());
+    context.insert("weekday", now.format("%A").to_string());
+    context.insert(
+        "timezone",
+        iana_time_zone::get_timezone().unwrap_or_else(|_| now.format("UTC%:z").to_string()),
+    );
+    context.insert("topic", session.topic.clone());
+    context.insert("agent", agent.name.clone());
+    context.insert(
+        "user",
+        std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default(),
+    );
+
+    Environment::new()
+        .render_str(&agent.prompt, context)
+        .map_err(|e| error::Error::Template(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(prompt: &str, template: bool) -> store::Agent {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "categoryId": 1,
+            "name": "助手",
+            "prompt": prompt,
+            "variables": [{ "name": "language", "default": "中文" }],
+            "promptTemplate": template,
+            "temperature": 1.0,
+            "maxTokens": 0,
+            "contextSize": 5,
+            "createdAt": 0
+        }))
+        .unwrap()
+    }
+
+    fn session(variables: serde_json::Value) -> store::ChatSession {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "agentId": 1,
+            "topic": "测试",
+            "variables": variables,
+            "createdAt": 0
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(
+            check(&agent("{{ agent }} 使用 {{ language }} 回答, 今天是 {{ date }}", true)).is_ok()
+        );
+
+        let error = check(&agent("{{ unknown }}", true)).unwrap_err().to_string();
+        assert!(error.contains("undefined variables: unknown"), "{error}");
+        assert!(check(&agent("{% if %}", true)).is_err());
+
+        // 自定义变量与内置变量重名
+        let mut conflict = agent("", true);
+        conflict.variables[0].name = "date".to_string();
+        assert!(check(&conflict).is_err());
+
+        // 未开启模板的旧提示词包含 JSON 和模板标记
+        assert!(check(&agent(r#"按 {"a": {{1}}} 格式回复 {% raw"#, false)).is_ok());
+    }
+
+    #[test]
+    fn test_render() {
+        let templated = agent("{{ agent }}: {{ language }}, {{ topic }}", true);
+        assert_eq!(
+            render(&templated, &session(serde_json::json!({}))).unwrap(),
+            "助手: 中文, 测试"
+        );
+
+        // 会话设置的值优先于默认值
+        let variables = serde_json::json!({ "language": "English" });
+        assert_eq!(render(&templated, &session(variables)).unwrap(), "助手: English, 测试");
+
+        // 未开启模板时原样返回
+        let legacy = agent(r#"按 {"a": {{1}}} 格式回复 {% raw"#, false);
+        assert_eq!(
+            render(&legacy, &session(serde_json::json!({}))).unwrap(),
+            r#"按 {"a": {{1}}} 格式回复 {% raw"#
+        );
+    }
+}
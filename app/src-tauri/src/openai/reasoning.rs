@@ -0,0 +1,158 @@
+use serde_json::{Map, Value, json};
+
+use store::ReasoningEffort;
+
+/// Anthropic 思考预算的最小值
+const MIN_ANTHROPIC_BUDGET: u32 = 1024;
+
+/// 思考强度对应的预算, 用于只支持预算的服务商
+fn effort_budget(effort: ReasoningEffort) -> u32 {
+    match effort {
+        ReasoningEffort::Low => 1024,
+        ReasoningEffort::Medium => 8192,
+        ReasoningEffort::High => 24576,
+    }
+}
+
+/// 思考预算对应的强度, 用于只支持强度的服务商
+fn budget_effort(budget: u32) -> ReasoningEffort {
+    match budget {
+        0..=2048 => ReasoningEffort::Low,
+        2049..=16384 => ReasoningEffort::Medium,
+        _ => ReasoningEffort::High,
+    }
+}
+
+fn effort_name(effort: ReasoningEffort) -> &'static str {
+    match effort {
+        ReasoningEffort::Low => "low",
+        ReasoningEffort::Medium => "medium",
+        ReasoningEffort::High => "high",
+    }
+}
+
+/// 按模型提供商的类别, 把智能体的思考设置转换为额外的请求参数, 值为 null 的参数从请求中去掉
+pub fn extra_body(agent: &store::Agent, provider: &store::Provider) -> Map<String, Value> {
+    let mut body = Map::new();
+
+    let effort = agent.reasoning_effort;
+    let budget = (agent.thinking_budget > 0).then_some(agent.thinking_budget);
+    if effort.is_none() && budget.is_none() {
+        return body;
+    }
+
+    match provider.api_category.to_lowercase().as_str() {
+        "anthropic" => {
+            let budget = budget.or(effort.map(effort_budget)).unwrap_or_default();
+            body.insert(
+                "thinking".to_string(),
+                json!({ "type": "enabled", "budget_tokens": budget.max(MIN_ANTHROPIC_BUDGET) }),
+            );
+            // 开启思考时不支持修改 temperature 和 top_p
+            body.insert("temperature".to_string(), Value::Null);
+            body.insert("top_p".to_string(), Value::Null);
+        }
+        "gemini" => match budget {
+            Some(budget) => {
+                body.insert(
+                    "extra_body".to_string(),
+                    json!({ "google": { "thinking_config": { "thinking_budget": budget } } }),
+                );
+            }
+            None => {
+                body.insert("reasoning_effort".to_string(), json!(effort.map(effort_name)));
+            }
+        },
+        "deepseek" => {
+            tracing::warn!("provider {} does not support reasoning controls", provider.name);
+        }
+        // OpenAI 兼容接口只支持强度, 设置了预算时换算成强度
+        _ => {
+            let effort = effort.or(budget.map(budget_effort)).map(effort_name);
+            body.insert("reasoning_effort".to_string(), json!(effort));
+        }
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(effort: Option<&str>, budget: u32) -> store::Agent {
+        serde_json::from_value(json!({
+            "id": 1,
+            "categoryId": 1,
+            "name": "助手",
+            "prompt": "",
+            "temperature": 1.0,
+            "maxTokens": 0,
+            "contextSize": 5,
+            "reasoningEffort": effort,
+            "thinkingBudget": budget,
+            "createdAt": 0
+        }))
+        .unwrap()
+    }
+
+    fn provider(api_category: &str) -> store::Provider {
+        serde_json::from_value(json!({
+            "id": 1,
+            "name": "test",
+            "apiCategory": api_category,
+            "url": "http://localhost"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_extra_body() {
+        let cases = [
+            // 没有思考设置时不添加参数
+            ("openai", None, 0, json!({})),
+            ("anthropic", None, 0, json!({})),
+            // OpenAI 兼容接口, 预算换算成强度
+            ("openai", Some("high"), 0, json!({ "reasoning_effort": "high" })),
+            ("OpenAI", None, 4096, json!({ "reasoning_effort": "medium" })),
+            ("custom", None, 1000, json!({ "reasoning_effort": "low" })),
+            ("mock", Some("low"), 0, json!({ "reasoning_effort": "low" })),
+            // Anthropic 强度换算成预算, 预算不低于最小值, 去掉 temperature 和 top_p
+            (
+                "anthropic",
+                Some("medium"),
+                0,
+                json!({
+                    "thinking": { "type": "enabled", "budget_tokens": 8192 },
+                    "temperature": null,
+                    "top_p": null
+                }),
+            ),
+            (
+                "Anthropic",
+                Some("high"),
+                100,
+                json!({
+                    "thinking": { "type": "enabled", "budget_tokens": 1024 },
+                    "temperature": null,
+                    "top_p": null
+                }),
+            ),
+            // Gemini 优先使用预算
+            (
+                "gemini",
+                Some("low"),
+                2048,
+                json!({ "extra_body": { "google": { "thinking_config": { "thinking_budget": 2048 } } } }),
+            ),
+            ("gemini", Some("low"), 0, json!({ "reasoning_effort": "low" })),
+            // DeepSeek 不支持思考控制
+            ("deepseek", Some("high"), 4096, json!({})),
+        ];
+
+        for (category, effort, budget, expected) in cases {
+            let body = extra_body(&agent(effort, budget), &provider(category));
+            assert_eq!(Value::Object(body), expected, "{category} {effort:?} {budget}");
+        }
+    }
+}
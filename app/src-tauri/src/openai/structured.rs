@@ -0,0 +1,331 @@
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequest, ResponseFormat,
+    ResponseFormatJsonSchema,
+};
+use serde_json::Value;
+
+use crate::{
+    error,
+    openai::chat::{self, ChatContext, ChatRound},
+};
+
+/// 结构化输出, 按智能体配置的 JSON Schema 约束和校验回复
+pub struct Structured {
+    schema: Value,
+    validator: jsonschema::Validator,
+}
+
+impl Structured {
+    pub fn new(schema: &Value) -> Result<Self, error::Error> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| error::Error::Schema(format!("invalid response schema: {e}")))?;
+        Ok(Self { schema: schema.clone(), validator })
+    }
+
+    /// 支持结构化输出的模型, 通过 response_format 约束
+    ///
+    /// 严格模式要求所有对象禁止额外属性且属性都必填, 不满足时不开启, 回复仍会校验
+    pub fn response_format(&self) -> ResponseFormat {
+        ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema {
+                description: None,
+                name: "response".to_string(),
+                schema: Some(self.schema.clone()),
+                strict: Some(is_strict_compatible(&self.schema)),
+            },
+        }
+    }
+
+    /// 不支持的模型, 通过系统提示词约束
+    pub fn prompt(&self) -> String {
+        format!(
+            "Reply with a single JSON value that matches the following JSON Schema. \
+             Do not add any explanation or markdown outside the JSON.\n{}",
+            self.schema
+        )
+    }
+
+    /// 修正提示, 带上上次校验失败的原因
+    pub fn repair_prompt(&self, error: &str) -> String {
+        format!(
+            "Your previous reply does not match the required JSON Schema: {error}. \
+             Reply again with only the corrected JSON."
+        )
+    }
+
+    /// 按结束原因校验回复, 被截断的回复不完整, 不解析也不修正
+    pub fn check(
+        &self, content: &str, truncated: Option<store::FinishReason>,
+    ) -> store::StructuredOutput {
+        let error = match truncated {
+            None => return self.validate(content),
+            Some(store::FinishReason::Length) => "reply truncated at the maximum length",
+            Some(store::FinishReason::ContentFilter) => "reply stopped by the content filter",
+        };
+        store::StructuredOutput { valid: false, value: None, error: Some(error.to_string()) }
+    }
+
+    /// 解析并校验回复内容
+    pub fn validate(&self, content: &str) -> store::StructuredOutput {
+        let value = match serde_json::from_str::<Value>(strip_code_fence(content)) {
+            Ok(value) => value,
+            Err(e) => {
+                return store::StructuredOutput {
+                    valid: false,
+                    value: None,
+                    error: Some(format!("invalid json: {e}")),
+                };
+            }
+        };
+
+        let errors = self
+            .validator
+            .iter_errors(&value)
+            .map(|e| format!("{} at '{}'", e, e.instance_path))
+            .collect::<Vec<_>>();
+
+        store::StructuredOutput {
+            valid: errors.is_empty(),
+            value: Some(value),
+            error: (!errors.is_empty()).then(|| errors.join("; ")),
+        }
+    }
+}
+
+/// 带上校验失败的原因要求模型修正一次, 返回修正的轮次和校验结果
+///
+/// 修正时禁用工具: 原生工具不随请求发送, 提示词方式的调用只隐藏不执行
+pub async fn repair(
+    ctx: &mut ChatContext, structured: &Structured, content: String, error: &str,
+    messages: &mut Vec<ChatCompletionRequestMessage>, stream: bool,
+    new_request: impl Fn(
+        &Vec<ChatCompletionRequestMessage>,
+    ) -> Result<CreateChatCompletionRequest, error::Error>,
+) -> Result<(ChatRound, store::StructuredOutput), error::Error> {
+    messages.push(
+        ChatCompletionRequestAssistantMessageArgs::default().content(content).build()?.into(),
+    );
+    messages.push(
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(structured.repair_prompt(error))
+            .build()?
+            .into(),
+    );
+    ctx.tool_limit = true;
+
+    let request = new_request(messages)?;
+    let round = if stream {
+        chat::chat_stream(ctx, request, messages).await?
+    } else {
+        chat::chat(ctx, request, messages).await?
+    };
+
+    let output = structured.check(&round.content, round.truncated);
+    Ok((round, output))
+}
+
+/// 去掉模型常加的 ```json 代码块标记
+fn strip_code_fence(content: &str) -> &str {
+    let content = content.trim();
+    let Some(inner) = content.strip_prefix("```") else {
+        return content;
+    };
+    // 跳过语言标记所在的第一行
+    let inner = inner.split_once('\n').map_or("", |(_, rest)| rest);
+    inner.trim_end().strip_suffix("```").unwrap_or(inner).trim()
+}
+
+/// Schema 是否满足严格模式: 每个对象都设置 additionalProperties 为 false, 且所有属性必填
+fn is_strict_compatible(schema: &Value) -> bool {
+    let Value::Object(map) = schema else {
+        return true;
+    };
+
+    if let Some(properties) = map.get("properties").and_then(Value::as_object) {
+        if map.get("additionalProperties") != Some(&Value::Bool(false)) {
+            return false;
+        }
+        let required = map.get("required").and_then(Value::as_array);
+        let all_required = properties.keys().all(|key| {
+            required.is_some_and(|required| required.iter().any(|r| r.as_str() == Some(key)))
+        });
+        if !all_required || !properties.values().all(is_strict_compatible) {
+            return false;
+        }
+    } else if map.get("type").and_then(Value::as_str) == Some("object")
+        && map.get("additionalProperties") != Some(&Value::Bool(false))
+    {
+        return false;
+    }
+
+    let nested = ["items", "anyOf", "$defs", "definitions"];
+    nested.iter().filter_map(|key| map.get(*key)).all(|value| match value {
+        Value::Array(list) => list.iter().all(is_strict_compatible),
+        Value::Object(defs) if !defs.contains_key("type") && !defs.contains_key("properties") => {
+            defs.values().all(is_strict_compatible)
+        }
+        value => is_strict_compatible(value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::{Client, config::OpenAIConfig, types::CreateChatCompletionRequestArgs};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// 依次返回指定回复内容的本地模型服务
+    async fn serve(replies: Vec<(&'static str, &'static str)>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for (content, finish_reason) in replies {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 65536];
+                let _ = socket.read(&mut request).await;
+                let body = serde_json::json!({
+                    "id": "1",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "m",
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": content },
+                        "finish_reason": finish_reason,
+                    }],
+                })
+                .to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                let _ = socket.shutdown().await;
+                let _ = socket.read_to_end(&mut Vec::new()).await;
+            }
+        });
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    async fn test_repair_still_invalid() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" } },
+            "required": ["name"]
+        });
+        let structured = Structured::new(&schema).unwrap();
+
+        let url = serve(vec![
+            // 修正时仍然调用工具并返回不符合的内容
+            ("<tool_call>{\"name\": \"search\"}</tool_call>{\"name\": 1}", "stop"),
+            // 修正的回复被截断
+            ("{\"name\": \"", "length"),
+        ])
+        .await;
+        let (on_event, _receiver) = tokio::sync::mpsc::channel(32);
+        let mut ctx = ChatContext {
+            client: Client::with_config(OpenAIConfig::new().with_api_base(url)),
+            http: reqwest::Client::new(),
+            tool_objects: Arc::new(Vec::new()),
+            on_event,
+            timeouts: Default::default(),
+            extra_body: Default::default(),
+            think_tags: None,
+            prompt_tools: true,
+            tool_limit: false,
+            tool_guard: Mutex::new(chat::ToolGuard::new(3)),
+        };
+        let new_request = |messages: &Vec<ChatCompletionRequestMessage>| {
+            Ok::<_, error::Error>(
+                CreateChatCompletionRequestArgs::default()
+                    .model("m")
+                    .messages(messages.clone())
+                    .build()?,
+            )
+        };
+
+        let mut messages = Vec::new();
+        let (round, output) = repair(
+            &mut ctx,
+            &structured,
+            "{}".to_string(),
+            "\"name\" is a required property",
+            &mut messages,
+            false,
+            new_request,
+        )
+        .await
+        .unwrap();
+        // 工具调用没有执行, 校验仍然失败
+        assert!(ctx.tool_limit);
+        assert!(round.tool_calls.is_empty());
+        assert_eq!(round.content, "{\"name\": 1}");
+        assert!(!output.valid);
+        assert!(output.error.unwrap().contains("is not of type"));
+        assert_eq!(messages.len(), 2);
+
+        // 被截断的回复不解析
+        let (round, output) = repair(
+            &mut ctx,
+            &structured,
+            round.content,
+            "invalid",
+            &mut messages,
+            false,
+            new_request,
+        )
+        .await
+        .unwrap();
+        assert_eq!(round.truncated, Some(store::FinishReason::Length));
+        assert!(!output.valid);
+        assert_eq!(output.value, None);
+        assert_eq!(output.error.as_deref(), Some("reply truncated at the maximum length"));
+    }
+
+    #[test]
+    fn test_strict_compatible() {
+        let strict = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "value": { "type": "string" } },
+                        "required": ["value"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["name", "tags"],
+            "additionalProperties": false
+        });
+        assert!(is_strict_compatible(&strict));
+
+        // 可选属性
+        let mut optional = strict.clone();
+        optional["required"] = serde_json::json!(["name"]);
+        assert!(!is_strict_compatible(&optional));
+
+        // 没有禁止额外属性
+        let mut open = strict.clone();
+        open.as_object_mut().unwrap().remove("additionalProperties");
+        assert!(!is_strict_compatible(&open));
+
+        // 嵌套对象不满足
+        let mut nested = strict.clone();
+        nested["properties"]["tags"]["items"]["required"] = serde_json::json!([]);
+        assert!(!is_strict_compatible(&nested));
+
+        let ResponseFormat::JsonSchema { json_schema } =
+            Structured::new(&optional).unwrap().response_format()
+        else {
+            panic!("expected json schema response format");
+        };
+        assert_eq!(json_schema.strict, Some(false));
+    }
+}
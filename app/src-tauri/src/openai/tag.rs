@@ -0,0 +1,140 @@
+/// 拆分后的一段文本
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    /// 标签内的文本
+    Tagged(String),
+    /// 标签外的文本
+    Text(String),
+}
+
+/// 流式拆分正文中以标签包裹的内容, 如思考内容和工具调用
+///
+/// 标签可能跨数据块, 末尾可能是标签开头的部分会暂存到下一个数据块再处理
+pub struct TagSplitter {
+    open: String,
+    close: String,
+    inside: bool,
+    pending: String,
+}
+
+impl TagSplitter {
+    pub fn new(open: &str, close: &str) -> Self {
+        Self {
+            open: open.to_string(),
+            close: close.to_string(),
+            inside: false,
+            pending: String::new(),
+        }
+    }
+
+    /// 处理一个数据块, 返回可以确定类型的文本段
+    pub fn push(&mut self, chunk: &str) -> Vec<Segment> {
+        self.pending.push_str(chunk);
+
+        let mut segments = Vec::new();
+        loop {
+            let tag = if self.inside { &self.close } else { &self.open };
+
+            if let Some(index) = self.pending.find(tag.as_str()) {
+                let text = self.pending[..index].to_string();
+                self.pending.drain(..index + tag.len());
+                self.emit(&mut segments, text);
+                self.inside = !self.inside;
+                continue;
+            }
+
+            // 保留可能是标签开头的后缀
+            let keep = partial_suffix(&self.pending, tag);
+            let text = self.pending[..self.pending.len() - keep].to_string();
+            self.pending.drain(..self.pending.len() - keep);
+            self.emit(&mut segments, text);
+            break;
+        }
+
+        segments
+    }
+
+    /// 流结束时输出暂存的文本
+    pub fn finish(&mut self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let text = std::mem::take(&mut self.pending);
+        self.emit(&mut segments, text);
+        segments
+    }
+
+    fn emit(&self, segments: &mut Vec<Segment>, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        segments.push(if self.inside { Segment::Tagged(text) } else { Segment::Text(text) });
+    }
+}
+
+/// 文本末尾与标签开头重合的最长长度
+fn partial_suffix(text: &str, tag: &str) -> usize {
+    (1..tag.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| tag.is_char_boundary(len) && text.ends_with(&tag[..len]))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 依次处理数据块, 合并相邻的同类文本段
+    fn split(splitter: &mut TagSplitter, chunks: &[&str]) -> Vec<Segment> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let pushed = chunks.iter().flat_map(|chunk| splitter.push(chunk)).collect::<Vec<_>>();
+        for segment in pushed.into_iter().chain(splitter.finish()) {
+            match (segments.last_mut(), &segment) {
+                (Some(Segment::Tagged(last)), Segment::Tagged(text))
+                | (Some(Segment::Text(last)), Segment::Text(text)) => last.push_str(text),
+                _ => segments.push(segment),
+            }
+        }
+        segments
+    }
+
+    #[test]
+    fn test_split_across_chunks() {
+        let mut splitter = TagSplitter::new("<think>", "</think>");
+        let segments = split(&mut splitter, &["<th", "ink>想一想", "</thi", "nk>回答"]);
+        assert_eq!(
+            segments,
+            vec![Segment::Tagged("想一想".to_string()), Segment::Text("回答".to_string())]
+        );
+
+        // 标签的部分前缀在确定之前不输出
+        let mut splitter = TagSplitter::new("<think>", "</think>");
+        assert_eq!(splitter.push("开头<th"), vec![Segment::Text("开头".to_string())]);
+        assert_eq!(splitter.push("e"), vec![Segment::Text("<the".to_string())]);
+    }
+
+    #[test]
+    fn test_finish_inside_tag() {
+        // 流在思考内容中结束, 剩余内容仍然是思考内容
+        let mut splitter = TagSplitter::new("<think>", "</think>");
+        let segments = split(&mut splitter, &["<think>还在", "思考</th"]);
+        assert_eq!(segments, vec![Segment::Tagged("还在思考</th".to_string())]);
+    }
+
+    #[test]
+    fn test_custom_tags() {
+        let mut splitter = TagSplitter::new("【思考】", "【/思考】");
+        let segments = split(&mut splitter, &["前言【思", "考】内容【/", "思考】结论"]);
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Text("前言".to_string()),
+                Segment::Tagged("内容".to_string()),
+                Segment::Text("结论".to_string()),
+            ]
+        );
+
+        // 没有标签时全部是正文
+        let mut splitter = TagSplitter::new("<reasoning>", "</reasoning>");
+        let segments = split(&mut splitter, &["<think>", "不是自定义标签"]);
+        assert_eq!(segments, vec![Segment::Text("<think>不是自定义标签".to_string())]);
+    }
+}
@@ -9,6 +9,12 @@ use search::SearchTaivlyTool;
 mod mcp;
 pub use mcp::McpTool;
 
+pub mod oauth;
+mod streamable;
+
+pub mod js;
+pub use js::JsTool;
+
 use crate::error;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,6 +26,35 @@ pub struct ToolDescription {
     pub schema: serde_json::Value,
 }
 
+/// 按工具的 inputSchema 校验参数, 失败原因返回给模型修正后重新调用
+pub fn validate_arguments(
+    description: &ToolDescription, args: &serde_json::Value,
+) -> Result<(), error::Error> {
+    // 工具提供的 Schema 无效时不校验
+    let validator = match jsonschema::validator_for(&description.schema) {
+        Ok(validator) => validator,
+        Err(e) => {
+            tracing::warn!("Invalid input schema of function {}: {e}", description.name);
+            return Ok(());
+        }
+    };
+
+    let errors = validator
+        .iter_errors(args)
+        .map(|e| format!("{} at '{}'", e, e.instance_path))
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(error::Error::Schema(format!(
+        "invalid arguments for function {}: {}. Fix the arguments to match the input schema \
+         and call the function again.",
+        description.name,
+        errors.join("; ")
+    )))
+}
+
 impl TryFrom<ToolDescription> for ChatCompletionTool {
     type Error = async_openai::error::OpenAIError;
     fn try_from(description: ToolDescription) -> Result<Self, Self::Error> {
@@ -54,17 +89,138 @@ impl Tool {
         Self(tool)
     }
 
-    pub async fn into_tool_object(self) -> Result<Box<dyn ToolObject>, error::Error> {
-        match self.0.data {
-            store::ToolData::McpSse(sse) => Ok(Box::new(McpTool::try_new_sse(sse.url).await?)),
+    /// store 用于读取远程 MCP 服务的 OAuth 令牌
+    pub async fn into_tool_object(
+        self, store: &store::Store,
+    ) -> Result<Box<dyn ToolObject>, error::Error> {
+        let store::Tool { id, name, description, data, .. } = self.0;
+        match data {
+            store::ToolData::McpSse(sse) => Ok(Box::new(McpTool::try_from_sse(store, sse).await?)),
+            store::ToolData::McpHttp(http) => {
+                Ok(Box::new(McpTool::try_from_http(store, http).await?))
+            }
             store::ToolData::McpIo(io) => {
                 Ok(Box::new(McpTool::try_new_io(io.command, io.args, io.env).await?))
             }
-            _ => Err(error::Error::InvalidData("Unsupported tool type".to_string())),
+            store::ToolData::JavsScript(script) => {
+                Ok(Box::new(JsTool::new(id, &name, description, script)))
+            }
         }
     }
 }
 
+/// 单独执行工具函数的结果, 不经过聊天
+#[derive(Debug, Serialize)]
+pub struct Invocation {
+    /// 执行的函数名称
+    pub name: String,
+    pub arguments: serde_json::Value,
+    pub result: Option<serde_json::Value>,
+    /// JS 工具的控制台输出, MCP 子进程的 stderr
+    pub logs: Vec<String>,
+    /// 耗时, 毫秒, 包括启动和连接 MCP 服务
+    pub duration: u64,
+    pub error: Option<String>,
+}
+
+impl Tool {
+    /// 执行工具函数, 未传入的参数使用参数定义的测试值
+    ///
+    /// name 不指定时执行第一个函数
+    pub async fn invoke(
+        self, store: &store::Store, name: Option<String>, arguments: serde_json::Value,
+    ) -> Invocation {
+        let start = std::time::Instant::now();
+        let mut invocation = Invocation {
+            name: name.clone().unwrap_or_default(),
+            arguments,
+            result: None,
+            logs: Vec::new(),
+            duration: 0,
+            error: None,
+        };
+
+        let result = match self.0.data {
+            store::ToolData::JavsScript(script) => {
+                let params = script.param.as_deref().unwrap_or_default();
+                invocation.arguments = js::test_arguments(params, invocation.arguments.take());
+                let tool = JsTool::new(self.0.id, &self.0.name, self.0.description, script);
+                let description = tool.description().remove(0);
+                invocation.name = description.name.clone();
+                match validate_arguments(&description, &invocation.arguments) {
+                    Ok(()) => match tool.execute(invocation.arguments.clone()).await {
+                        Ok(output) => {
+                            invocation.logs = output.logs;
+                            output.result.map_err(error::Error::from)
+                        }
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                }
+            }
+            store::ToolData::McpIo(io) => {
+                // 子进程的 stderr 写入临时文件, 执行后读取
+                let nanos = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos())
+                    .unwrap_or_default();
+                let log = std::env::temp_dir().join(format!("mcp-invoke-{nanos}.log"));
+                let result = invoke_mcp_io(io, &log, name, &mut invocation).await;
+                if let Ok(stderr) = std::fs::read_to_string(&log) {
+                    invocation.logs = stderr.lines().map(String::from).collect();
+                }
+                let _ = std::fs::remove_file(&log);
+                result
+            }
+            store::ToolData::McpSse(sse) => match McpTool::try_from_sse(store, sse).await {
+                Ok(tool) => invoke_mcp(&tool, name, &mut invocation).await,
+                Err(e) => Err(e),
+            },
+            store::ToolData::McpHttp(http) => match McpTool::try_from_http(store, http).await {
+                Ok(tool) => invoke_mcp(&tool, name, &mut invocation).await,
+                Err(e) => Err(e),
+            },
+        };
+
+        match result {
+            Ok(value) => invocation.result = Some(value),
+            Err(e) => invocation.error = Some(e.to_string()),
+        }
+        invocation.duration = start.elapsed().as_millis() as u64;
+        invocation
+    }
+}
+
+async fn invoke_mcp_io(
+    io: store::ToolMcpIo, log: &std::path::Path, name: Option<String>, invocation: &mut Invocation,
+) -> Result<serde_json::Value, error::Error> {
+    let stderr = std::fs::File::create(log)?;
+    let tool = McpTool::try_new_io_with_stderr(io.command, io.args, io.env, stderr.into()).await?;
+    invoke_mcp(&tool, name, invocation).await
+}
+
+async fn invoke_mcp(
+    tool: &McpTool, name: Option<String>, invocation: &mut Invocation,
+) -> Result<serde_json::Value, error::Error> {
+    let name = match name {
+        Some(name) => name,
+        None => tool
+            .description()
+            .into_iter()
+            .next()
+            .map(|description| description.name)
+            .ok_or(error::Error::Mcp("No function found".to_string()))?,
+    };
+    invocation.name = name.clone();
+    if invocation.arguments.is_null() {
+        invocation.arguments = serde_json::Value::Object(Default::default());
+    }
+    if let Some(description) = tool.description().into_iter().find(|desc| desc.name == name) {
+        validate_arguments(&description, &invocation.arguments)?;
+    }
+    tool.call(&name, invocation.arguments.clone()).await
+}
+
 pub struct Search(store::Search);
 
 impl Search {
@@ -81,3 +237,73 @@ impl Search {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_arguments() {
+        let description = ToolDescription {
+            name: "search".to_string(),
+            description: String::new(),
+            schema: json!({
+                "type": "object",
+                "properties": {
+                    "q": {"type": "string"},
+                    "limit": {"type": "integer"}
+                },
+                "required": ["q"]
+            }),
+        };
+        assert!(validate_arguments(&description, &json!({"q": "rust", "limit": 5})).is_ok());
+
+        // 返回给模型的错误包括函数名, 每个错误的位置和修正提示
+        let error = validate_arguments(&description, &json!({"limit": "5"})).unwrap_err();
+        let text = error.to_string();
+        assert!(text.starts_with("Schema error: invalid arguments for function search: "));
+        assert!(text.contains(r#""q" is a required property at ''"#));
+        assert!(text.contains(r#""5" is not of type "integer" at '/limit'"#));
+        assert!(
+            text.ends_with(
+                "Fix the arguments to match the input schema and call the function again."
+            )
+        );
+
+        // Schema 无效时不校验
+        let invalid = ToolDescription { schema: json!({"type": 1}), ..description };
+        assert!(validate_arguments(&invalid, &json!({"limit": "5"})).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_invoke_invalid_arguments() {
+        let tool: store::Tool = serde_json::from_value(json!({
+            "id": 1,
+            "categoryId": 0,
+            "iconId": null,
+            "name": "add_one",
+            "description": null,
+            "data": {
+                "type": "javsSript",
+                "param": [{"name": "a", "type": "integer", "description": "", "required": true}],
+                "code": "a + 1"
+            },
+            "createdAt": 0,
+            "updatedAt": null
+        }))
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = store::Store::open(dir.path()).unwrap();
+
+        // 参数不符合定义时不执行, 返回与模型调用相同的错误
+        let invocation = Tool::new(tool).invoke(&store, None, json!({"a": "x"})).await;
+        assert_eq!(invocation.name, "add_one");
+        assert!(invocation.result.is_none());
+        let error = invocation.error.unwrap();
+        assert!(error.contains("invalid arguments for function add_one"));
+        assert!(error.contains(r#""x" is not of type "integer" at '/a'"#));
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use super::{ToolDescription, ToolObject};
+
+use crate::error;
+
+/// 在内嵌的 JS 运行时中执行的工具
+pub struct JsTool {
+    script: store::ToolJavaScript,
+    description: Vec<ToolDescription>,
+}
+
+impl JsTool {
+    pub fn new(
+        id: u64, name: &str, description: Option<String>, script: store::ToolJavaScript,
+    ) -> Self {
+        let params = script.param.as_deref().unwrap_or_default();
+        let description = ToolDescription {
+            name: function_name(id, name),
+            description: description.unwrap_or_else(|| name.to_string()),
+            schema: store::Param::object_schema(params),
+        };
+        Self { script, description: vec![description] }
+    }
+
+    fn sandbox(&self) -> tools::deno::Sandbox {
+        let mut sandbox = tools::deno::Sandbox {
+            allow_hosts: self.script.allow_hosts.clone(),
+            ..Default::default()
+        };
+        if self.script.timeout > 0 {
+            sandbox.timeout = Duration::from_secs(self.script.timeout);
+        }
+        if self.script.memory_limit > 0 {
+            sandbox.memory_limit = self.script.memory_limit;
+        }
+        sandbox
+    }
+
+    /// 整理参数后执行脚本, 返回结果和控制台输出
+    pub async fn execute(&self, param: Value) -> Result<tools::deno::JsOutput, error::Error> {
+        let params = self.script.param.as_deref().unwrap_or_default();
+        let arguments = arguments(params, param)?;
+        Ok(tools::deno::run(self.script.code.clone(), arguments, self.sandbox()).await)
+    }
+}
+
+/// 函数名只能包含字母, 数字, 下划线和横线, 其它名称使用工具ID
+fn function_name(id: u64, name: &str) -> String {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        name.to_string()
+    } else {
+        format!("js_tool_{id}")
+    }
+}
+
+/// 按参数定义整理模型传入的参数, 只保留定义过的参数
+pub fn arguments(params: &[store::Param], mut input: Value) -> Result<Value, error::Error> {
+    let mut arguments = Map::new();
+    for param in params {
+        let value = input.get_mut(&param.name).map(Value::take).filter(|value| !value.is_null());
+        match value {
+            Some(value) => {
+                arguments.insert(param.name.clone(), coerce(&param.param_type, value));
+            }
+            None if param.required => {
+                return Err(error::Error::InvalidData(format!(
+                    "Missing required parameter {}",
+                    param.name
+                )));
+            }
+            None => {}
+        }
+    }
+    Ok(Value::Object(arguments))
+}
+
+/// 参数的测试值, 传入的参数优先
+pub fn test_arguments(params: &[store::Param], input: Value) -> Value {
+    let mut arguments: Map<String, Value> = params
+        .iter()
+        .filter_map(|param| {
+            let value = param.test_value.as_ref().filter(|value| !value.is_empty())?;
+            Some((param.name.clone(), coerce(&param.param_type, Value::String(value.clone()))))
+        })
+        .collect();
+    if let Value::Object(input) = input {
+        arguments.extend(input);
+    }
+    Value::Object(arguments)
+}
+
+/// 字符串形式的数字, 布尔值和对象转换为参数定义的类型
+pub fn coerce(param_type: &str, value: Value) -> Value {
+    let Value::String(text) = &value else {
+        return value;
+    };
+    let text = text.trim();
+    match param_type {
+        "number" | "integer" => text
+            .parse::<i64>()
+            .map(Value::from)
+            .ok()
+            .or_else(|| {
+                text.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number)
+            })
+            .unwrap_or(value),
+        "boolean" => match text {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => value,
+        },
+        "object" | "array" => serde_json::from_str(text).unwrap_or(value),
+        _ => value,
+    }
+}
+
+impl ToolObject for JsTool {
+    fn description(&self) -> Vec<ToolDescription> {
+        self.description.clone()
+    }
+    fn call<'a>(
+        &'a self, _name: &'a str, param: Value,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Value, error::Error>> + Send + 'a>>
+    {
+        Box::pin(async move {
+            let output = self.execute(param).await?;
+            for log in &output.logs {
+                tracing::debug!("JavaScript tool {}: {log}", self.description[0].name);
+            }
+            Ok(output.result?)
+        })
+    }
+}
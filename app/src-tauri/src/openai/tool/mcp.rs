@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, process::Stdio};
 
+use reqwest::header::HeaderMap;
 use rmcp::{
     RoleClient, ServiceExt,
     model::{
@@ -7,17 +8,41 @@ use rmcp::{
         InitializeRequestParam, ListToolsResult,
     },
     service::RunningService,
-    transport::{SseTransport, TokioChildProcess},
+    transport::{SseTransport, TokioChildProcess, sse::ReqwestSseClient},
+};
+use tokio::{
+    process::Command,
+    sync::{Mutex, RwLock},
 };
-use tokio::process::Command;
 
-use super::{ToolDescription, ToolObject};
+use super::{ToolDescription, ToolObject, oauth, streamable};
 
 use crate::error;
 
 pub struct McpTool {
-    client: RunningService<RoleClient, InitializeRequestParam>,
+    client: RwLock<RunningService<RoleClient, InitializeRequestParam>>,
     description: Vec<ToolDescription>,
+    oauth: Option<OAuthConnection>,
+}
+
+/// 使用 OAuth 的远程服务, 连接的请求头固定, 令牌刷新后需要重新连接
+struct OAuthConnection {
+    store: store::Store,
+    data: store::ToolData,
+    /// 建立连接时使用的访问令牌
+    token: Mutex<String>,
+}
+
+impl OAuthConnection {
+    async fn try_new(
+        store: &store::Store, url: &str, auth: Option<&store::McpAuth>, data: store::ToolData,
+    ) -> Result<Option<Self>, error::Error> {
+        let Some(store::McpAuth::OAuth(_)) = auth else {
+            return Ok(None);
+        };
+        let token = oauth::access_token(store, url).await?;
+        Ok(Some(Self { store: store.clone(), data, token: Mutex::new(token) }))
+    }
 }
 
 impl McpTool {
@@ -33,11 +58,19 @@ impl McpTool {
                 schema: serde_json::to_value(&*tool.input_schema).unwrap(),
             })
             .collect();
-        Self { client, description }
+        Self { client: RwLock::new(client), description, oauth: None }
     }
 
     pub async fn try_new_io(
         command: String, args: Option<Vec<String>>, env: Option<HashMap<String, String>>,
+    ) -> Result<Self, error::Error> {
+        Self::try_new_io_with_stderr(command, args, env, Stdio::inherit()).await
+    }
+
+    /// 指定子进程 stderr 的输出位置, 测试工具时收集日志
+    pub async fn try_new_io_with_stderr(
+        command: String, args: Option<Vec<String>>, env: Option<HashMap<String, String>>,
+        stderr: Stdio,
     ) -> Result<Self, error::Error> {
         let mut path = std::env::var("PATH").unwrap_or_default();
 
@@ -69,17 +102,9 @@ impl McpTool {
 
         // 添加系统环境变量
         cmd.env("PATH", path);
+        cmd.stderr(stderr);
 
-        let client_info = ClientInfo {
-            protocol_version: Default::default(),
-            capabilities: ClientCapabilities::default(),
-            client_info: Implementation {
-                name: env!("CARGO_PKG_NAME").to_string(),
-                version: env!("CARGO_PKG_VERSION").to_string(),
-            },
-        };
-
-        let client = client_info
+        let client = client_info()
             .serve(TokioChildProcess::new(&mut cmd)?)
             .await
             .map_err(|e| error::Error::Mcp(format!("{e}")))?;
@@ -92,21 +117,71 @@ impl McpTool {
         Ok(Self::new(client, tools))
     }
 
-    pub async fn try_new_sse(url: String) -> Result<Self, error::Error> {
-        let transport =
-            SseTransport::start(url).await.map_err(|e| error::Error::Mcp(format!("{e}")))?;
-
-        let client_info = ClientInfo {
-            protocol_version: Default::default(),
-            capabilities: ClientCapabilities::default(),
-            client_info: Implementation {
-                name: env!("CARGO_PKG_NAME").to_string(),
-                version: env!("CARGO_PKG_VERSION").to_string(),
-            },
-        };
+    /// 按工具配置连接 MCP-SSE 服务, 带上自定义请求头和认证
+    pub async fn try_from_sse(
+        store: &store::Store, sse: store::ToolMcpSse,
+    ) -> Result<Self, error::Error> {
+        let headers = oauth::headers(store, &sse.url, &sse.headers, sse.auth.as_ref()).await?;
+        let mut tool = Self::try_new_sse(sse.url.clone(), headers).await?;
+        let (url, auth) = (sse.url.clone(), sse.auth.clone());
+        let data = store::ToolData::McpSse(sse);
+        tool.oauth = OAuthConnection::try_new(store, &url, auth.as_ref(), data).await?;
+        Ok(tool)
+    }
+
+    /// 按工具配置连接 MCP Streamable HTTP 服务, 带上自定义请求头和认证
+    pub async fn try_from_http(
+        store: &store::Store, http: store::ToolMcpHttp,
+    ) -> Result<Self, error::Error> {
+        let headers = oauth::headers(store, &http.url, &http.headers, http.auth.as_ref()).await?;
+        let mut tool = Self::try_new_http(http.url.clone(), headers).await?;
+        let (url, auth) = (http.url.clone(), http.auth.clone());
+        let data = store::ToolData::McpHttp(http);
+        tool.oauth = OAuthConnection::try_new(store, &url, auth.as_ref(), data).await?;
+        Ok(tool)
+    }
+
+    /// headers 为自定义请求头和认证, 每个请求都会带上
+    pub async fn try_new_sse(url: String, headers: HeaderMap) -> Result<Self, error::Error> {
+        let http = reqwest::Client::builder().default_headers(headers).build()?;
+        let sse = ReqwestSseClient::new_with_client(url.as_str(), http)
+            .await
+            .map_err(|e| error::Error::Mcp(format!("{e}")))?;
+        let transport = SseTransport::start_with_client(sse)
+            .await
+            .map_err(|e| error::Error::Mcp(format!("{e}")))?;
 
         let client =
-            client_info.serve(transport).await.map_err(|e| error::Error::Mcp(format!("{e}")))?;
+            client_info().serve(transport).await.map_err(|e| error::Error::Mcp(format!("{e}")))?;
+
+        let tools = client
+            .list_tools(Default::default())
+            .await
+            .map_err(|e| error::Error::Mcp(format!("{e}")))?;
+
+        Ok(Self::new(client, tools))
+    }
+
+    /// Streamable HTTP 传输, 服务端不支持时回退到 SSE
+    pub async fn try_new_http(url: String, headers: HeaderMap) -> Result<Self, error::Error> {
+        let (transport, failure) = streamable::start(url.clone(), headers.clone())?;
+        let client = match client_info().serve(transport).await {
+            Ok(client) => client,
+            Err(e) => {
+                // 优先返回初始化请求失败的状态码和内容
+                let failure = failure.lock().unwrap().take();
+                return match failure {
+                    Some(streamable::InitError::Legacy(status)) => {
+                        tracing::info!(
+                            "MCP server {url} returned {status} for streamable http, fallback to sse"
+                        );
+                        Self::try_new_sse(url, headers).await
+                    }
+                    Some(streamable::InitError::Failed(message)) => Err(error::Error::Mcp(message)),
+                    None => Err(error::Error::Mcp(format!("{e}"))),
+                };
+            }
+        };
 
         let tools = client
             .list_tools(Default::default())
@@ -117,6 +192,46 @@ impl McpTool {
     }
 }
 
+impl McpTool {
+    /// 每次调用前读取令牌, 令牌已刷新时用新的令牌重新连接
+    async fn reconnect_if_refreshed(&self) -> Result<(), error::Error> {
+        let Some(connection) = &self.oauth else {
+            return Ok(());
+        };
+        let url = match &connection.data {
+            store::ToolData::McpSse(sse) => &sse.url,
+            store::ToolData::McpHttp(http) => &http.url,
+            _ => return Ok(()),
+        };
+        let token = oauth::access_token(&connection.store, url).await?;
+        let mut current = connection.token.lock().await;
+        if *current == token {
+            return Ok(());
+        }
+
+        tracing::info!("MCP server {url} token refreshed, reconnect");
+        let tool = match connection.data.clone() {
+            store::ToolData::McpSse(sse) => Self::try_from_sse(&connection.store, sse).await?,
+            store::ToolData::McpHttp(http) => Self::try_from_http(&connection.store, http).await?,
+            _ => return Ok(()),
+        };
+        *self.client.write().await = tool.client.into_inner();
+        *current = token;
+        Ok(())
+    }
+}
+
+fn client_info() -> ClientInfo {
+    ClientInfo {
+        protocol_version: Default::default(),
+        capabilities: ClientCapabilities::default(),
+        client_info: Implementation {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    }
+}
+
 impl ToolObject for McpTool {
     fn description(&self) -> Vec<ToolDescription> {
         self.description.clone()
@@ -132,12 +247,21 @@ impl ToolObject for McpTool {
                 _ => None,
             };
 
+            self.reconnect_if_refreshed().await?;
+
             let response = self
                 .client
+                .read()
+                .await
                 .call_tool(CallToolRequestParam { name: name.to_string().into(), arguments })
                 .await
                 .map_err(|e| error::Error::Mcp(format!("{e}")))?;
 
+            // 服务端返回的工具错误
+            if response.is_error == Some(true) {
+                return Err(error::Error::Mcp(serde_json::to_string(&response.content)?));
+            }
+
             serde_json::to_value(response).map_err(|e| error::Error::Mcp(format!("{e}")))
         })
     }
@@ -0,0 +1,534 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use base64::prelude::*;
+use rand::{Rng, distributions::Alphanumeric};
+use reqwest::{
+    Url,
+    header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::error;
+
+/// 令牌过期前提前刷新的时间, 毫秒
+const REFRESH_MARGIN: i64 = 60_000;
+/// 等待浏览器完成授权的时间
+const AUTHORIZE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// 每个服务地址的刷新锁, 同时使用即将过期的令牌时只刷新一次
+static REFRESHING: OnceLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+
+/// 授权服务的元数据, RFC 8414
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    registration_endpoint: Option<String>,
+}
+
+/// 受保护资源的元数据, RFC 9728, 指出使用的授权服务
+#[derive(Debug, Deserialize)]
+struct ResourceMetadata {
+    #[serde(default)]
+    authorization_servers: Vec<String>,
+}
+
+/// 动态注册的客户端, RFC 7591
+#[derive(Debug, Deserialize)]
+struct Registration {
+    client_id: String,
+    client_secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    /// 有效期, 秒
+    expires_in: Option<i64>,
+}
+
+/// 远程 MCP 服务的请求头, 包括自定义请求头和认证
+pub async fn headers(
+    store: &store::Store, url: &str, headers: &HashMap<String, String>,
+    auth: Option<&store::McpAuth>,
+) -> Result<HeaderMap, error::Error> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| error::Error::InvalidData(format!("Invalid header name {name}: {e}")))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| error::Error::InvalidData(format!("Invalid header {name}: {e}")))?;
+        map.insert(name, value);
+    }
+
+    let token = match auth {
+        None => return Ok(map),
+        Some(store::McpAuth::Bearer { token }) => token.clone(),
+        Some(store::McpAuth::OAuth(_)) => access_token(store, url).await?,
+    };
+    let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+        .map_err(|e| error::Error::InvalidData(format!("Invalid token: {e}")))?;
+    value.set_sensitive(true);
+    map.insert(AUTHORIZATION, value);
+    Ok(map)
+}
+
+/// 保存的访问令牌, 即将过期时使用刷新令牌更新
+pub async fn access_token(store: &store::Store, url: &str) -> Result<String, error::Error> {
+    if let Some(token) = valid_token(store, url)? {
+        return Ok(token);
+    }
+
+    // 同一服务串行刷新, 等待期间其它请求已经刷新时直接使用新的令牌
+    let lock = REFRESHING
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(url.to_string())
+        .or_default()
+        .clone();
+    let _refreshing = lock.lock().await;
+    if let Some(token) = valid_token(store, url)? {
+        return Ok(token);
+    }
+
+    let token = store
+        .get_mcp_oauth_token(url)?
+        .ok_or_else(|| error::Error::Mcp(format!("{url} is not authorized")))?;
+    let now = chrono::Utc::now().timestamp_millis();
+
+    let Some(refresh_token) = token.refresh_token else {
+        return Err(error::Error::Mcp(format!("Authorization of {url} expired")));
+    };
+    let mut params = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_str()),
+        ("client_id", token.client_id.as_str()),
+    ];
+    if let Some(secret) = &token.client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+    let response = request_token(&reqwest::Client::new(), &token.token_endpoint, &params).await?;
+
+    // 服务端没有返回新的刷新令牌时继续使用原来的
+    let token = store::McpOAuthToken {
+        access_token: response.access_token,
+        refresh_token: response.refresh_token.or(Some(refresh_token)),
+        expires_at: response.expires_in.map(|expires_in| now + expires_in * 1000),
+        token_endpoint: token.token_endpoint,
+        client_id: token.client_id,
+        client_secret: token.client_secret,
+    };
+    store.set_mcp_oauth_token(url, &token)?;
+    Ok(token.access_token)
+}
+
+/// 保存的访问令牌还在有效期内时返回, 未授权时返回错误
+fn valid_token(store: &store::Store, url: &str) -> Result<Option<String>, error::Error> {
+    let token = store
+        .get_mcp_oauth_token(url)?
+        .ok_or_else(|| error::Error::Mcp(format!("{url} is not authorized")))?;
+    let now = chrono::Utc::now().timestamp_millis();
+    Ok(token
+        .expires_at
+        .is_none_or(|expires_at| expires_at - REFRESH_MARGIN > now)
+        .then_some(token.access_token))
+}
+
+/// OAuth 授权码流程, 使用 PKCE, open 在浏览器中打开授权页面, 授权后回调到本地端口
+pub async fn authorize(
+    store: &store::Store, url: &str, oauth: &store::McpOAuth,
+    open: impl FnOnce(&str) -> Result<(), error::Error>,
+) -> Result<store::McpOAuthToken, error::Error> {
+    let http = reqwest::Client::new();
+    let metadata = discover(&http, url).await?;
+
+    let listener = TcpListener::bind(("127.0.0.1", oauth.redirect_port)).await?;
+    let redirect_uri = format!("http://127.0.0.1:{}/callback", listener.local_addr()?.port());
+
+    let (client_id, client_secret) = match &oauth.client_id {
+        Some(client_id) => (client_id.clone(), oauth.client_secret.clone()),
+        None => {
+            let registration = register(&http, &metadata, &redirect_uri).await?;
+            (registration.client_id, registration.client_secret)
+        }
+    };
+
+    let verifier = random_string(64);
+    let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let state = random_string(32);
+
+    let mut authorize_url = parse_url(&metadata.authorization_endpoint)?;
+    {
+        let mut query = authorize_url.query_pairs_mut();
+        query
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &client_id)
+            .append_pair("redirect_uri", &redirect_uri)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256")
+            .append_pair("state", &state)
+            .append_pair("resource", url);
+        if let Some(scope) = &oauth.scope {
+            query.append_pair("scope", scope);
+        }
+    }
+    open(authorize_url.as_str())?;
+
+    let code = tokio::time::timeout(AUTHORIZE_TIMEOUT, callback(&listener, &state))
+        .await
+        .map_err(|_| error::Error::Timeout("Waiting for authorization".to_string()))??;
+
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", client_id.as_str()),
+        ("code_verifier", verifier.as_str()),
+        ("resource", url),
+    ];
+    if let Some(secret) = &client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+    let response = request_token(&http, &metadata.token_endpoint, &params).await?;
+
+    let token = store::McpOAuthToken {
+        access_token: response.access_token,
+        refresh_token: response.refresh_token,
+        expires_at: response
+            .expires_in
+            .map(|expires_in| chrono::Utc::now().timestamp_millis() + expires_in * 1000),
+        token_endpoint: metadata.token_endpoint,
+        client_id,
+        client_secret,
+    };
+    store.set_mcp_oauth_token(url, &token)?;
+    Ok(token)
+}
+
+fn parse_url(url: &str) -> Result<Url, error::Error> {
+    Url::parse(url).map_err(|e| error::Error::InvalidData(format!("Invalid url {url}: {e}")))
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+/// 查找授权服务, 先读取受保护资源的元数据, 没有时授权服务与 MCP 服务同源
+async fn discover(http: &reqwest::Client, url: &str) -> Result<Metadata, error::Error> {
+    let origin = parse_url(url)?.origin().ascii_serialization();
+
+    let response = http.get(format!("{origin}/.well-known/oauth-protected-resource")).send().await;
+    let server = match response.and_then(|response| response.error_for_status()) {
+        Ok(response) => response
+            .json::<ResourceMetadata>()
+            .await
+            .ok()
+            .and_then(|resource| resource.authorization_servers.into_iter().next()),
+        Err(_) => None,
+    };
+    let issuer = match server {
+        Some(server) => server.trim_end_matches('/').to_string(),
+        None => origin,
+    };
+
+    // issuer 带路径时, 路径放在 well-known 之后
+    let issuer_url = parse_url(&issuer)?;
+    let well_known = format!(
+        "{}/.well-known/oauth-authorization-server{}",
+        issuer_url.origin().ascii_serialization(),
+        issuer_url.path().trim_end_matches('/')
+    );
+    let response = http.get(well_known).send().await?;
+    if response.status().is_success() {
+        return Ok(response.json().await?);
+    }
+
+    // 没有元数据时使用默认地址
+    Ok(Metadata {
+        authorization_endpoint: format!("{issuer}/authorize"),
+        token_endpoint: format!("{issuer}/token"),
+        registration_endpoint: Some(format!("{issuer}/register")),
+    })
+}
+
+async fn register(
+    http: &reqwest::Client, metadata: &Metadata, redirect_uri: &str,
+) -> Result<Registration, error::Error> {
+    let Some(endpoint) = &metadata.registration_endpoint else {
+        return Err(error::Error::Mcp(
+            "Authorization server does not support client registration, set client id".to_string(),
+        ));
+    };
+    let response = http
+        .post(endpoint)
+        .json(&serde_json::json!({
+            "client_name": "Causal AI",
+            "redirect_uris": [redirect_uri],
+            "grant_types": ["authorization_code", "refresh_token"],
+            "response_types": ["code"],
+            "token_endpoint_auth_method": "none",
+        }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(error::Error::Mcp(format!("Register client: {status} {body}")));
+    }
+    Ok(response.json().await?)
+}
+
+async fn request_token(
+    http: &reqwest::Client, endpoint: &str, params: &[(&str, &str)],
+) -> Result<TokenResponse, error::Error> {
+    let response = http.post(endpoint).form(params).send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(error::Error::Mcp(format!("Request token: {status} {body}")));
+    }
+    Ok(response.json().await?)
+}
+
+/// 等待浏览器回调, 返回授权码
+///
+/// 其它请求, state 不匹配的回调以及读写失败的连接都不影响等待, 直到本次授权的回调或者超时
+async fn callback(listener: &TcpListener, state: &str) -> Result<String, error::Error> {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Accept OAuth callback error: {e}");
+                continue;
+            }
+        };
+        match handle_callback(stream, state).await {
+            Ok(Some(result)) => return result,
+            Ok(None) => {}
+            Err(e) => tracing::warn!("OAuth callback connection error: {e}"),
+        }
+    }
+}
+
+/// 处理一个回调连接, 不是本次授权的回调时返回 None
+async fn handle_callback(
+    stream: TcpStream, state: &str,
+) -> Result<Option<Result<String, error::Error>>, error::Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let path = line.split_whitespace().nth(1).unwrap_or_default();
+    let url = parse_url(&format!("http://127.0.0.1{path}"))?;
+    // 请求头不需要, 读到空行为止
+    let mut header = String::new();
+    while reader.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+
+    let mut stream = reader.into_inner();
+    let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    if url.path() != "/callback" || query.get("state").map(String::as_str) != Some(state) {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await?;
+        return Ok(None);
+    }
+
+    let result = if let Some(error) = query.get("error") {
+        let description = query.get("error_description").map(String::as_str);
+        Err(error::Error::Mcp(format!(
+            "Authorization failed: {error} {}",
+            description.unwrap_or_default()
+        )))
+    } else {
+        query
+            .get("code")
+            .cloned()
+            .ok_or(error::Error::Mcp("Authorization code not found".to_string()))
+    };
+
+    let body = match &result {
+        Ok(_) => "授权完成, 可以关闭此页面".to_string(),
+        Err(e) => e.to_string(),
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    // 已经拿到授权结果, 浏览器断开也不影响
+    let _ = stream.write_all(response.as_bytes()).await;
+    Ok(Some(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// 模拟的授权服务, 自动同意授权, 按记录的 PKCE challenge 校验令牌请求
+    #[derive(Default)]
+    struct AuthServer {
+        base: String,
+        challenge: String,
+        refreshed: usize,
+    }
+
+    async fn handle(stream: TcpStream, server: Arc<Mutex<AuthServer>>) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let url = Url::parse(&format!("http://127.0.0.1{}", parts.next().unwrap_or("/"))).unwrap();
+
+        let mut length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? <= 2 {
+                break;
+            }
+            let header = line.split_once(':');
+            if let Some((_, value)) =
+                header.filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            {
+                length = value.trim().parse().unwrap_or_default();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await?;
+        let form: HashMap<String, String> =
+            Url::parse(&format!("http://127.0.0.1/?{}", String::from_utf8_lossy(&body)))
+                .unwrap()
+                .query_pairs()
+                .into_owned()
+                .collect();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+        let (status, location, body) = {
+            let mut server = server.lock().unwrap();
+            match (method.as_str(), url.path()) {
+                ("GET", "/.well-known/oauth-authorization-server") => {
+                    let base = &server.base;
+                    let metadata = serde_json::json!({
+                        "issuer": base,
+                        "authorization_endpoint": format!("{base}/authorize"),
+                        "token_endpoint": format!("{base}/token"),
+                        "registration_endpoint": format!("{base}/register"),
+                    });
+                    ("200 OK", None, metadata.to_string())
+                }
+                ("POST", "/register") => {
+                    ("201 Created", None, r#"{"client_id":"dynamic-client"}"#.to_string())
+                }
+                ("GET", "/authorize") => {
+                    assert_eq!(query["code_challenge_method"], "S256");
+                    server.challenge = query["code_challenge"].clone();
+                    let location = format!(
+                        "{}?code=test-code&state={}",
+                        query["redirect_uri"], query["state"]
+                    );
+                    ("302 Found", Some(location), String::new())
+                }
+                ("POST", "/token") if form["grant_type"] == "authorization_code" => {
+                    let challenge = BASE64_URL_SAFE_NO_PAD
+                        .encode(Sha256::digest(form["code_verifier"].as_bytes()));
+                    if form["code"] == "test-code" && challenge == server.challenge {
+                        // 有效期短于提前刷新的时间, 下次使用时刷新
+                        let token = r#"{"access_token":"access-1","refresh_token":"refresh-1","expires_in":10}"#;
+                        ("200 OK", None, token.to_string())
+                    } else {
+                        ("400 Bad Request", None, r#"{"error":"invalid_grant"}"#.to_string())
+                    }
+                }
+                ("POST", "/token") if form["refresh_token"] == "refresh-1" => {
+                    server.refreshed += 1;
+                    ("200 OK", None, r#"{"access_token":"access-2","expires_in":3600}"#.to_string())
+                }
+                _ => ("404 Not Found", None, String::new()),
+            }
+        };
+
+        let location = location.map(|location| format!("Location: {location}\r\n"));
+        let response = format!(
+            "HTTP/1.1 {status}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            location.unwrap_or_default(),
+            body.len()
+        );
+        reader.into_inner().write_all(response.as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn test_authorize_and_refresh() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = Arc::new(Mutex::new(AuthServer { base: base.clone(), ..Default::default() }));
+        let state = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, state.clone()));
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = store::Store::open(dir.path()).unwrap();
+        let url = format!("{base}/mcp");
+
+        // 模拟浏览器打开授权页面, 跟随重定向回调到本地端口
+        // 之前断开的连接和 state 不匹配的回调不影响授权
+        let token = authorize(&store, &url, &store::McpOAuth::default(), |authorize_url| {
+            let authorize_url = authorize_url.to_string();
+            let redirect_uri = Url::parse(&authorize_url)
+                .unwrap()
+                .query_pairs()
+                .find(|(key, _)| key == "redirect_uri")
+                .map(|(_, value)| value.into_owned())
+                .unwrap();
+            tokio::spawn(async move {
+                let callback = Url::parse(&redirect_uri).unwrap();
+                let addr = format!("127.0.0.1:{}", callback.port().unwrap());
+                drop(TcpStream::connect(&addr).await.unwrap());
+                let _ = reqwest::get(format!("{redirect_uri}?code=forged&state=wrong")).await;
+                let _ = reqwest::get(authorize_url).await;
+            });
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(token.access_token, "access-1");
+        assert_eq!(token.client_id, "dynamic-client");
+
+        // 令牌即将过期, 同时使用时只刷新一次, 服务端没有返回新的刷新令牌时保留原来的
+        let (first, second) = tokio::join!(access_token(&store, &url), access_token(&store, &url));
+        assert_eq!(first.unwrap(), "access-2");
+        assert_eq!(second.unwrap(), "access-2");
+        assert_eq!(access_token(&store, &url).await.unwrap(), "access-2");
+        assert_eq!(server.lock().unwrap().refreshed, 1);
+        let saved = store.get_mcp_oauth_token(&url).unwrap().unwrap();
+        assert_eq!(saved.refresh_token.as_deref(), Some("refresh-1"));
+
+        let custom = HashMap::from([("X-Api-Key".to_string(), "key".to_string())]);
+        let oauth = store::McpAuth::OAuth(store::McpOAuth::default());
+        let map = headers(&store, &url, &custom, Some(&oauth)).await.unwrap();
+        assert_eq!(map[AUTHORIZATION], "Bearer access-2");
+        assert_eq!(map["x-api-key"], "key");
+
+        let bearer = store::McpAuth::Bearer { token: "secret".to_string() };
+        let map = headers(&store, &url, &HashMap::new(), Some(&bearer)).await.unwrap();
+        assert_eq!(map[AUTHORIZATION], "Bearer secret");
+
+        // 未授权的服务
+        let other = store::McpAuth::OAuth(store::McpOAuth::default());
+        let other_url = format!("{base}/other");
+        assert!(headers(&store, &other_url, &HashMap::new(), Some(&other)).await.is_err());
+    }
+}
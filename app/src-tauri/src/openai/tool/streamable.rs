@@ -0,0 +1,670 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{StreamExt, channel::mpsc};
+use reqwest::{
+    Method, StatusCode,
+    header::{ACCEPT, CONTENT_TYPE, HeaderMap},
+};
+use rmcp::model::{ClientJsonRpcMessage, ServerJsonRpcMessage};
+use serde_json::Value;
+
+use crate::error;
+
+/// 服务端分配的会话ID, 之后的请求都要带上
+const SESSION_ID: &str = "mcp-session-id";
+/// 恢复 SSE 流时带上最后收到的事件ID
+const LAST_EVENT_ID: &str = "last-event-id";
+/// SSE 流连续断开后最多恢复的次数
+const MAX_RESUME: usize = 5;
+/// 服务端没有指定 retry 时, 恢复前等待的时间
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+/// rmcp 使用的传输, 发送和接收 JSON-RPC 消息
+pub type Transport =
+    (mpsc::UnboundedSender<ClientJsonRpcMessage>, mpsc::UnboundedReceiver<ServerJsonRpcMessage>);
+
+/// 初始化失败的原因, 传输关闭后由调用方读取
+#[derive(Debug, Clone, PartialEq)]
+pub enum InitError {
+    /// 初始化请求返回 4xx, 说明服务端只支持旧的 SSE 传输
+    Legacy(StatusCode),
+    /// 初始化请求失败, 包括响应的状态码和内容
+    Failed(String),
+}
+
+/// 启动 Streamable HTTP 传输
+///
+/// 初始化失败时传输关闭, 返回的 InitError 记录失败的原因,
+/// headers 为自定义请求头和认证, 每个请求都会带上
+pub fn start(
+    url: String, headers: HeaderMap,
+) -> Result<(Transport, Arc<Mutex<Option<InitError>>>), error::Error> {
+    let (sink, outgoing) = mpsc::unbounded();
+    let (incoming, stream) = mpsc::unbounded();
+    let failure = Arc::new(Mutex::new(None));
+
+    let connection = Arc::new(Connection {
+        url,
+        http: reqwest::Client::builder().default_headers(headers).build()?,
+        session: Mutex::new(None),
+        initialize: Mutex::new(Vec::new()),
+        renewing: tokio::sync::Mutex::new(()),
+        incoming,
+    });
+    tauri::async_runtime::spawn(run(connection, outgoing, failure.clone()));
+
+    Ok(((sink, stream), failure))
+}
+
+/// 发送消息的任务之间共享的连接状态
+struct Connection {
+    url: String,
+    http: reqwest::Client,
+    session: Mutex<Option<String>>,
+    /// 初始化请求和通知, 会话过期时重新发送
+    initialize: Mutex<Vec<Value>>,
+    renewing: tokio::sync::Mutex<()>,
+    incoming: mpsc::UnboundedSender<ServerJsonRpcMessage>,
+}
+
+async fn run(
+    connection: Arc<Connection>, mut outgoing: mpsc::UnboundedReceiver<ClientJsonRpcMessage>,
+    failure: Arc<Mutex<Option<InitError>>>,
+) {
+    // 第一条消息为初始化请求, 等待完成以获得会话ID, 失败时记录原因并关闭传输
+    let Some(message) = outgoing.next().await else {
+        return;
+    };
+    let message = match serde_json::to_value(&message) {
+        Ok(message) => message,
+        Err(e) => {
+            *failure.lock().unwrap() = Some(InitError::Failed(e.to_string()));
+            return;
+        }
+    };
+    if let Err(e) = connection.initialize(&message).await {
+        tracing::info!("MCP streamable http: {} initialize {e:?}", connection.url);
+        *failure.lock().unwrap() = Some(e);
+        return;
+    }
+
+    let mut listener = None;
+    while let Some(message) = outgoing.next().await {
+        let message = match serde_json::to_value(&message) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("MCP streamable http: {e}");
+                continue;
+            }
+        };
+
+        // 初始化完成后打开 GET 流, 接收服务端主动发送的消息
+        if message.get("method").and_then(Value::as_str) == Some("notifications/initialized") {
+            connection.initialize.lock().unwrap().push(message.clone());
+            if let Err(e) = connection.post(&message).await {
+                tracing::warn!("MCP streamable http: {e}");
+            }
+            if listener.is_none() {
+                listener = Some(tauri::async_runtime::spawn(connection.clone().listen()));
+            }
+            continue;
+        }
+
+        let connection = connection.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = connection.post(&message).await {
+                tracing::warn!("MCP streamable http: {e}");
+                // 请求失败时返回错误响应, 避免等待到超时
+                if let Some(id) = request_id(&message) {
+                    connection.deliver(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32000, "message": e.to_string() }
+                    }));
+                }
+            }
+        });
+    }
+
+    // 客户端关闭, 结束会话
+    if let Some(listener) = listener {
+        listener.abort();
+    }
+    let session = connection.session.lock().unwrap().clone();
+    if let Some(session) = session {
+        let _ = connection.http.delete(&connection.url).header(SESSION_ID, session).send().await;
+    }
+}
+
+/// 旧的 SSE 服务端不接受 POST 初始化请求, 需要授权的除外
+fn is_legacy(status: StatusCode) -> bool {
+    status.is_client_error()
+        && status != StatusCode::UNAUTHORIZED
+        && status != StatusCode::FORBIDDEN
+}
+
+/// 请求的ID, 通知和响应没有
+fn request_id(message: &Value) -> Option<Value> {
+    message.get("method")?;
+    message.get("id").cloned()
+}
+
+impl Connection {
+    /// 发送初始化请求, 成功后保存以便会话过期时重新发送
+    async fn initialize(&self, message: &Value) -> Result<(), InitError> {
+        let failed = |e: error::Error| InitError::Failed(e.to_string());
+        let (_, response) = self.send(message).await.map_err(failed)?;
+        let status = response.status();
+        if is_legacy(status) {
+            return Err(InitError::Legacy(status));
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(InitError::Failed(format!("{status}: {body}")));
+        }
+        self.receive(response, request_id(message), true).await.map_err(failed)?;
+        self.initialize.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+
+    fn request(&self, method: Method) -> reqwest::RequestBuilder {
+        let request = self.http.request(method, &self.url);
+        match self.session.lock().unwrap().as_deref() {
+            Some(session) => request.header(SESSION_ID, session),
+            None => request,
+        }
+    }
+
+    /// 发送消息, 返回使用的会话ID和响应
+    async fn send(
+        &self, message: &Value,
+    ) -> Result<(Option<String>, reqwest::Response), error::Error> {
+        let session = self.session.lock().unwrap().clone();
+        let response = self
+            .request(Method::POST)
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(message)
+            .send()
+            .await?;
+        if let Some(id) = response.headers().get(SESSION_ID).and_then(|v| v.to_str().ok()) {
+            *self.session.lock().unwrap() = Some(id.to_string());
+        }
+        Ok((session, response))
+    }
+
+    /// 发送消息并处理响应, 会话过期时重新初始化后再发送一次
+    async fn post(&self, message: &Value) -> Result<(), error::Error> {
+        let (session, mut response) = self.send(message).await?;
+        if response.status() == StatusCode::NOT_FOUND && session.is_some() {
+            self.renew(session).await?;
+            response = self.send(message).await?.1;
+        }
+        self.receive(response, request_id(message), true).await
+    }
+
+    /// 会话过期, 不带会话ID重新发送初始化请求和通知, 初始化结果不再交给客户端
+    async fn renew(&self, expired: Option<String>) -> Result<(), error::Error> {
+        let _renewing = self.renewing.lock().await;
+        if *self.session.lock().unwrap() != expired {
+            // 其它请求已经重新初始化
+            return Ok(());
+        }
+        tracing::info!("MCP streamable http: session expired, initialize again");
+
+        *self.session.lock().unwrap() = None;
+        let messages = self.initialize.lock().unwrap().clone();
+        for message in messages {
+            let (_, response) = self.send(&message).await?;
+            self.receive(response, request_id(&message), false).await?;
+        }
+        Ok(())
+    }
+
+    /// 处理 POST 的响应, JSON 或者 SSE 流, deliver 为 false 时丢弃收到的消息
+    async fn receive(
+        &self, response: reqwest::Response, request_id: Option<Value>, deliver: bool,
+    ) -> Result<(), error::Error> {
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(error::Error::Mcp(format!("{status}: {body}")));
+        }
+        if status == StatusCode::ACCEPTED {
+            return Ok(());
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if !content_type.starts_with("text/event-stream") {
+            let body = response.bytes().await?;
+            if deliver && !body.is_empty() {
+                self.deliver(serde_json::from_slice(&body)?);
+            }
+            return Ok(());
+        }
+
+        let Some(request_id) = request_id else {
+            return Ok(());
+        };
+        let mut stream = EventStream::default();
+        let mut response = response;
+        let mut resumed = 0;
+        loop {
+            if stream.read(self, response, Some(&request_id), deliver).await {
+                return Ok(());
+            }
+            // 流在收到响应之前断开, 带上最后的事件ID恢复
+            let Some(last_id) = stream.last_id.clone() else {
+                return Err(error::Error::Mcp("Stream closed before response".to_string()));
+            };
+            if resumed >= MAX_RESUME {
+                return Err(error::Error::Mcp("Stream closed, resume failed".to_string()));
+            }
+            resumed += 1;
+            tokio::time::sleep(stream.retry).await;
+            response = self.resume(&last_id).await?;
+            if !response.status().is_success() {
+                return Err(error::Error::Mcp(format!("Resume stream: {}", response.status())));
+            }
+        }
+    }
+
+    async fn resume(&self, last_id: &str) -> Result<reqwest::Response, error::Error> {
+        let request = self.request(Method::GET).header(ACCEPT, "text/event-stream");
+        Ok(request.header(LAST_EVENT_ID, last_id).send().await?)
+    }
+
+    /// 接收服务端主动发送的消息, 服务端不支持时返回 405
+    async fn listen(self: Arc<Self>) {
+        let mut stream = EventStream::default();
+        let mut failures = 0;
+        loop {
+            let response = match stream.last_id.clone() {
+                Some(last_id) => self.resume(&last_id).await,
+                None => self
+                    .request(Method::GET)
+                    .header(ACCEPT, "text/event-stream")
+                    .send()
+                    .await
+                    .map_err(error::Error::from),
+            };
+            match response {
+                Ok(response) if response.status().is_success() => {
+                    failures = 0;
+                    stream.read(&self, response, None, true).await;
+                }
+                Ok(response) if response.status() == StatusCode::METHOD_NOT_ALLOWED => return,
+                Ok(response) => {
+                    tracing::debug!("MCP streamable http listen: {}", response.status());
+                    failures += 1;
+                }
+                Err(e) => {
+                    tracing::debug!("MCP streamable http listen: {e}");
+                    failures += 1;
+                }
+            }
+            if failures >= MAX_RESUME {
+                return;
+            }
+            tokio::time::sleep(stream.retry).await;
+        }
+    }
+
+    /// 把服务端消息交给客户端, 批量消息逐条发送
+    fn deliver(&self, message: Value) {
+        let messages = match message {
+            Value::Array(messages) => messages,
+            message => vec![message],
+        };
+        for message in messages {
+            match serde_json::from_value::<ServerJsonRpcMessage>(message) {
+                Ok(message) => {
+                    let _ = self.incoming.unbounded_send(message);
+                }
+                Err(e) => tracing::warn!("MCP streamable http: invalid message {e}"),
+            }
+        }
+    }
+}
+
+/// SSE 流的解析状态, 恢复时保留最后的事件ID和重试间隔
+struct EventStream {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+    last_id: Option<String>,
+    retry: Duration,
+}
+
+impl Default for EventStream {
+    fn default() -> Self {
+        Self { buffer: Vec::new(), data: Vec::new(), last_id: None, retry: RESUME_DELAY }
+    }
+}
+
+impl EventStream {
+    /// 读取到流结束, 收到请求的响应时返回 true
+    async fn read(
+        &mut self, connection: &Connection, response: reqwest::Response,
+        request_id: Option<&Value>, deliver: bool,
+    ) -> bool {
+        self.buffer.clear();
+        self.data.clear();
+
+        let mut chunks = response.bytes_stream();
+        while let Some(Ok(chunk)) = chunks.next().await {
+            self.buffer.extend_from_slice(&chunk);
+            while let Some(message) = self.next_message() {
+                let answered = request_id.is_some_and(|id| {
+                    message.get("id") == Some(id)
+                        && (message.get("result").is_some() || message.get("error").is_some())
+                });
+                if deliver {
+                    connection.deliver(message);
+                }
+                if answered {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// 解析缓冲中完整的事件, 返回下一条 JSON 消息
+    fn next_message(&mut self) -> Option<Value> {
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                // 空行结束一个事件
+                let data = std::mem::take(&mut self.data).join("\n");
+                if data.is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&data) {
+                    Ok(message) => return Some(message),
+                    Err(e) => tracing::warn!("MCP streamable http: invalid event {e}"),
+                }
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "data" => self.data.push(value.to_string()),
+                "id" => self.last_id = Some(value.to_string()),
+                "retry" => {
+                    if let Ok(retry) = value.parse() {
+                        self.retry = Duration::from_millis(retry);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// 模拟服务收到的请求
+    #[derive(Debug, Clone)]
+    struct Request {
+        method: String,
+        session: Option<String>,
+        last_event_id: Option<String>,
+        body: Value,
+    }
+
+    /// 模拟的 Streamable HTTP 服务
+    #[derive(Default)]
+    struct McpServer {
+        /// 初始化请求返回的状态和内容, 为空时正常初始化
+        reject: Option<(&'static str, &'static str)>,
+        sessions: usize,
+        /// 已过期的会话ID, 带上它的请求返回 404
+        expired: Option<String>,
+        requests: Vec<Request>,
+    }
+
+    fn pong(id: &Value) -> String {
+        json!({"jsonrpc": "2.0", "id": id, "result": {}}).to_string()
+    }
+
+    async fn handle(stream: TcpStream, server: Arc<Mutex<McpServer>>) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let method = line.split_whitespace().next().unwrap_or_default().to_string();
+
+        let (mut length, mut session, mut last_event_id) = (0, None, None);
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? <= 2 {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => length = value.parse().unwrap_or_default(),
+                SESSION_ID => session = Some(value),
+                LAST_EVENT_ID => last_event_id = Some(value),
+                _ => {}
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await?;
+        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        let request = Request { method, session, last_event_id, body };
+
+        let (status, session, content_type, body) = {
+            let mut server = server.lock().unwrap();
+            server.requests.push(request.clone());
+            let expired = request.session.is_some() && request.session == server.expired;
+            let id = &request.body["id"];
+            match (request.method.as_str(), request.body["method"].as_str().unwrap_or_default()) {
+                ("POST", "initialize") => match server.reject {
+                    Some((status, body)) => (status, None, "text/plain", body.to_string()),
+                    None => {
+                        server.sessions += 1;
+                        let result = json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "result": {
+                                "protocolVersion": "2025-03-26",
+                                "capabilities": {},
+                                "serverInfo": {"name": "mock", "version": "1.0"}
+                            }
+                        });
+                        let session = format!("session-{}", server.sessions);
+                        ("200 OK", Some(session), "application/json", result.to_string())
+                    }
+                },
+                // 不支持服务端主动推送的流
+                ("GET", _) if request.last_event_id.is_none() => {
+                    ("405 Method Not Allowed", None, "text/plain", String::new())
+                }
+                _ if expired => ("404 Not Found", None, "text/plain", String::new()),
+                ("POST", "notifications/initialized") => {
+                    ("202 Accepted", None, "text/plain", String::new())
+                }
+                // 第一次 ping 的流只发送事件ID就断开, 恢复后再返回响应
+                ("POST", "ping") if *id == 2 => {
+                    ("200 OK", None, "text/event-stream", "retry: 10\nid: event-1\n\n".to_string())
+                }
+                ("POST", "ping") => ("200 OK", None, "application/json", pong(id)),
+                ("GET", _) if request.last_event_id.as_deref() == Some("event-1") => {
+                    let event = format!("id: event-2\ndata: {}\n\n", pong(&json!(2)));
+                    ("200 OK", None, "text/event-stream", event)
+                }
+                _ => ("400 Bad Request", None, "text/plain", String::new()),
+            }
+        };
+
+        let session = session.map(|session| format!("{SESSION_ID}: {session}\r\n"));
+        let response = format!(
+            "HTTP/1.1 {status}\r\n{}Content-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            session.unwrap_or_default(),
+            body.len()
+        );
+        reader.into_inner().write_all(response.as_bytes()).await
+    }
+
+    async fn serve(server: McpServer) -> (String, Arc<Mutex<McpServer>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/mcp", listener.local_addr().unwrap());
+        let server = Arc::new(Mutex::new(server));
+        let state = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, state.clone()));
+            }
+        });
+        (url, server)
+    }
+
+    fn message(value: Value) -> ClientJsonRpcMessage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn initialize() -> ClientJsonRpcMessage {
+        message(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "protocolVersion": "2025-03-26",
+                "capabilities": {},
+                "clientInfo": {"name": "test", "version": "1.0"}
+            }
+        }))
+    }
+
+    fn ping(id: u32) -> ClientJsonRpcMessage {
+        message(json!({"jsonrpc": "2.0", "id": id, "method": "ping"}))
+    }
+
+    /// 下一条服务端消息, 传输关闭时为空
+    async fn next(stream: &mut mpsc::UnboundedReceiver<ServerJsonRpcMessage>) -> Option<Value> {
+        let message = tokio::time::timeout(Duration::from_secs(5), stream.next()).await.unwrap()?;
+        Some(serde_json::to_value(message).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_session_renew_and_resume() {
+        let (url, server) = serve(McpServer::default()).await;
+        let ((sink, mut stream), failure) = start(url, HeaderMap::new()).unwrap();
+
+        sink.unbounded_send(initialize()).unwrap();
+        assert_eq!(next(&mut stream).await.unwrap()["id"], 1);
+        let initialized = json!({"jsonrpc": "2.0", "method": "notifications/initialized"});
+        sink.unbounded_send(message(initialized)).unwrap();
+
+        // 流在响应之前断开, 带上最后的事件ID恢复
+        sink.unbounded_send(ping(2)).unwrap();
+        assert_eq!(next(&mut stream).await.unwrap()["id"], 2);
+
+        // 会话过期, 重新初始化后再发送一次, 初始化的响应不交给客户端
+        server.lock().unwrap().expired = Some("session-1".to_string());
+        sink.unbounded_send(ping(3)).unwrap();
+        assert_eq!(next(&mut stream).await.unwrap()["id"], 3);
+        assert!(failure.lock().unwrap().is_none());
+
+        let requests = server.lock().unwrap().requests.clone();
+        let posts = requests
+            .iter()
+            .filter(|request| request.method == "POST")
+            .map(|request| {
+                let method = request.body["method"].as_str().unwrap_or_default().to_string();
+                (method, request.session.as_deref().map(str::to_string))
+            })
+            .collect::<Vec<_>>();
+        let session = |id: &str| Some(format!("session-{id}"));
+        assert_eq!(
+            posts,
+            vec![
+                ("initialize".to_string(), None),
+                ("notifications/initialized".to_string(), session("1")),
+                ("ping".to_string(), session("1")),
+                ("ping".to_string(), session("1")),
+                ("initialize".to_string(), None),
+                ("notifications/initialized".to_string(), session("2")),
+                ("ping".to_string(), session("2")),
+            ]
+        );
+
+        let resume = requests.iter().find(|request| request.last_event_id.is_some()).unwrap();
+        assert_eq!(resume.method, "GET");
+        assert_eq!(resume.session, session("1"));
+        assert_eq!(resume.last_event_id.as_deref(), Some("event-1"));
+    }
+
+    #[tokio::test]
+    async fn test_initialize_failure() {
+        // 初始化请求返回 4xx, 回退到旧的 SSE 传输
+        let reject = Some(("405 Method Not Allowed", ""));
+        let (url, _) = serve(McpServer { reject, ..Default::default() }).await;
+        let ((sink, mut stream), failure) = start(url, HeaderMap::new()).unwrap();
+        sink.unbounded_send(initialize()).unwrap();
+        assert!(next(&mut stream).await.is_none());
+        assert_eq!(
+            *failure.lock().unwrap(),
+            Some(InitError::Legacy(StatusCode::METHOD_NOT_ALLOWED))
+        );
+
+        // 认证失败时返回状态码和内容
+        let reject = Some(("401 Unauthorized", "invalid token"));
+        let (url, _) = serve(McpServer { reject, ..Default::default() }).await;
+        let ((sink, mut stream), failure) = start(url, HeaderMap::new()).unwrap();
+        sink.unbounded_send(initialize()).unwrap();
+        assert!(next(&mut stream).await.is_none());
+        assert_eq!(
+            *failure.lock().unwrap(),
+            Some(InitError::Failed("401 Unauthorized: invalid token".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let mut stream = EventStream::default();
+        stream.buffer.extend_from_slice(b"retry: 3000\r\nid: 1\r\ndata: {\"jsonrpc\":");
+        assert!(stream.next_message().is_none());
+
+        // 数据块在事件中间断开
+        stream
+            .buffer
+            .extend_from_slice(b"\"2.0\",\"id\":1,\r\ndata: \"result\":{}}\r\n\r\n: ping\n\n");
+        let message = stream.next_message().unwrap();
+        assert_eq!(message["id"], 1);
+        assert_eq!(stream.last_id.as_deref(), Some("1"));
+        assert_eq!(stream.retry, Duration::from_millis(3000));
+        assert!(stream.next_message().is_none());
+        assert!(stream.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_legacy_status() {
+        assert!(is_legacy(StatusCode::NOT_FOUND));
+        assert!(is_legacy(StatusCode::METHOD_NOT_ALLOWED));
+        assert!(!is_legacy(StatusCode::UNAUTHORIZED));
+        assert!(!is_legacy(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+}
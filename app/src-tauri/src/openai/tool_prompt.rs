@@ -0,0 +1,255 @@
+use async_openai::types::{ChatCompletionMessageToolCall, ChatCompletionToolType, FunctionCall};
+use serde_json::{Map, Value, json};
+
+use crate::openai::tool::ToolDescription;
+
+/// 工具调用的开始标签
+pub const TOOL_CALL_OPEN: &str = "<tool_call>";
+/// 工具调用的结束标签
+pub const TOOL_CALL_CLOSE: &str = "</tool_call>";
+
+/// 在系统提示词中描述可用工具和调用格式, 用于不支持原生函数调用的模型
+pub fn prompt(descriptions: &[ToolDescription]) -> String {
+    let tools = descriptions
+        .iter()
+        .map(|tool| {
+            json!({
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.schema,
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "# Tools\n\n\
+         You may call the following tools, each described by its name, description \
+         and JSON Schema parameters:\n\n\
+         <tools>\n{tools}\n</tools>\n\n\
+         To call a tool, reply with one block per call in exactly this format:\n\
+         {TOOL_CALL_OPEN}\n{{\"name\": \"<tool name>\", \"arguments\": {{<arguments as JSON>}}}}\n{TOOL_CALL_CLOSE}\n\n\
+         Do not write anything after the tool calls. The results will be sent back \
+         in <tool_result> blocks. Only call tools when needed, otherwise answer directly."
+    )
+}
+
+/// 把工具结果包装为发送给模型的消息内容
+pub fn result(name: &str, content: &str) -> String {
+    format!("<tool_result name=\"{name}\">\n{content}\n</tool_result>")
+}
+
+/// 从模型输出的文本中解析工具调用
+///
+/// 只解析 `<tool_call>` 标签内的调用, 代码块和整段 JSON 可能是结构化输出等普通回复.
+/// 兼容 `arguments`/`parameters` 等常见写法以及字符串形式的参数
+pub fn parse(content: &str, id_prefix: &str) -> Vec<ChatCompletionMessageToolCall> {
+    tagged_blocks(content)
+        .into_iter()
+        .filter_map(parse_json)
+        .flat_map(|value| match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        })
+        .filter_map(|value| to_call(&value))
+        .enumerate()
+        .map(|(index, function)| ChatCompletionMessageToolCall {
+            id: format!("{id_prefix}_{index}"),
+            r#type: ChatCompletionToolType::Function,
+            function,
+        })
+        .collect()
+}
+
+/// `<tool_call>` 标签内的文本, 最后一个标签可以没有结束标签
+fn tagged_blocks(content: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(TOOL_CALL_OPEN) {
+        rest = &rest[start + TOOL_CALL_OPEN.len()..];
+        let end = rest.find(TOOL_CALL_CLOSE).unwrap_or(rest.len());
+        blocks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    blocks
+}
+
+/// 截取第一个 JSON 对象或数组并解析
+fn parse_json(block: &str) -> Option<Value> {
+    let start = block.find(['{', '['])?;
+    let end = block.rfind(['}', ']'])?;
+    let text = block.get(start..=end)?;
+
+    serde_json::from_str(text)
+        .ok()
+        .or_else(|| serde_json::from_str(&strip_trailing_commas(text)).ok())
+}
+
+/// 去掉对象和数组末尾多余的逗号, 忽略字符串内的逗号
+fn strip_trailing_commas(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut output = String::with_capacity(text.len());
+    let (mut in_string, mut escaped) = (false, false);
+
+    for (index, &c) in chars.iter().enumerate() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ','
+            && chars[index + 1..]
+                .iter()
+                .find(|c| !c.is_whitespace())
+                .is_some_and(|c| matches!(c, '}' | ']'))
+        {
+            continue;
+        }
+        output.push(c);
+    }
+    output
+}
+
+fn to_call(value: &Value) -> Option<FunctionCall> {
+    let object = value.as_object()?;
+
+    // 兼容 OpenAI 格式 {"function": {"name", "arguments"}}
+    if let Some(function) = object.get("function").and_then(Value::as_object) {
+        return to_call(&Value::Object(function.clone()));
+    }
+
+    let name = ["name", "tool", "function", "tool_name"]
+        .iter()
+        .find_map(|key| object.get(*key).and_then(Value::as_str))
+        .filter(|name| !name.trim().is_empty())?;
+
+    let arguments = ["arguments", "parameters", "args", "input"]
+        .iter()
+        .find_map(|key| object.get(*key))
+        .cloned()
+        .unwrap_or(Value::Object(Map::new()));
+
+    // 字符串形式的参数必须是 JSON
+    let arguments = match arguments {
+        Value::String(text) => serde_json::from_str::<Value>(&text).ok()?,
+        value => value,
+    };
+
+    Some(FunctionCall { name: name.trim().to_string(), arguments: arguments.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::tag::{Segment, TagSplitter};
+
+    fn calls(content: &str) -> Vec<(String, String, Value)> {
+        parse(content, "call")
+            .into_iter()
+            .map(|call| {
+                let arguments = serde_json::from_str(&call.function.arguments).unwrap();
+                (call.id, call.function.name, arguments)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        // 缺少括号的 JSON 不是工具调用
+        assert!(
+            calls("<tool_call>\n{\"name\": \"search\", \"arguments\": {\"q\": 1}\n</tool_call>")
+                .is_empty()
+        );
+        // 字符串形式的参数不是 JSON
+        assert!(
+            calls("<tool_call>{\"name\": \"search\", \"arguments\": \"q=1\"}</tool_call>")
+                .is_empty()
+        );
+        // 没有工具名
+        assert!(calls("<tool_call>{\"arguments\": {}}</tool_call>").is_empty());
+
+        // 多余的逗号可以兼容
+        assert_eq!(
+            calls(
+                "<tool_call>{\"name\": \"search\", \"arguments\": {\"q\": \"a,}\",},}</tool_call>"
+            ),
+            vec![("call_0".to_string(), "search".to_string(), json!({"q": "a,}"}))]
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple() {
+        let content = "<tool_call>\n{\"name\": \"search\", \"arguments\": {\"q\": \"rust\"}}\n</tool_call>\n\
+                       <tool_call>\n{\"name\": \"fetch\", \"parameters\": \"{\\\"url\\\": \\\"a\\\"}\"}\n</tool_call>";
+        assert_eq!(
+            calls(content),
+            vec![
+                ("call_0".to_string(), "search".to_string(), json!({"q": "rust"})),
+                ("call_1".to_string(), "fetch".to_string(), json!({"url": "a"})),
+            ]
+        );
+
+        // 数组形式的多个调用
+        let content = "<tool_call>[{\"name\": \"search\"}, {\"function\": {\"name\": \"fetch\", \"arguments\": {}}}]</tool_call>";
+        assert_eq!(
+            calls(content),
+            vec![
+                ("call_0".to_string(), "search".to_string(), json!({})),
+                ("call_1".to_string(), "fetch".to_string(), json!({})),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_surrounding_text() {
+        let content = "我先搜索一下.\n<tool_call>{\"name\": \"search\", \"arguments\": {\"q\": \"rust\"}}</tool_call>\n请稍等";
+        assert_eq!(
+            calls(content),
+            vec![("call_0".to_string(), "search".to_string(), json!({"q": "rust"}))]
+        );
+
+        // 最后一个标签没有结束标签
+        let content = "好的\n<tool_call>{\"name\": \"fetch\", \"arguments\": {\"url\": \"a\"}}";
+        assert_eq!(
+            calls(content),
+            vec![("call_0".to_string(), "fetch".to_string(), json!({"url": "a"}))]
+        );
+
+        // 没有标签的代码块和 JSON 回复不是工具调用, 包括结构化输出中的同名字段
+        assert!(calls("结果是 {\"name\": \"search\"}").is_empty());
+        assert!(calls("{\"name\": \"search\", \"arguments\": {}}").is_empty());
+        assert!(calls("```json\n{\"name\": \"fetch\", \"arguments\": {}}\n```").is_empty());
+    }
+
+    #[test]
+    fn test_parse_split_chunks() {
+        let chunks = [
+            "我先搜",
+            "索.<tool",
+            "_call>{\"name\": \"sea",
+            "rch\", \"arguments\": {\"q\": \"rust\"}}</tool_",
+            "call>",
+        ];
+        let mut splitter = TagSplitter::new(TOOL_CALL_OPEN, TOOL_CALL_CLOSE);
+        let mut text = String::new();
+        for segment in chunks.iter().flat_map(|chunk| splitter.push(chunk)).chain(splitter.finish())
+        {
+            if let Segment::Text(segment) = segment {
+                text.push_str(&segment);
+            }
+        }
+
+        // 工具调用不显示在正文中, 完整的正文可以解析出工具调用
+        assert_eq!(text, "我先搜索.");
+        assert_eq!(
+            calls(&chunks.concat()),
+            vec![("call_0".to_string(), "search".to_string(), json!({"q": "rust"}))]
+        );
+    }
+}
@@ -1,10 +1,10 @@
 import { Agent, AgentCategory, Tool, ToolCategory, McpTool } from './typings';
 import { mockKnowledgeBases } from './mock/knowledgeData';
 import { KnowledgeBase, KnowledgeBaseCategory } from './typings';
-import { ChatSession, ChatMessage } from './typings';
+import { ChatSession, ChatMessage, QueueEntry, UsageQuery, UsageRow } from './typings';
 import { Provider } from './typings';
 import { Settings } from './typings';
-import { ToolMcpIo } from './typings';
+import { ToolMcpIo, ToolMcpSse, ToolMcpHttp, McpOAuth, ToolInvocation } from './typings';
 
 // 导入Tauri API
 import * as tauriApi from './tauriApi';
@@ -166,8 +166,23 @@ export async function deleteToolByCategory(categoryId: number): Promise<boolean>
 }
 
 // 获取mcp-sse所有工具 
-export async function getMcpSseTools(url: string): Promise<McpTool[]> {
-  return tauriApi.fetch_local('tool.mcp.sse.tools', url) as Promise<McpTool[]>;
+export async function getMcpSseTools(sse: Omit<ToolMcpSse, 'type'>): Promise<McpTool[]> {
+  return tauriApi.fetch_local('tool.mcp.sse.tools', sse) as Promise<McpTool[]>;
+}
+
+// 获取mcp-http所有工具, 服务端不支持时回退到 SSE
+export async function getMcpHttpTools(http: Omit<ToolMcpHttp, 'type'>): Promise<McpTool[]> {
+  return tauriApi.fetch_local('tool.mcp.http.tools', http) as Promise<McpTool[]>;
+}
+
+// 远程 MCP 服务的 OAuth 授权, 在浏览器中完成后保存令牌, 返回过期时间
+export async function authorizeMcpOAuth(url: string, oauth?: McpOAuth): Promise<{ expiresAt?: number }> {
+  return tauriApi.fetch_local('tool.mcp.oauth.authorize', { url, oauth }) as Promise<{ expiresAt?: number }>;
+}
+
+// 删除远程 MCP 服务保存的 OAuth 令牌
+export async function revokeMcpOAuth(url: string): Promise<boolean> {
+  return tauriApi.fetch_local('tool.mcp.oauth.revoke', url) as Promise<boolean>;
 }
 
 // 获取mcp-io所有工具 
@@ -175,9 +190,9 @@ export async function getMcpIoTools(io: ToolMcpIo): Promise<McpTool[]> {
   return tauriApi.fetch_local('tool.mcp.io.tools', io) as Promise<McpTool[]>;
 }
 
-// 测试工具
-export async function testTool(tool: Tool): Promise<string> {
-  return tauriApi.fetch_local('tool.test', tool) as Promise<string>;
+// 测试工具, 传入 id 执行已保存的工具, 传入 tool 执行编辑中的工具, 未传入的参数使用测试值
+export async function invokeTool(options: { id?: number; tool?: Tool; name?: string; arguments?: Record<string, any> }): Promise<ToolInvocation> {
+  return tauriApi.fetch_local('tool.invoke', options) as Promise<ToolInvocation>;
 }
 
 // 模拟固定的知识库类别
@@ -324,6 +339,17 @@ export async function deleteSession(id: number): Promise<boolean> {
   return tauriApi.fetch_local('chat.session.delete', id) as Promise<boolean>;
 }
 
+// 重新生成会话标题, 不指定会话时批量处理全部会话
+export async function retitleSession(sessionId?: number): Promise<ChatSession | ChatSession[]> {
+  const data = sessionId === undefined ? { all: true } : { session: sessionId };
+  return tauriApi.fetch_local('chat.session.retitle', data) as Promise<ChatSession | ChatSession[]>;
+}
+
+// 复制会话中到指定消息为止的对话到新会话, 可以指定新的智能体
+export async function forkSession(sessionId: number, messageId: number, agentId?: number): Promise<ChatSession> {
+  return tauriApi.fetch_local('chat.session.fork', { session: sessionId, message: messageId, agentId }) as Promise<ChatSession>;
+}
+
 // 获取所有会话session
 export async function getAllSessions(): Promise<ChatSession[]> {
   return tauriApi.fetch_local('chat.session.list', null) as Promise<ChatSession[]>;
@@ -359,6 +385,21 @@ export async function getMessagesBySession(sessionId: number, limit: number, mes
   return tauriApi.fetch_local('chat.message.list.by.session', { session: sessionId, message: messageId, limit: limit}) as Promise<ChatMessage[]>;
 }
 
+// 获取会话当前分支的消息, 指定消息时返回从第一条消息到该消息的路径
+export async function getMessagePath(sessionId: number, messageId?: number): Promise<ChatMessage[]> {
+  return tauriApi.fetch_local('chat.message.path', { session: sessionId, message: messageId }) as Promise<ChatMessage[]>;
+}
+
+// 获取与消息同一上级的分支, 编辑过去的问题时产生新的分支
+export async function getMessageBranches(messageId: number): Promise<ChatMessage[]> {
+  return tauriApi.fetch_local('chat.message.branches', messageId) as Promise<ChatMessage[]>;
+}
+
+// 切换到消息所在的分支, 返回新的当前分支
+export async function switchMessageBranch(messageId: number): Promise<ChatMessage[]> {
+  return tauriApi.fetch_local('chat.message.switch', messageId) as Promise<ChatMessage[]>;
+}
+
 // 通过会话id删除聊天消息
 export async function deleteMessagesBySession(sessionId: number): Promise<boolean> {
   return tauriApi.fetch_local('chat.message.delete.by.session', sessionId) as Promise<boolean>;
@@ -374,10 +415,25 @@ export async function chatEvent(message: ChatMessage, search: boolean, time: boo
   return tauriApi.event_local(message, time, search, stream, onData);
 }
 
+// 继续生成被截断的回复
+export async function chatEventContinue(messageId: number, search: boolean, time: boolean, stream: boolean, onData: (chunk: tauriApi.MessageEvent) => void) {
+  return tauriApi.event_continue_local(messageId, time, search, stream, onData);
+}
+
 export async function chatEventExit(messageId: number) {
   return tauriApi.event_exit_local(messageId);
 }
 
+// 获取正在生成和排队中的消息, 不指定会话时返回所有会话
+export async function listMessageQueue(sessionId?: number): Promise<QueueEntry[]> {
+  return tauriApi.fetch_local('chat.queue.list', { session: sessionId }) as Promise<QueueEntry[]>;
+}
+
+// 取消排队中的消息
+export async function cancelQueuedMessage(messageId: number): Promise<boolean> {
+  return tauriApi.fetch_local('chat.queue.cancel', messageId) as Promise<boolean>;
+}
+
 export async function convertFile(name: string, data: string): Promise<string> {
   return tauriApi.fetch_local('file.convert', { name, data }) as Promise<string>;
 }
@@ -411,3 +467,9 @@ export const openInUrl = tauriApi.openInUrl;
 export const isTauriAvailable = tauriApi.isTauriAvailable;
 export const safeTauriCall = tauriApi.safeTauriCall;
 export type MessageEvent = tauriApi.MessageEvent;
+
+// 用量统计
+export async function getUsageStats(query: UsageQuery): Promise<UsageRow[]> {
+  const utcOffset = query.utcOffset ?? -new Date().getTimezoneOffset();
+  return tauriApi.fetch_local('stats.usage', { ...query, utcOffset }) as Promise<UsageRow[]>;
+}
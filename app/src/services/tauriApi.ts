@@ -1,6 +1,6 @@
 import { invoke, Channel } from "@tauri-apps/api/core";
 import { openPath, openUrl } from '@tauri-apps/plugin-opener';
-import { ChatMessage } from "./typings";
+import { ChatMessage, ChatSession, CompareTarget, FinishReason, StructuredOutput } from "./typings";
 
 interface Response {
   status: string;
@@ -62,6 +62,31 @@ export type  MessageEvent =
       content: string;
     };
   }
+| {
+    // 流式输出中开始生成工具调用
+    event: 'toolCallStarted';
+    data: {
+      id: string;
+      name: string;
+    };
+  }
+| {
+    // 工具调用参数片段
+    event: 'toolCallArguments';
+    data: {
+      id: string;
+      delta: string;
+    };
+  }
+| {
+    // 工具开始执行
+    event: 'toolRunning';
+    data: {
+      id: string;
+      name: string;
+      arguments: string;
+    };
+  }
 | {
     event: 'tool';
     data: {
@@ -69,6 +94,72 @@ export type  MessageEvent =
       name: string;
       arguments: string;
       result: string;
+      cost: number;
+    };
+  }
+| {
+    event: 'toolError';
+    data: {
+      id: string;
+      name: string;
+      arguments: string;
+      error: string;
+      cost: number;
+    };
+  }
+| {
+    event: 'toolLimit';
+    data: {
+      // maxRounds: 超过最大轮数, repeatedCall: 重复调用
+      reason: 'maxRounds' | 'repeatedCall';
+      rounds: number;
+      message: string;
+    };
+  }
+| {
+    event: 'contentReset';
+    data: {
+      reason: string;
+    };
+  }
+| {
+    event: 'structured';
+    data: {
+      output: StructuredOutput;
+    };
+  }
+| {
+    // 会话中已有回复在生成, 排队等待, position 为前面的消息数
+    event: 'queued';
+    data: {
+      position: number;
+    };
+  }
+| {
+    // 重新生成的回复, 替换已有的消息
+    event: 'retryAssistantMessage';
+    data: {
+      message: ChatMessage;
+    };
+  }
+| {
+    // 继续生成被截断的回复, 新内容追加到已有的消息
+    event: 'continueAssistantMessage';
+    data: {
+      message: ChatMessage;
+    };
+  }
+| {
+    // 回复因长度或内容过滤被截断
+    event: 'truncated';
+    data: {
+      reason: FinishReason;
+    };
+  }
+| {
+    event: 'sessionUpdated';
+    data: {
+      session: ChatSession;
     };
   }
 | {
@@ -78,6 +169,16 @@ export type  MessageEvent =
       promptTokens: number;
       completionTokens: number;
       totalTokens: number;
+      // 按模型价格计算的费用
+      fee?: number;
+      // 首个token和思考后首个正文的耗时(毫秒)
+      firstToken?: number;
+      firstContent?: number;
+      // 每秒输出的tokens
+      tokensPerSecond?: number;
+      // success, failed, timeout, cancelled
+      status: string;
+      error?: string;
     };
 };
     
@@ -111,6 +212,67 @@ export async function event_local(message: ChatMessage, time: boolean, search: b
   }
 }
 
+/**
+ * 同一个问题同时发给多个智能体或模型
+ * @param targets 对比的智能体或模型
+ * @param onReply 每条回复的事件, index 对应 targets 的下标
+ */
+export async function event_compare_local(message: ChatMessage, targets: CompareTarget[], time: boolean, search: boolean, stream: boolean, onData: (event: MessageEvent) => void, onReply: (index: number, event: MessageEvent) => void): Promise<Object> {
+  try {
+    console.log('event_compare_local:', message, targets);
+
+    const onEvent = new Channel<MessageEvent>();
+    onEvent.onmessage = (message) => {
+      onData(message);
+    };
+
+    const onReplies = targets.map((_, index) => {
+      const channel = new Channel<MessageEvent>();
+      channel.onmessage = (message) => {
+        onReply(index, message);
+      };
+      return channel;
+    });
+
+    let result = await invoke('event_compare', { message, targets, search, time, stream, onEvent, onReplies }) as Response;
+    console.log('event_compare_local result:', result);
+
+    if (result.status === "error") {
+      throw 'error:' + result.error;
+    }
+    return result.data === undefined ? true : result.data as Object;
+  } catch (error) {
+    console.error('Failed to call event_compare_local function:', error);
+    throw error;
+  }
+}
+
+/**
+ * 继续生成被截断的助手回复
+ * @param messageId 助手消息ID
+ */
+export async function event_continue_local(messageId: number, time: boolean, search: boolean, stream: boolean, onData: (event: MessageEvent) => void): Promise<Object> {
+  try {
+    console.log('event_continue_local:', messageId);
+
+    const onEvent = new Channel<MessageEvent>();
+    onEvent.onmessage = (message) => {
+      onData(message);
+    };
+
+    let result = await invoke('event_continue', { message: messageId, search, time, stream, onEvent }) as Response;
+    console.log('event_continue_local result:', result);
+
+    if (result.status === "error") {
+      throw 'error:' + result.error;
+    }
+    return result.data === undefined ? true : result.data as Object;
+  } catch (error) {
+    console.error('Failed to call event_continue_local function:', error);
+    throw error;
+  }
+}
+
 export async function event_exit_local(messageId: number): Promise<Object> {
   try {
     console.log('event_exit_local:', messageId);
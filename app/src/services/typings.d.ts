@@ -6,9 +6,32 @@ const ProviderApiCategory = ['OpenAI', "Gemini", "Anthropic", "Xai", "Ollama", "
 */
 
 // 模型名, 标签包含 [推理,向量,图片]
+// 模型价格, 每百万tokens
+export interface ModelPrice {
+  input: number;
+  output: number;
+  cachedInput?: number;
+}
+
+// 正文中包裹思考内容的标签, 例如 <think> 和 </think>
+export interface ThinkTags {
+  open: string;
+  close: string;
+}
+
 export interface Model {
   name: string;
   tags: string[];
+  price?: ModelPrice;
+  // 思考标签, 覆盖模型提供商的设置
+  thinkTags?: ThinkTags;
+}
+
+// 模拟模型提供商的配置, record 转发到 url 并录制, replay 从录制文件回放
+export interface MockConfig {
+  mode: 'record' | 'replay';
+  // 录制文件目录
+  dir: string;
 }
 
 // 模型提供商
@@ -25,6 +48,17 @@ export interface Provider {
   apiKey?: string;
 
   models?: Model[];
+
+  // 连接超时, 秒, 0 不限制
+  connectTimeout?: number;
+  // 等待首个token的超时, 秒, 0 不限制
+  firstTokenTimeout?: number;
+  // 流式输出中两次数据之间的超时, 秒, 0 不限制
+  idleTimeout?: number;
+  // 思考标签, 设置时从正文中拆分思考内容
+  thinkTags?: ThinkTags;
+  // 接口类型为 mock 时的录制和回放配置
+  mock?: MockConfig;
 }
 
 // 模型
@@ -39,7 +73,7 @@ export interface ProviderModel {
 export interface ModelParam {
   // 参数名称
   name: string;
-  // 参数类型 string, number, boolean, object
+  // 参数类型 string, number, integer, boolean, object, array
   type: string;
   // 参数值
   value: string;
@@ -62,9 +96,15 @@ export interface Agent {
   // 模型通过providerId和name获取
   model?: ProviderModel;
 
-  // 提示词
+  // 提示词, promptTemplate 开启时支持模板语法, 内置变量: date, time, datetime, weekday, timezone, topic, agent, user
   prompt: string;
 
+  // 提示词是否按模板渲染, 默认否, 原样使用
+  promptTemplate?: boolean;
+
+  // 提示词自定义变量
+  variables?: PromptVariable[];
+
   // 模型生成文本的随机程度。值越大，回复内容越赋有多样性、创造性、随机性；设为 0 根据事实回答。日常聊天建议设置为 0.7
   // 默认1, 0 - 2
   temperature: number;
@@ -88,6 +128,24 @@ export interface Agent {
   // 工具集合
   tools?: number[];
 
+  // 单次回复最多的工具调用轮数, 0 使用默认值(10)
+  maxToolRounds?: number;
+
+  // 结构化输出的 JSON Schema
+  responseSchema?: Object;
+
+  // 思考强度, 不设置时使用模型默认值
+  reasoningEffort?: 'low' | 'medium' | 'high';
+
+  // 思考预算tokens, 0 不设置, 仅部分服务商支持
+  thinkingBudget?: number;
+
+  // 历史消息中思考内容的处理方式: 丢弃, 保留, 摘要
+  reasoningHistory?: 'drop' | 'include' | 'summarize';
+
+  // 回复达到最大长度被截断时自动继续的最多次数, 0 不自动继续
+  autoContinue?: number;
+
   // 自定义问题
   // 1. 自定义问题, 例如: 你是谁? 你能做什么?
   customQuestions?: string[];
@@ -124,6 +182,12 @@ export interface Param {
   required: boolean;
   // 测试值
   testValue?: string;
+  // 可选值, 设置时只能取其中之一
+  enum?: (string | number | boolean)[];
+  // 数组元素的定义, type 为 array 时使用
+  items?: Param;
+  // 对象的属性, type 为 object 时使用
+  properties?: Param[];
 }
 
 // tool
@@ -137,7 +201,14 @@ export interface McpTool {
 export interface ToolJavaScript {
   type: 'javaScript';
   param?: Param[];
+  // 代码中定义 async function toolFunction(params), 返回值作为工具结果
   code: string;
+  // 执行超时, 秒, 0 使用默认值 30 秒
+  timeout?: number;
+  // 内存上限, MB, 0 使用默认值 128 MB
+  memoryLimit?: number;
+  // fetch 允许访问的域名, 包括子域名, 为空时禁止访问网络, '*' 允许所有
+  allowHosts?: string[];
 }
 
 // mcp-io
@@ -150,10 +221,35 @@ export interface ToolMcpIo {
   };
 }
 
+// 远程 MCP 服务的 OAuth 客户端配置, 不设置 clientId 时动态注册客户端
+export interface McpOAuth {
+  clientId?: string;
+  clientSecret?: string;
+  // 申请的权限, 空格分隔
+  scope?: string;
+  // 接收授权回调的本地端口, 0 随机端口, 预先注册的客户端需要固定端口
+  redirectPort?: number;
+}
+
+// 远程 MCP 服务的认证方式, oauth 需要先调用 authorizeMcpOAuth 完成授权
+export type McpAuth = { type: 'bearer'; token: string } | ({ type: 'oauth' } & McpOAuth);
+
 // mcp-sse
 export interface ToolMcpSse {
   type: 'mcpSse';
   url: string;
+  // 自定义请求头
+  headers?: Record<string, string>;
+  auth?: McpAuth;
+}
+
+// mcp-http, Streamable HTTP, 服务端不支持时回退到 SSE
+export interface ToolMcpHttp {
+  type: 'mcpHttp';
+  url: string;
+  // 自定义请求头
+  headers?: Record<string, string>;
+  auth?: McpAuth;
 }
 
 // 工具
@@ -168,12 +264,26 @@ export interface Tool {
   description?: string;
 
   // 工具类型
-  data: ToolJavaScript | ToolMcpIo | ToolMcpSse;
+  data: ToolJavaScript | ToolMcpIo | ToolMcpSse | ToolMcpHttp;
 
   createdAt: i64;
   updatedAt?: i64;
 }
 
+// 单独执行工具函数的结果
+export interface ToolInvocation {
+  // 执行的函数名称
+  name: string;
+  // 实际使用的参数
+  arguments: Object;
+  result?: any;
+  // JS 工具的控制台输出, MCP 子进程的 stderr
+  logs: string[];
+  // 耗时, 毫秒
+  duration: number;
+  error?: string;
+}
+
 // 知识库类型, 目前就固定的2个类型
 // 1. 通用知识库
 // 2. 站点同步
@@ -217,6 +327,20 @@ export interface Attachment {
   data: string;
 }
 
+// 提示词自定义变量
+export interface PromptVariable {
+  name: string;
+  description?: string;
+  default?: string;
+}
+
+// 结构化输出的校验结果
+export interface StructuredOutput {
+  valid: boolean;
+  value?: any;
+  error?: string;
+}
+
 // 工具执行结果
 export interface ToolResult {
   // 工具id
@@ -227,6 +351,12 @@ export interface ToolResult {
   arguments: string;
   // json格式
   result: string;
+  // 执行状态
+  status?: 'success' | 'failed';
+  // 错误信息
+  error?: string;
+  // 耗时, 毫秒
+  cost?: number;
 }
 
 // 聊天信息
@@ -249,6 +379,9 @@ export interface ChatMessage {
 
   status: string;
 
+  // 失败原因
+  error?: string;
+
   // 反馈 默认 = 0, 赞 = 1, 踩 = 2
   feedback?: number;
 
@@ -259,11 +392,51 @@ export interface ChatMessage {
   promptTokens?: number;
   completionTokens?: number;
   totalTokens?: number;
+
+  // 上级消息ID, 助手回复对应用户消息, 用户消息对应上一条回复, 第一条消息为 0
+  // 发送新消息时指定上级, 即编辑过去的问题, 会产生新的分支
+  parentId?: number;
+
+  // 分支会话中复制的消息对应的原消息ID
+  forkedFrom?: number;
+
+  // 生成回复的智能体和模型
+  agentId?: number;
+  model?: ProviderModel;
+
+  // 多条对比回复中是否选中用于继续对话
+  selected?: boolean;
+
+  // 结构化输出的校验结果
+  structured?: StructuredOutput;
+
+  // 费用, 按模型价格计算
+  fee?: number;
+
+  // 首个token和思考后首个正文的耗时(毫秒), 每秒输出tokens
+  firstToken?: number;
+  firstContent?: number;
+  tokensPerSecond?: number;
+
+  // 思考内容的摘要
+  reasoningSummary?: string;
+
+  // 回复被截断的原因, 正常结束时为空
+  finishReason?: FinishReason;
   
   createdAt: number;
   updatedAt?: number;
 }
 
+// 回复被截断的原因: 达到最大长度, 被内容过滤
+export type FinishReason = 'length' | 'content_filter';
+
+// 对比回复的目标, 都不指定时使用会话的智能体
+export interface CompareTarget {
+  agentId?: number;
+  model?: ProviderModel;
+}
+
 // 会话输入状态
 export interface ChatInput {
   time: boolean;
@@ -271,6 +444,15 @@ export interface ChatInput {
   stream: boolean;
 }
 
+// 分支会话的来源
+export interface SessionFork {
+  // 原会话id
+  sessionId: number;
+
+  // 复制到的最后一条消息id
+  messageId: number;
+}
+
 // 聊天会话信息
 export interface ChatSession {
   // 会话id
@@ -282,7 +464,19 @@ export interface ChatSession {
   // 会话主题
   topic: string;
 
+  // 主题是否被用户修改过, 修改过的会话不再自动生成标题
+  topicEdited?: boolean;
+
   input: ChatInput;
+
+  // 提示词自定义变量的值
+  variables?: Record<string, string>;
+
+  // 当前分支末端的消息id, 不设置时为最后一条消息
+  activeLeaf?: number;
+
+  // 分支会话的来源
+  forkedFrom?: SessionFork;
   
   createdAt: number;
   updatedAt?: number;
@@ -314,6 +508,21 @@ export interface Search {
 export interface Settings {
   search: Search;
   transcriptions?: ProviderModel;
+  // 生成会话标题和思考摘要的模型
+  titleModel?: ProviderModel;
+  // 会话中已有回复在生成时, 新发送的消息排队等待或者直接拒绝
+  queuePolicy?: 'wait' | 'reject';
+}
+
+// 会话中正在生成或排队等待的消息
+export interface QueueEntry {
+  sessionId: number;
+  // 用户消息ID, 可以通过 chatEventExit 取消
+  messageId: number;
+  // 消息内容预览
+  content: string;
+  running: boolean;
+  createdAt: number;
 }
 
 // API 响应格式
@@ -321,4 +530,35 @@ export interface ApiResponse<T = any> {
   code: number;
   message: string;
   data?: T;
-}
\ No newline at end of file
+}
+
+// 用量统计查询条件, 时间单位毫秒, 按本地时区的天对齐
+export interface UsageQuery {
+  start?: number;
+  end?: number;
+  provider?: number;
+  model?: string;
+  agent?: number;
+  period?: 'day' | 'week';
+  // 本地时区相对 UTC 的偏移, 单位分钟, 不传时使用当前时区
+  utcOffset?: number;
+}
+
+// 一组用量统计, period 为所在天或周的开始时间, 即本地时区的零点
+export interface UsageRow {
+  period: number;
+  provider: number;
+  model: string;
+  agent: number;
+  replies: number;
+  errors: number;
+  promptTokens: number;
+  completionTokens: number;
+  totalTokens: number;
+  fee: number;
+  cost: number;
+  toolCalls: number;
+  toolErrors: number;
+  averageCost: number;
+  errorRate: number;
+}
@@ -101,6 +101,19 @@ export const useChatSessionStore = defineStore('chatSession', () => {
     }
   }
 
+  /**
+   * 用后端已经保存的会话替换本地状态, 例如自动生成标题后
+   */
+  function refreshSession(session: ChatSession) {
+    const sessionIndex = sessions.value.findIndex(s => s.id === session.id);
+    if (sessionIndex === -1) {
+      sessions.value.push(session);
+    } else {
+      sessions.value[sessionIndex] = session;
+    }
+    sortSessions();
+  }
+
   /**
    * 删除会话
    */
@@ -184,6 +197,7 @@ export const useChatSessionStore = defineStore('chatSession', () => {
     getSessionsByAgentId,
     createNewSession,
     updateSession,
+    refreshSession,
     deleteSession,
     updateSessionTopic  // 导出新函数
   };
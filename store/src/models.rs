@@ -4,6 +4,7 @@ mod knowledge;
 mod provider;
 mod settings;
 mod tool;
+mod usage;
 
 pub use agent::*;
 pub use chat::*;
@@ -11,3 +12,4 @@ pub use knowledge::*;
 pub use provider::*;
 pub use settings::*;
 pub use tool::*;
+pub use usage::*;
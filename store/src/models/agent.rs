@@ -35,6 +35,39 @@ pub struct ModelParam {
     pub value: String,
 }
 
+/// 思考强度
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+/// 历史消息中思考内容的处理方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningHistory {
+    /// 不发送
+    #[default]
+    Drop,
+    /// 完整发送
+    Include,
+    /// 发送摘要
+    Summarize,
+}
+
+/// 提示词自定义变量, 每个会话可以设置不同的值
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptVariable {
+    /// 变量名, 在提示词中通过 {{ name }} 引用
+    pub name: String,
+    /// 说明
+    pub description: Option<String>,
+    /// 会话没有设置时使用的默认值
+    pub default: Option<String>,
+}
+
 /// 智能体
 #[derive(Debug, Serialize, Deserialize, Collection, Clone)]
 #[collection(name = "agents")]
@@ -60,9 +93,17 @@ pub struct Agent {
     /// 使用的模型
     pub model: Option<ProviderModel>,
 
-    /// 提示词
+    /// 提示词, prompt_template 开启时支持模板语法和变量
     pub prompt: String,
 
+    /// 提示词自定义变量
+    #[serde(default)]
+    pub variables: Vec<PromptVariable>,
+
+    /// 提示词是否按模板渲染, 旧的提示词可能包含 `{{` 等字符, 默认原样使用
+    #[serde(default, rename = "promptTemplate")]
+    pub prompt_template: bool,
+
     /// 生成文本的随机程度(0-2)
     pub temperature: f64,
 
@@ -88,6 +129,30 @@ pub struct Agent {
     /// 工具集合
     pub tools: Option<Vec<u64>>,
 
+    /// 单次回复最多的工具调用轮数, 0表示使用默认值
+    #[serde(default, rename = "maxToolRounds")]
+    pub max_tool_rounds: u32,
+
+    /// 结构化输出的 JSON Schema, 设置后回复必须是符合该结构的JSON
+    #[serde(default, rename = "responseSchema")]
+    pub response_schema: Option<serde_json::Value>,
+
+    /// 思考强度, 不设置时使用模型默认值
+    #[serde(default, rename = "reasoningEffort")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+
+    /// 思考的最多tokens, 0表示不限制
+    #[serde(default, rename = "thinkingBudget")]
+    pub thinking_budget: u32,
+
+    /// 历史消息中思考内容的处理方式
+    #[serde(default, rename = "reasoningHistory")]
+    pub reasoning_history: ReasoningHistory,
+
+    /// 回复达到最大长度被截断时自动继续的最多次数, 0表示不自动继续
+    #[serde(default, rename = "autoContinue")]
+    pub auto_continue: u32,
+
     /// 自定义问题
     /// 例如: ["你是谁", "你能做什么"]
     #[serde(rename = "customQuestions")]
@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+
 use bonsaidb::core::schema::Collection;
 use serde::{Deserialize, Serialize};
 
+use crate::models::{ProviderModel, UsageBySlot};
+
 /// 角色类型
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
@@ -24,6 +28,20 @@ pub enum MessageStatus {
     Timeout,
     Success,
     Error,
+    Cancelled,
+}
+
+/// 会话第一条消息的上级消息ID
+pub const ROOT_MESSAGE_ID: u64 = 0;
+
+/// 回复被截断的原因
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// 达到最大输出长度
+    Length,
+    /// 被内容过滤
+    ContentFilter,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,6 +52,26 @@ pub struct Attachment {
     pub data: String,
 }
 
+/// 工具执行状态
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolStatus {
+    #[default]
+    Success,
+    Failed,
+}
+
+/// 结构化输出的校验结果
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StructuredOutput {
+    /// 是否通过 JSON Schema 校验
+    pub valid: bool,
+    /// 解析后的JSON
+    pub value: Option<serde_json::Value>,
+    /// 解析或校验失败的原因
+    pub error: Option<String>,
+}
+
 /// 工具结果
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ToolResult {
@@ -45,11 +83,18 @@ pub struct ToolResult {
     pub arguments: String,
     /// 工具结果
     pub result: String,
+    /// 执行状态
+    #[serde(default)]
+    pub status: ToolStatus,
+    /// 错误信息
+    pub error: Option<String>,
+    /// 耗时, 单位毫秒
+    pub cost: Option<i64>,
 }
 
 /// 聊天消息
 #[derive(Debug, Serialize, Deserialize, Collection, Clone)]
-#[collection(name = "chat_messages", primary_key = u64)]
+#[collection(name = "chat_messages", primary_key = u64, views = [UsageBySlot])]
 pub struct ChatMessage {
     /// 消息ID
     #[natural_id]
@@ -70,6 +115,8 @@ pub struct ChatMessage {
     pub attachments: Option<Vec<Attachment>>,
     /// 状态
     pub status: MessageStatus,
+    /// 失败原因
+    pub error: Option<String>,
 
     /// 反馈 默认 = 0, 赞 = 1, 踩 = 2
     #[serde(default)]
@@ -90,6 +137,51 @@ pub struct ChatMessage {
     #[serde(rename = "totalTokens")]
     pub total_tokens: Option<u32>,
 
+    /// 上级消息ID, 助手回复对应的用户消息, 用户消息对应上一条助手回复,
+    /// 会话的第一条消息为 [`ROOT_MESSAGE_ID`], 旧数据没有上级时按ID顺序取上一条
+    #[serde(rename = "parentId")]
+    pub parent_id: Option<u64>,
+
+    /// 生成回复的智能体
+    #[serde(rename = "agentId")]
+    pub agent_id: Option<u64>,
+
+    /// 生成回复的模型
+    pub model: Option<ProviderModel>,
+
+    /// 同一问题的多条对比回复中, 是否选中用于继续对话
+    pub selected: Option<bool>,
+
+    /// 结构化输出的校验结果
+    pub structured: Option<StructuredOutput>,
+
+    /// 费用, 按模型价格计算, 包括工具调用的各轮请求
+    pub fee: Option<f64>,
+
+    /// 首个token的耗时, 单位毫秒
+    #[serde(rename = "firstToken")]
+    pub first_token: Option<i64>,
+
+    /// 思考结束后首个正文内容的耗时, 单位毫秒
+    #[serde(rename = "firstContent")]
+    pub first_content: Option<i64>,
+
+    /// 每秒输出的tokens
+    #[serde(rename = "tokensPerSecond")]
+    pub tokens_per_second: Option<f64>,
+
+    /// 思考内容的摘要, 历史消息按摘要发送时生成
+    #[serde(rename = "reasoningSummary")]
+    pub reasoning_summary: Option<String>,
+
+    /// 回复被截断的原因, 正常结束时为空
+    #[serde(default, rename = "finishReason")]
+    pub finish_reason: Option<FinishReason>,
+
+    /// 分支会话中复制的消息对应的原消息ID
+    #[serde(default, rename = "forkedFrom")]
+    pub forked_from: Option<u64>,
+
     /// 创建时间
     #[serde(rename = "createdAt")]
     pub created_at: i64,
@@ -106,11 +198,24 @@ impl ChatMessage {
             tools: None,
             attachments,
             status: MessageStatus::Sending,
+            error: None,
             feedback: 0,
             cost: None,
             prompt_tokens: None,
             completion_tokens: None,
             total_tokens: None,
+            parent_id: None,
+            agent_id: None,
+            model: None,
+            selected: None,
+            structured: None,
+            fee: None,
+            first_token: None,
+            first_content: None,
+            tokens_per_second: None,
+            reasoning_summary: None,
+            finish_reason: None,
+            forked_from: None,
             created_at: 0,
         }
     }
@@ -125,11 +230,24 @@ impl ChatMessage {
             tools: None,
             attachments: None,
             status: MessageStatus::Sending,
+            error: None,
             feedback: 0,
             cost: None,
             prompt_tokens: None,
             completion_tokens: None,
             total_tokens: None,
+            parent_id: None,
+            agent_id: None,
+            model: None,
+            selected: None,
+            structured: None,
+            fee: None,
+            first_token: None,
+            first_content: None,
+            tokens_per_second: None,
+            reasoning_summary: None,
+            finish_reason: None,
+            forked_from: None,
             created_at: 0,
         }
     }
@@ -148,6 +266,17 @@ impl Default for ChatInput {
     }
 }
 
+/// 分支会话的来源, 复制了原会话中到指定消息为止的消息
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionFork {
+    /// 原会话ID
+    #[serde(rename = "sessionId")]
+    pub session_id: u64,
+    /// 复制到的最后一条消息ID
+    #[serde(rename = "messageId")]
+    pub message_id: u64,
+}
+
 /// 聊天会话
 #[derive(Debug, Serialize, Deserialize, Collection, Clone)]
 #[collection(name = "chat_sessions", primary_key = u64)]
@@ -160,9 +289,21 @@ pub struct ChatSession {
     pub agent_id: u64,
     /// 会话主题
     pub topic: String,
+    /// 主题是否被用户修改过, 修改过的会话不再自动生成标题
+    #[serde(default, rename = "topicEdited")]
+    pub topic_edited: bool,
     /// 输入状态
     #[serde(default)]
     pub input: ChatInput,
+    /// 提示词自定义变量的值
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// 当前分支末端的消息ID, 不设置时为最后一条消息
+    #[serde(default, rename = "activeLeaf")]
+    pub active_leaf: Option<u64>,
+    /// 分支会话的来源
+    #[serde(default, rename = "forkedFrom")]
+    pub forked_from: Option<SessionFork>,
     /// 创建时间
     #[serde(rename = "createdAt")]
     pub created_at: i64,
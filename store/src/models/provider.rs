@@ -1,11 +1,88 @@
 use bonsaidb::core::schema::Collection;
 use serde::{Deserialize, Serialize};
 
+/// 模型标签: 支持 response_format 的 JSON Schema 结构化输出
+pub const MODEL_TAG_STRUCTURED_OUTPUT: &str = "结构化输出";
+
+/// 模型标签: 不支持原生函数调用, 通过系统提示词描述工具并从回复中解析调用
+pub const MODEL_TAG_PROMPT_TOOLS: &str = "提示词工具";
+
+/// 模型价格, 单位为每百万tokens的价格
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ModelPrice {
+    /// 输入价格
+    pub input: f64,
+    /// 输出价格
+    pub output: f64,
+    /// 命中缓存的输入价格, 不设置时按输入价格计算
+    #[serde(rename = "cachedInput")]
+    pub cached_input: Option<f64>,
+}
+
+impl ModelPrice {
+    /// 计算费用, prompt_tokens 包含命中缓存的 cached_tokens
+    pub fn fee(&self, prompt_tokens: u32, cached_tokens: u32, completion_tokens: u32) -> f64 {
+        let cached_tokens = cached_tokens.min(prompt_tokens);
+        let input = (prompt_tokens - cached_tokens) as f64 * self.input;
+        let cached = cached_tokens as f64 * self.cached_input.unwrap_or(self.input);
+        let output = completion_tokens as f64 * self.output;
+        (input + cached + output) / 1_000_000.0
+    }
+}
+
+/// 正文中包裹思考内容的标签, 用于把 `<think>...</think>` 拆分为思考内容
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ThinkTags {
+    /// 开始标签
+    pub open: String,
+    /// 结束标签
+    pub close: String,
+}
+
+impl Default for ThinkTags {
+    fn default() -> Self {
+        Self { open: "<think>".to_string(), close: "</think>".to_string() }
+    }
+}
+
+/// 模拟模型提供商的模式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MockMode {
+    /// 转发到真实的模型提供商, 录制请求和响应
+    Record,
+    /// 从录制文件回放响应, 不需要网络
+    #[default]
+    Replay,
+}
+
+/// 模拟模型提供商的配置, 接口类型为 mock 时使用
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct MockConfig {
+    #[serde(default)]
+    pub mode: MockMode,
+    /// 录制文件目录
+    pub dir: String,
+}
+
 /// 模型名及其标签
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Model {
     pub name: String,
     pub tags: Vec<String>,
+    /// 价格, 不设置时不计算费用
+    #[serde(default)]
+    pub price: Option<ModelPrice>,
+    /// 思考标签, 设置时覆盖模型提供商的设置
+    #[serde(default, rename = "thinkTags")]
+    pub think_tags: Option<ThinkTags>,
+}
+
+impl Model {
+    /// 是否有指定标签
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
 }
 
 /// 模型提供商
@@ -27,6 +104,21 @@ pub struct Provider {
     pub api_key: Option<String>,
     /// 支持的模型
     pub models: Option<Vec<Model>>,
+    /// 连接超时, 单位秒, 0表示不限制
+    #[serde(default, rename = "connectTimeout")]
+    pub connect_timeout: u64,
+    /// 等待首个token的超时, 单位秒, 0表示不限制
+    #[serde(default, rename = "firstTokenTimeout")]
+    pub first_token_timeout: u64,
+    /// 流式输出中两次数据之间的超时, 单位秒, 0表示不限制
+    #[serde(default, rename = "idleTimeout")]
+    pub idle_timeout: u64,
+    /// 思考标签, 设置时从正文中拆分思考内容
+    #[serde(default, rename = "thinkTags")]
+    pub think_tags: Option<ThinkTags>,
+    /// 模拟模型提供商的配置, 录制时转发到 url
+    #[serde(default)]
+    pub mock: Option<MockConfig>,
     /// 创建时间
     #[serde(rename = "createdAt")]
     pub created_at: Option<i64>,
@@ -34,3 +126,15 @@ pub struct Provider {
     #[serde(rename = "updatedAt")]
     pub updated_at: Option<i64>,
 }
+
+impl Provider {
+    /// 按名称查找支持的模型
+    pub fn model(&self, name: &str) -> Option<&Model> {
+        self.models.as_ref()?.iter().find(|model| model.name == name)
+    }
+
+    /// 模型使用的思考标签, 模型未设置时使用提供商的设置
+    pub fn think_tags(&self, name: &str) -> Option<&ThinkTags> {
+        self.model(name).and_then(|model| model.think_tags.as_ref()).or(self.think_tags.as_ref())
+    }
+}
@@ -30,8 +30,25 @@ pub struct Search {
 
 // 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
 
+/// 会话中已有回复在生成时, 新发送消息的处理方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuePolicy {
+    /// 排队等待前面的回复完成
+    #[default]
+    Wait,
+    /// 直接拒绝
+    Reject,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub search: Search,
     pub transcriptions: Option<ProviderModel>,
+    /// 生成会话标题和思考摘要的模型, 不设置时不自动生成标题
+    #[serde(default, rename = "titleModel")]
+    pub title_model: Option<ProviderModel>,
+    /// 会话中已有回复在生成时, 新发送消息的处理方式
+    #[serde(default, rename = "queuePolicy")]
+    pub queue_policy: QueuePolicy,
 }
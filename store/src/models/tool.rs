@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 pub struct Param {
     /// 参数名称
     pub name: String,
-    /// 参数类型: string, number, boolean, object
+    /// 参数类型: string, number, integer, boolean, object, array
     #[serde(rename = "type")]
     pub param_type: String,
     /// 参数描述
@@ -18,6 +18,75 @@ pub struct Param {
     /// 测试值
     #[serde(rename = "testValue")]
     pub test_value: Option<String>,
+    /// 可选值, 设置时只能取其中之一
+    #[serde(default, rename = "enum")]
+    pub enum_values: Option<Vec<serde_json::Value>>,
+    /// 数组元素的定义, 类型为 array 时使用
+    #[serde(default)]
+    pub items: Option<Box<Param>>,
+    /// 对象的属性, 类型为 object 时使用
+    #[serde(default)]
+    pub properties: Option<Vec<Param>>,
+}
+
+impl Param {
+    /// 参数列表对应的对象 JSON Schema, 作为工具的 inputSchema
+    pub fn object_schema(params: &[Param]) -> serde_json::Value {
+        let properties = params
+            .iter()
+            .map(|param| (param.name.clone(), param.schema()))
+            .collect::<serde_json::Map<_, _>>();
+        let required = params
+            .iter()
+            .filter(|param| param.required)
+            .map(|param| serde_json::Value::String(param.name.clone()))
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })
+    }
+
+    /// 参数的 JSON Schema, 支持可选值以及嵌套的对象和数组
+    pub fn schema(&self) -> serde_json::Value {
+        let param_type = match self.param_type.trim().to_lowercase() {
+            param_type if param_type.is_empty() => "string".to_string(),
+            param_type => param_type,
+        };
+
+        let mut schema = match param_type.as_str() {
+            "object" => Self::object_schema(self.properties.as_deref().unwrap_or_default()),
+            "array" => serde_json::json!({
+                "type": "array",
+                // 没有定义元素时不限制类型
+                "items": self.items.as_ref().map(|items| items.schema()).unwrap_or(serde_json::json!({})),
+            }),
+            _ => serde_json::json!({ "type": param_type }),
+        };
+
+        if !self.description.is_empty() {
+            schema["description"] = self.description.clone().into();
+        }
+        if let Some(values) = self.enum_values.as_ref().filter(|values| !values.is_empty()) {
+            let values = values.iter().map(|value| enum_value(&param_type, value)).collect();
+            schema["enum"] = serde_json::Value::Array(values);
+        }
+        schema
+    }
+}
+
+/// 界面上以字符串填写的可选值转换为参数类型
+fn enum_value(param_type: &str, value: &serde_json::Value) -> serde_json::Value {
+    let Some(text) = value.as_str() else {
+        return value.clone();
+    };
+    let parsed = match param_type {
+        "number" | "integer" | "boolean" => serde_json::from_str(text.trim()).ok(),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| value.clone())
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,6 +105,18 @@ pub struct ToolJavaScript {
 
     /// JS代码
     pub code: String,
+
+    /// 执行超时, 单位秒, 0 表示使用默认值
+    #[serde(default)]
+    pub timeout: u64,
+
+    /// 内存上限, 单位MB, 0 表示使用默认值
+    #[serde(default, rename = "memoryLimit")]
+    pub memory_limit: u64,
+
+    /// 允许 fetch 访问的域名, 包括其子域名, 为空时禁止访问网络, `*` 允许所有域名
+    #[serde(default, rename = "allowHosts")]
+    pub allow_hosts: Vec<String>,
 }
 
 /// MCP-IO工具数据
@@ -56,9 +137,77 @@ pub struct ToolMcpIo {
 pub struct ToolMcpSse {
     /// URL
     pub url: String,
+
+    /// 自定义请求头
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// 认证方式, 不设置时不认证
+    #[serde(default)]
+    pub auth: Option<McpAuth>,
+}
+
+/// MCP Streamable HTTP 工具数据, 服务端不支持时回退到 SSE
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ToolMcpHttp {
+    /// URL
+    pub url: String,
+
+    /// 自定义请求头
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// 认证方式, 不设置时不认证
+    #[serde(default)]
+    pub auth: Option<McpAuth>,
+}
+
+/// 远程 MCP 服务的认证方式
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum McpAuth {
+    /// 固定的 Bearer Token
+    #[serde(rename = "bearer")]
+    Bearer { token: String },
+    /// OAuth 授权码流程, 令牌保存在存储中并自动刷新
+    #[serde(rename = "oauth")]
+    OAuth(McpOAuth),
+}
+
+/// MCP OAuth 客户端配置, 不设置 client_id 时动态注册客户端
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct McpOAuth {
+    #[serde(rename = "clientId")]
+    pub client_id: Option<String>,
+    #[serde(rename = "clientSecret")]
+    pub client_secret: Option<String>,
+    /// 申请的权限, 空格分隔
+    pub scope: Option<String>,
+    /// 接收授权回调的本地端口, 0 表示随机端口, 预先注册的客户端需要固定端口
+    #[serde(default, rename = "redirectPort")]
+    pub redirect_port: u16,
+}
+
+/// MCP OAuth 令牌, 按服务 URL 保存
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct McpOAuthToken {
+    #[serde(rename = "accessToken")]
+    pub access_token: String,
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: Option<String>,
+    /// 过期时间, 毫秒时间戳, 不设置时不过期
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<i64>,
+    /// 刷新令牌使用的地址和客户端
+    #[serde(rename = "tokenEndpoint")]
+    pub token_endpoint: String,
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+    #[serde(rename = "clientSecret")]
+    pub client_secret: Option<String>,
 }
 
-/// 工具数据（可能是JS脚本、MCP-IO或MCP-SSE）
+/// 工具数据（可能是JS脚本、MCP-IO、MCP-SSE或MCP-HTTP）
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum ToolData {
@@ -68,6 +217,8 @@ pub enum ToolData {
     McpIo(ToolMcpIo),
     #[serde(rename = "mcpSse")]
     McpSse(ToolMcpSse),
+    #[serde(rename = "mcpHttp")]
+    McpHttp(ToolMcpHttp),
 }
 
 /// 工具类别
@@ -129,10 +280,11 @@ impl Tool {
         }
     }
 
-    // 获取URL（如果是MCP-SSE工具）
+    // 获取URL（如果是MCP-SSE或MCP-HTTP工具）
     pub fn url(&self) -> Option<&str> {
         match &self.data {
             ToolData::McpSse(mcp_sse) => Some(&mcp_sse.url),
+            ToolData::McpHttp(mcp_http) => Some(&mcp_http.url),
             _ => None,
         }
     }
@@ -0,0 +1,189 @@
+use std::ops::AddAssign;
+
+use bonsaidb::core::{
+    document::{CollectionDocument, Emit},
+    schema::{
+        CollectionMapReduce, ReduceResult, View, ViewMapResult, ViewMappedValue, ViewSchema,
+        view::map::Mappings,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{ChatMessage, MessageStatus, Role, ToolStatus};
+
+/// 一天的毫秒数
+pub const DAY_MILLIS: i64 = 86_400_000;
+
+/// 用量视图分组的时长, 15分钟, 各时区相对 UTC 的偏移都是它的整数倍
+pub const SLOT_MILLIS: i64 = 900_000;
+
+/// 用量统计的时间粒度
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UsagePeriod {
+    #[default]
+    Day,
+    /// 按周一开始的自然周
+    Week,
+}
+
+/// 用量查询条件, 时间按客户端时区的天对齐
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UsageQuery {
+    /// 开始时间, 单位毫秒, 包含所在的整天
+    pub start: Option<i64>,
+    /// 结束时间, 单位毫秒, 包含所在的整天
+    pub end: Option<i64>,
+    /// 模型提供商ID
+    pub provider: Option<u64>,
+    /// 模型名称
+    pub model: Option<String>,
+    /// 智能体ID
+    pub agent: Option<u64>,
+    /// 时间粒度
+    #[serde(default)]
+    pub period: UsagePeriod,
+    /// 客户端时区相对 UTC 的偏移, 单位分钟, 如东八区为 480, 不传时按 UTC 分天
+    #[serde(default, rename = "utcOffset")]
+    pub utc_offset: i64,
+}
+
+/// 一组用量统计
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UsageRow {
+    /// 所在天或周的开始时间, 即客户端时区的零点, 单位毫秒
+    pub period: i64,
+    /// 模型提供商ID
+    pub provider: u64,
+    /// 模型名称
+    pub model: String,
+    /// 智能体ID
+    pub agent: u64,
+    #[serde(flatten)]
+    pub stats: UsageStats,
+    /// 平均耗时, 单位毫秒
+    #[serde(rename = "averageCost")]
+    pub average_cost: f64,
+    /// 失败率
+    #[serde(rename = "errorRate")]
+    pub error_rate: f64,
+}
+
+/// 用量统计
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct UsageStats {
+    /// 回复数
+    pub replies: u64,
+    /// 失败或超时的回复数
+    pub errors: u64,
+    /// 提示tokens
+    #[serde(rename = "promptTokens")]
+    pub prompt_tokens: u64,
+    /// 完成tokens
+    #[serde(rename = "completionTokens")]
+    pub completion_tokens: u64,
+    /// 总tokens
+    #[serde(rename = "totalTokens")]
+    pub total_tokens: u64,
+    /// 费用
+    pub fee: f64,
+    /// 总耗时, 单位毫秒
+    pub cost: i64,
+    /// 工具调用次数
+    #[serde(rename = "toolCalls")]
+    pub tool_calls: u64,
+    /// 工具调用失败次数
+    #[serde(rename = "toolErrors")]
+    pub tool_errors: u64,
+}
+
+impl UsageStats {
+    /// 平均耗时, 单位毫秒
+    pub fn average_cost(&self) -> f64 {
+        if self.replies == 0 { 0.0 } else { self.cost as f64 / self.replies as f64 }
+    }
+
+    /// 失败率
+    pub fn error_rate(&self) -> f64 {
+        if self.replies == 0 { 0.0 } else { self.errors as f64 / self.replies as f64 }
+    }
+}
+
+impl AddAssign<&UsageStats> for UsageStats {
+    fn add_assign(&mut self, other: &UsageStats) {
+        self.replies += other.replies;
+        self.errors += other.errors;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.fee += other.fee;
+        self.cost += other.cost;
+        self.tool_calls += other.tool_calls;
+        self.tool_errors += other.tool_errors;
+    }
+}
+
+/// 按(15分钟时段, 模型提供商ID, 模型名, 智能体ID)汇总助手回复的用量
+///
+/// 视图随消息的增删改增量更新, 查询时不需要扫描全部消息.
+/// 按时段而不是按天分组, 查询时再按客户端时区汇总到本地日期.
+/// 开始记录智能体和模型之前保存的回复没有这两个字段, 不计入统计
+#[derive(Debug, Clone, View, ViewSchema)]
+#[view(collection = ChatMessage, key = (u64, u64, String, u64), value = UsageStats, name = "usage-by-slot")]
+pub struct UsageBySlot;
+
+impl CollectionMapReduce for UsageBySlot {
+    fn map<'doc>(&self, document: CollectionDocument<ChatMessage>) -> ViewMapResult<'doc, Self>
+    where
+        CollectionDocument<ChatMessage>: 'doc,
+    {
+        let message = &document.contents;
+
+        // 只统计已经结束并记录了模型和智能体的回复
+        let finished = matches!(
+            message.status,
+            MessageStatus::Success
+                | MessageStatus::Failed
+                | MessageStatus::Timeout
+                | MessageStatus::Error
+                | MessageStatus::Cancelled
+        );
+        let (Some(model), Some(agent_id)) = (message.model.as_ref(), message.agent_id) else {
+            return Ok(Mappings::default());
+        };
+        // 分支会话复制的回复已经在原会话中统计
+        if message.role != Role::Assistant || !finished || message.forked_from.is_some() {
+            return Ok(Mappings::default());
+        }
+
+        let tools = message.tools.as_deref().unwrap_or_default();
+        let stats = UsageStats {
+            replies: 1,
+            errors: matches!(
+                message.status,
+                MessageStatus::Failed | MessageStatus::Timeout | MessageStatus::Error
+            ) as u64,
+            prompt_tokens: message.prompt_tokens.unwrap_or_default() as u64,
+            completion_tokens: message.completion_tokens.unwrap_or_default() as u64,
+            total_tokens: message.total_tokens.unwrap_or_default() as u64,
+            fee: message.fee.unwrap_or_default(),
+            cost: message.cost.unwrap_or_default(),
+            tool_calls: tools.len() as u64,
+            tool_errors: tools.iter().filter(|tool| tool.status == ToolStatus::Failed).count()
+                as u64,
+        };
+
+        let slot = (message.created_at.max(0) / SLOT_MILLIS) as u64;
+        document.header.emit_key_and_value((slot, model.id, model.name.clone(), agent_id), stats)
+    }
+
+    fn reduce(
+        &self, mappings: &[ViewMappedValue<'_, Self>], _rereduce: bool,
+    ) -> ReduceResult<Self::View> {
+        let mut stats = UsageStats::default();
+        for mapping in mappings {
+            stats += &mapping.value;
+        }
+        Ok(stats)
+    }
+}
@@ -222,7 +222,14 @@ mod tests {
             models: Some(vec![Model {
                 name: "gpt-3.5-turbo".to_string(),
                 tags: vec!["chat".to_string()],
+                price: None,
+                think_tags: None,
             }]),
+            connect_timeout: 0,
+            first_token_timeout: 0,
+            idle_timeout: 0,
+            think_tags: None,
+            mock: None,
             created_at: Some(Utc::now().timestamp()),
             updated_at: None,
         };
@@ -237,6 +244,8 @@ mod tests {
             description: Some("这是一个测试智能体".to_string()),
             model: Some(ProviderModel { id: 1, name: "gpt-3.5-turbo".to_string() }),
             prompt: "你是一个测试助手".to_string(),
+            variables: vec![],
+            prompt_template: false,
             temperature: 0.7,
             top_p: 0.9,
             max_tokens: 2000,
@@ -248,6 +257,12 @@ mod tests {
                 value: "test_value".to_string(),
             }]),
             tools: Some(vec![1, 2, 3]),
+            max_tool_rounds: 0,
+            response_schema: None,
+            reasoning_effort: None,
+            thinking_budget: 0,
+            reasoning_history: Default::default(),
+            auto_continue: 0,
             custom_questions: Some(vec!["你是谁?".to_string(), "你能做什么?".to_string()]),
             created_at: 0, // 将被覆盖
             updated_at: None,
@@ -319,6 +334,8 @@ mod tests {
             description: None,
             model: None,
             prompt: "测试提示词".to_string(),
+            variables: vec![],
+            prompt_template: false,
             temperature: 0.7,
             top_p: 0.9,
             max_tokens: 2000,
@@ -326,6 +343,12 @@ mod tests {
             context_extend: false,
             params: None,
             tools: None,
+            max_tool_rounds: 0,
+            response_schema: None,
+            reasoning_effort: None,
+            thinking_budget: 0,
+            reasoning_history: Default::default(),
+            auto_continue: 0,
             custom_questions: None,
             created_at: 0,
             updated_at: None,
@@ -339,6 +362,8 @@ mod tests {
             description: None,
             model: None,
             prompt: "测试提示词".to_string(),
+            variables: vec![],
+            prompt_template: false,
             temperature: 0.7,
             top_p: 0.9,
             max_tokens: 2000,
@@ -346,6 +371,12 @@ mod tests {
             context_extend: false,
             params: None,
             tools: None,
+            max_tool_rounds: 0,
+            response_schema: None,
+            reasoning_effort: None,
+            thinking_budget: 0,
+            reasoning_history: Default::default(),
+            auto_continue: 0,
             custom_questions: None,
             created_at: 0,
             updated_at: None,
@@ -359,6 +390,8 @@ mod tests {
             description: None,
             model: None,
             prompt: "测试提示词".to_string(),
+            variables: vec![],
+            prompt_template: false,
             temperature: 0.7,
             top_p: 0.9,
             max_tokens: 2000,
@@ -366,6 +399,12 @@ mod tests {
             context_extend: false,
             params: None,
             tools: None,
+            max_tool_rounds: 0,
+            response_schema: None,
+            reasoning_effort: None,
+            thinking_budget: 0,
+            reasoning_history: Default::default(),
+            auto_continue: 0,
             custom_questions: None,
             created_at: 0,
             updated_at: None,
@@ -417,6 +456,8 @@ mod tests {
             description: None,
             model: None,
             prompt: "测试提示词".to_string(),
+            variables: vec![],
+            prompt_template: false,
             temperature: 0.7,
             top_p: 0.9,
             max_tokens: 2000,
@@ -424,6 +465,12 @@ mod tests {
             context_extend: false,
             params: None,
             tools: None,
+            max_tool_rounds: 0,
+            response_schema: None,
+            reasoning_effort: None,
+            thinking_budget: 0,
+            reasoning_history: Default::default(),
+            auto_continue: 0,
             custom_questions: None,
             created_at: Utc::now().timestamp(),
             updated_at: None,
@@ -0,0 +1,471 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::Store;
+use crate::error::StoreError;
+use crate::models::{ChatMessage, ChatSession, ROOT_MESSAGE_ID, Role, SessionFork};
+use bonsaidb::core::schema::SerializedCollection;
+use chrono::Utc;
+
+/// 消息的上级, 旧数据没有上级时按ID顺序取上一条选中的消息, 第一条消息返回 None
+fn parent_of(messages: &[ChatMessage], index: usize) -> Option<u64> {
+    match messages[index].parent_id {
+        Some(ROOT_MESSAGE_ID) => None,
+        Some(parent) => Some(parent),
+        None => messages[..index].iter().rev().find(|m| m.selected != Some(false)).map(|m| m.id),
+    }
+}
+
+/// 从第一条消息到指定消息的路径, messages 按ID升序
+fn build_path(messages: &[ChatMessage], leaf: u64) -> Vec<ChatMessage> {
+    let index: HashMap<u64, usize> = messages.iter().enumerate().map(|(i, m)| (m.id, i)).collect();
+
+    let mut path = Vec::new();
+    let mut visited = HashSet::new();
+    let mut current = Some(leaf);
+    while let Some(id) = current {
+        // 上级消息已删除或者数据有环时停止
+        let Some(&i) = index.get(&id) else {
+            break;
+        };
+        if !visited.insert(id) {
+            break;
+        }
+        path.push(messages[i].clone());
+        current = parent_of(messages, i);
+    }
+
+    path.reverse();
+    path
+}
+
+/// 当前分支的末端消息, 未选中的对比回复换成选中的一条
+fn active_leaf(messages: &[ChatMessage], leaf: Option<u64>) -> Option<u64> {
+    let leaf = leaf.and_then(|id| messages.iter().find(|m| m.id == id)).or(messages.last())?;
+
+    Some(match leaf.selected {
+        Some(false) => {
+            messages
+                .iter()
+                .find(|m| m.parent_id == leaf.parent_id && m.selected == Some(true))
+                .unwrap_or(leaf)
+                .id
+        }
+        _ => leaf.id,
+    })
+}
+
+impl Store {
+    /// 会话的消息, 按ID升序
+    fn get_sorted_messages(&self, session_id: u64) -> Result<Vec<ChatMessage>, StoreError> {
+        let mut messages = self.get_messages_by_session(session_id)?;
+        messages.sort_by_key(|m| m.id);
+        Ok(messages)
+    }
+
+    /// 会话当前分支的末端消息, 没有设置时为最后一条消息, 未选中的对比回复换成选中的一条
+    pub fn get_active_leaf(&self, session_id: u64) -> Result<Option<u64>, StoreError> {
+        let session = self
+            .get_chat_session(session_id)?
+            .ok_or(StoreError::NotFound(format!("ChatSession with id {}", session_id)))?;
+        let messages = self.get_sorted_messages(session_id)?;
+        Ok(active_leaf(&messages, session.active_leaf))
+    }
+
+    /// 设置会话当前分支的末端消息
+    pub fn set_active_leaf(&self, session_id: u64, message_id: u64) -> Result<(), StoreError> {
+        let mut doc = ChatSession::get(&session_id, &self.db)
+            .map_err(|e| StoreError::Operator(format!("get chat session {e}")))?
+            .ok_or(StoreError::NotFound(format!("ChatSession with id {}", session_id)))?;
+        // 不经过 update_chat_session, 它会保留原来的 active_leaf
+        if doc.contents.active_leaf != Some(message_id) {
+            doc.contents.active_leaf = Some(message_id);
+            doc.update(&self.db)
+                .map_err(|e| StoreError::Operator(format!("update chat session {e}")))?;
+        }
+        Ok(())
+    }
+
+    /// 从会话第一条消息到指定消息的路径, 包括该消息
+    pub fn get_message_path(
+        &self, session_id: u64, message_id: u64,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        let messages = self.get_sorted_messages(session_id)?;
+        Ok(build_path(&messages, message_id))
+    }
+
+    /// 会话当前分支的路径, 用于展示和构建上下文
+    pub fn get_active_path(&self, session_id: u64) -> Result<Vec<ChatMessage>, StoreError> {
+        match self.get_active_leaf(session_id)? {
+            Some(leaf) => self.get_message_path(session_id, leaf),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// 把已保存的新消息接到会话中, 没有指定上级时接在当前分支末端,
+    /// 指定上级时作为该上级下的新分支, 返回上级的路径作为上下文, 最多 limit 条
+    pub fn attach_chat_message(
+        &self, message_id: u64, limit: usize,
+    ) -> Result<(ChatMessage, Vec<ChatMessage>), StoreError> {
+        let mut message = self
+            .get_chat_message(message_id)?
+            .ok_or(StoreError::NotFound(format!("ChatMessage with id {}", message_id)))?;
+        let session = self
+            .get_chat_session(message.session_id)?
+            .ok_or(StoreError::NotFound(format!("ChatSession with id {}", message.session_id)))?;
+
+        let mut messages = self.get_sorted_messages(session.id)?;
+        messages.retain(|m| m.id != message_id);
+
+        let parent = match message.parent_id {
+            Some(parent) => parent,
+            None => active_leaf(&messages, session.active_leaf).unwrap_or(ROOT_MESSAGE_ID),
+        };
+        if message.parent_id != Some(parent) {
+            message.parent_id = Some(parent);
+            self.update_chat_message(message.clone())?;
+        }
+
+        let mut path = match parent {
+            ROOT_MESSAGE_ID => Vec::new(),
+            parent => build_path(&messages, parent),
+        };
+        path.drain(..path.len().saturating_sub(limit));
+
+        Ok((message, path))
+    }
+
+    /// 用户消息的回复, 优先当前分支上的一条, 其次选中的对比回复和最新的回复
+    pub fn get_message_reply(&self, message_id: u64) -> Result<Option<ChatMessage>, StoreError> {
+        let message = self
+            .get_chat_message(message_id)?
+            .ok_or(StoreError::NotFound(format!("ChatMessage with id {}", message_id)))?;
+        let session = self
+            .get_chat_session(message.session_id)?
+            .ok_or(StoreError::NotFound(format!("ChatSession with id {}", message.session_id)))?;
+        let messages = self.get_sorted_messages(session.id)?;
+
+        let active = active_leaf(&messages, session.active_leaf)
+            .map(|leaf| build_path(&messages, leaf).into_iter().map(|m| m.id).collect())
+            .unwrap_or_else(HashSet::new);
+
+        Ok((0..messages.len())
+            .filter(|&i| parent_of(&messages, i) == Some(message_id))
+            .map(|i| &messages[i])
+            .filter(|m| m.role == Role::Assistant)
+            .max_by_key(|m| (active.contains(&m.id), m.selected != Some(false), m.id))
+            .cloned())
+    }
+
+    /// 与指定消息有相同上级和角色的分支, 包括该消息, 按ID升序
+    pub fn get_message_branches(&self, message_id: u64) -> Result<Vec<ChatMessage>, StoreError> {
+        let message = self
+            .get_chat_message(message_id)?
+            .ok_or(StoreError::NotFound(format!("ChatMessage with id {}", message_id)))?;
+        let messages = self.get_sorted_messages(message.session_id)?;
+
+        let parents: Vec<Option<u64>> =
+            (0..messages.len()).map(|i| parent_of(&messages, i)).collect();
+        let Some(index) = messages.iter().position(|m| m.id == message_id) else {
+            return Ok(vec![message]);
+        };
+
+        Ok(messages
+            .iter()
+            .zip(parents.iter())
+            .filter(|(m, parent)| **parent == parents[index] && m.role == message.role)
+            .map(|(m, _)| m.clone())
+            .collect())
+    }
+
+    /// 切换到指定消息所在的分支, 末端为该消息下最新的消息, 返回新的当前路径
+    pub fn switch_message_branch(&self, message_id: u64) -> Result<Vec<ChatMessage>, StoreError> {
+        let message = self
+            .get_chat_message(message_id)?
+            .ok_or(StoreError::NotFound(format!("ChatMessage with id {}", message_id)))?;
+        let session_id = message.session_id;
+
+        // 对比回复同时切换选中的回复
+        if message.selected == Some(false) {
+            self.select_chat_message(message_id)?;
+        }
+
+        let messages = self.get_sorted_messages(session_id)?;
+        let parents: Vec<Option<u64>> =
+            (0..messages.len()).map(|i| parent_of(&messages, i)).collect();
+
+        let mut leaf = message_id;
+        let mut visited = HashSet::from([leaf]);
+        loop {
+            // 优先选中的对比回复, 其次最新的消息
+            let child = messages
+                .iter()
+                .zip(parents.iter())
+                .filter(|(m, parent)| **parent == Some(leaf) && !visited.contains(&m.id))
+                .map(|(m, _)| m)
+                .max_by_key(|m| (m.selected != Some(false), m.id));
+            let Some(child) = child else {
+                break;
+            };
+            leaf = child.id;
+            visited.insert(leaf);
+        }
+
+        self.set_active_leaf(session_id, leaf)?;
+        Ok(build_path(&messages, leaf))
+    }
+
+    /// 复制会话中到指定消息为止的当前路径到新会话, 包括附件和工具结果,
+    /// 可以指定新的智能体, 原会话不受影响
+    pub fn fork_chat_session(
+        &self, session_id: u64, message_id: u64, agent_id: Option<u64>,
+    ) -> Result<ChatSession, StoreError> {
+        let session = self
+            .get_chat_session(session_id)?
+            .ok_or(StoreError::NotFound(format!("ChatSession with id {}", session_id)))?;
+        let path = self.get_message_path(session_id, message_id)?;
+        if path.last().is_none_or(|m| m.id != message_id) {
+            return Err(StoreError::NotFound(format!(
+                "ChatMessage with id {} in session {}",
+                message_id, session_id
+            )));
+        }
+
+        // 新消息按路径顺序连续编号, 回复的ID仍然紧跟在问题之后
+        let mut base = Utc::now().timestamp_millis() as u64;
+        while (base..base + path.len() as u64)
+            .any(|id| self.get_chat_message(id).is_ok_and(|m| m.is_some()))
+        {
+            base += path.len() as u64;
+        }
+        let mut fork_id = Utc::now().timestamp_millis() as u64;
+        while self.get_chat_session(fork_id)?.is_some() {
+            fork_id += 1;
+        }
+
+        let fork = self.add_chat_session(ChatSession {
+            id: fork_id,
+            agent_id: agent_id.unwrap_or(session.agent_id),
+            topic: session.topic,
+            topic_edited: session.topic_edited,
+            input: session.input,
+            variables: session.variables,
+            active_leaf: Some(base + path.len() as u64 - 1),
+            forked_from: Some(SessionFork { session_id, message_id }),
+            created_at: 0,
+            updated_at: None,
+        })?;
+
+        let mut parent = ROOT_MESSAGE_ID;
+        for (index, message) in path.into_iter().enumerate() {
+            let id = base + index as u64;
+            self.add_chat_message(ChatMessage {
+                id,
+                session_id: fork.id,
+                parent_id: Some(parent),
+                // 路径上只有一条回复, 不再是对比回复
+                selected: None,
+                forked_from: Some(message.id),
+                ..message
+            })?;
+            parent = id;
+        }
+
+        Ok(fork)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Attachment, ChatInput, MessageStatus, Role, ToolResult, ToolStatus};
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn message(id: u64, role: Role, parent_id: Option<u64>) -> ChatMessage {
+        let mut message = match role {
+            Role::User => ChatMessage::new_user(1, format!("消息 {id}"), None),
+            _ => ChatMessage::new_assistant(id, 1),
+        };
+        message.id = id;
+        message.parent_id = parent_id;
+        message.status = MessageStatus::Success;
+        message
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<u64> {
+        messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn test_message_branches() {
+        // 创建临时测试目录
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+
+        let session = ChatSession {
+            id: 1,
+            agent_id: 1,
+            topic: "分支".to_string(),
+            topic_edited: false,
+            input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: None,
+            created_at: 0,
+            updated_at: None,
+        };
+        store.add_chat_session(session).unwrap();
+
+        // 旧数据没有上级消息, 按ID顺序组成一条路径
+        store.add_chat_message(message(1, Role::User, None)).unwrap();
+        store.add_chat_message(message(2, Role::Assistant, None)).unwrap();
+        assert_eq!(ids(&store.get_active_path(1).unwrap()), vec![1, 2]);
+
+        // 编辑第二个问题产生新的分支
+        store.add_chat_message(message(3, Role::User, Some(2))).unwrap();
+        store.add_chat_message(message(4, Role::Assistant, Some(3))).unwrap();
+        store.add_chat_message(message(5, Role::User, Some(2))).unwrap();
+        store.add_chat_message(message(6, Role::Assistant, Some(5))).unwrap();
+        assert_eq!(ids(&store.get_active_path(1).unwrap()), vec![1, 2, 5, 6]);
+        assert_eq!(ids(&store.get_message_path(1, 4).unwrap()), vec![1, 2, 3, 4]);
+        assert_eq!(ids(&store.get_message_branches(3).unwrap()), vec![3, 5]);
+
+        // 编辑第一个问题, 新的根消息与旧数据的第一条消息互为分支
+        store.add_chat_message(message(7, Role::User, Some(ROOT_MESSAGE_ID))).unwrap();
+        assert_eq!(ids(&store.get_message_branches(1).unwrap()), vec![1, 7]);
+        assert_eq!(ids(&store.get_message_path(1, 7).unwrap()), vec![7]);
+
+        // 切换到旧的分支, 末端为该分支最新的消息
+        assert_eq!(ids(&store.switch_message_branch(3).unwrap()), vec![1, 2, 3, 4]);
+        assert_eq!(store.get_chat_session(1).unwrap().unwrap().active_leaf, Some(4));
+        assert_eq!(ids(&store.get_active_path(1).unwrap()), vec![1, 2, 3, 4]);
+
+        // 从第一条消息切换时沿最新的分支向下
+        assert_eq!(ids(&store.switch_message_branch(1).unwrap()), vec![1, 2, 5, 6]);
+
+        // 新消息接在当前分支末端, 上下文只包括当前分支
+        store.add_chat_message(message(8, Role::User, None)).unwrap();
+        let (attached, history) = store.attach_chat_message(8, 3).unwrap();
+        assert_eq!(attached.parent_id, Some(6));
+        assert_eq!(ids(&history), vec![2, 5, 6]);
+
+        // 指定上级时作为新的分支
+        store.add_chat_message(message(9, Role::User, Some(4))).unwrap();
+        let (attached, history) = store.attach_chat_message(9, 10).unwrap();
+        assert_eq!(attached.parent_id, Some(4));
+        assert_eq!(ids(&history), vec![1, 2, 3, 4]);
+
+        // 按上下级关系查找回复, 对比回复的ID不一定紧跟在用户消息之后
+        store.add_chat_message(message(20, Role::Assistant, Some(9))).unwrap();
+        let mut compare = message(21, Role::Assistant, Some(9));
+        compare.selected = Some(false);
+        store.add_chat_message(compare).unwrap();
+        assert_eq!(store.get_message_reply(9).unwrap().map(|m| m.id), Some(20));
+        store.set_active_leaf(1, 21).unwrap();
+        assert_eq!(store.get_message_reply(9).unwrap().map(|m| m.id), Some(21));
+        assert_eq!(store.get_message_reply(1).unwrap().map(|m| m.id), Some(2));
+        assert!(store.get_message_reply(20).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_update_keeps_active_leaf() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+
+        let session = ChatSession {
+            id: 1,
+            agent_id: 1,
+            topic: "分支".to_string(),
+            topic_edited: false,
+            input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: Some(SessionFork { session_id: 2, message_id: 3 }),
+            created_at: 0,
+            updated_at: None,
+        };
+        store.add_chat_session(session.clone()).unwrap();
+        store.set_active_leaf(1, 4).unwrap();
+
+        // 前端保存旧的会话副本, 当前分支和分支来源不被覆盖
+        let stale = ChatSession { topic: "新主题".to_string(), forked_from: None, ..session };
+        store.update_chat_session(stale).unwrap();
+        let saved = store.get_chat_session(1).unwrap().unwrap();
+        assert_eq!(saved.topic, "新主题");
+        assert_eq!(saved.active_leaf, Some(4));
+        assert_eq!(saved.forked_from, Some(SessionFork { session_id: 2, message_id: 3 }));
+
+        store.set_active_leaf(1, 6).unwrap();
+        assert_eq!(store.get_chat_session(1).unwrap().unwrap().active_leaf, Some(6));
+    }
+
+    #[test]
+    fn test_fork_chat_session() {
+        // 创建临时测试目录
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+
+        let session = ChatSession {
+            id: 1,
+            agent_id: 1,
+            topic: "原会话".to_string(),
+            topic_edited: false,
+            input: ChatInput::default(),
+            variables: HashMap::from([("name".to_string(), "value".to_string())]),
+            active_leaf: None,
+            forked_from: None,
+            created_at: 0,
+            updated_at: None,
+        };
+        store.add_chat_session(session).unwrap();
+
+        let mut question = message(1, Role::User, Some(ROOT_MESSAGE_ID));
+        question.attachments = Some(vec![Attachment {
+            name: "a.txt".to_string(),
+            size: 4,
+            data: "dGVzdA==".to_string(),
+        }]);
+        store.add_chat_message(question).unwrap();
+
+        // 两条对比回复, 选中第二条
+        for id in [2, 3] {
+            let mut reply = message(id, Role::Assistant, Some(1));
+            reply.selected = Some(id == 3);
+            reply.tools = Some(vec![ToolResult {
+                id: format!("call_{id}"),
+                name: "search".to_string(),
+                arguments: "{}".to_string(),
+                result: "结果".to_string(),
+                status: ToolStatus::Success,
+                error: None,
+                cost: None,
+            }]);
+            store.add_chat_message(reply).unwrap();
+        }
+        store.add_chat_message(message(4, Role::User, Some(3))).unwrap();
+        store.add_chat_message(message(5, Role::Assistant, Some(4))).unwrap();
+
+        // 复制到选中的回复, 使用新的智能体
+        let fork = store.fork_chat_session(1, 3, Some(2)).unwrap();
+        assert_ne!(fork.id, 1);
+        assert_eq!(fork.agent_id, 2);
+        assert_eq!(fork.variables.get("name").map(String::as_str), Some("value"));
+        assert_eq!(fork.forked_from, Some(SessionFork { session_id: 1, message_id: 3 }));
+
+        let path = store.get_active_path(fork.id).unwrap();
+        assert_eq!(path.iter().map(|m| m.forked_from).collect::<Vec<_>>(), vec![Some(1), Some(3)]);
+        assert_eq!(path[0].parent_id, Some(ROOT_MESSAGE_ID));
+        assert_eq!(path[1].id, path[0].id + 1);
+        assert_eq!(path[1].parent_id, Some(path[0].id));
+        assert_eq!(path[1].selected, None);
+        assert_eq!(path[0].attachments.as_ref().map(Vec::len), Some(1));
+        assert_eq!(path[1].tools.as_ref().unwrap()[0].id, "call_3");
+
+        // 原会话不受影响
+        assert_eq!(store.get_messages_by_session(1).unwrap().len(), 5);
+        assert_eq!(ids(&store.get_active_path(1).unwrap()), vec![1, 3, 4, 5]);
+
+        // 不在会话中的消息不能复制
+        assert!(store.fork_chat_session(1, 999, None).is_err());
+    }
+}
@@ -55,6 +55,12 @@ impl Store {
 
         let mut updated_session = session;
 
+        // 当前分支和分支来源由后端维护, 前端保存的会话可能是旧的
+        updated_session.active_leaf = doc.contents.active_leaf;
+        updated_session.forked_from = doc.contents.forked_from.clone();
+        updated_session.topic_edited =
+            doc.contents.topic_edited || updated_session.topic != doc.contents.topic;
+
         // 确保更新时间有值
         updated_session.updated_at = Some(Utc::now().timestamp());
 
@@ -65,6 +71,27 @@ impl Store {
         Ok(())
     }
 
+    /// 设置生成的会话标题, keep_edited 为 true 时不覆盖用户修改过的标题
+    ///
+    /// 返回更新后的会话, 没有更新时返回 None
+    pub fn set_generated_topic(
+        &self, id: u64, topic: String, keep_edited: bool,
+    ) -> Result<Option<ChatSession>, StoreError> {
+        let mut doc = ChatSession::get(&id, &self.db)
+            .map_err(|e| StoreError::Operator(format!("get chat session {e}")))?
+            .ok_or(StoreError::NotFound(format!("ChatSession with id {}", id)))?;
+        if keep_edited && doc.contents.topic_edited {
+            return Ok(None);
+        }
+
+        doc.contents.topic = topic;
+        doc.contents.topic_edited = false;
+        doc.contents.updated_at = Some(Utc::now().timestamp());
+        doc.update(&self.db)
+            .map_err(|e| StoreError::Operator(format!("update chat session {e}")))?;
+        Ok(Some(doc.contents))
+    }
+
     /// 删除聊天会话
     pub fn delete_chat_session(&self, id: u64) -> Result<(), StoreError> {
         // 检查是否存在该会话
@@ -121,7 +148,8 @@ impl Store {
     pub fn add_chat_message(&self, message: ChatMessage) -> Result<ChatMessage, StoreError> {
         let mut message_to_save = message;
 
-        if message_to_save.id == 0 {
+        let generated = message_to_save.id == 0;
+        if generated {
             // 如果没有ID，则生成一个新的ID
             message_to_save.id = Utc::now().timestamp_millis() as u64;
         }
@@ -139,11 +167,16 @@ impl Store {
             )));
         }
 
-        message_to_save
-            .clone()
-            .push_into(&self.db)
-            .map_err(|e| StoreError::Operator(format!("add chat message {e}")))?;
-        Ok(message_to_save)
+        loop {
+            match message_to_save.clone().push_into(&self.db) {
+                Ok(_) => return Ok(message_to_save),
+                // 生成的ID已被同一毫秒内保存的消息占用, 顺延后重试
+                Err(_) if generated && self.get_chat_message(message_to_save.id)?.is_some() => {
+                    message_to_save.id += 1;
+                }
+                Err(e) => return Err(StoreError::Operator(format!("add chat message {e}"))),
+            }
+        }
     }
 
     /// 通过ID获取聊天消息
@@ -220,22 +253,45 @@ impl Store {
         Ok(())
     }
 
+    /// 选中同一问题的多条对比回复中的一条, 其它回复不再进入后续对话的上下文
+    pub fn select_chat_message(&self, id: u64) -> Result<(), StoreError> {
+        let message = self
+            .get_chat_message(id)?
+            .ok_or(StoreError::NotFound(format!("ChatMessage with id {}", id)))?;
+
+        let Some(parent_id) = message.parent_id else {
+            return Err(StoreError::Operator(format!("chat message {id} has no parent")));
+        };
+
+        let siblings = self
+            .get_messages_by_session(message.session_id)?
+            .into_iter()
+            .filter(|m| m.parent_id == Some(parent_id) && m.selected.is_some());
+
+        for mut sibling in siblings {
+            let selected = sibling.id == id;
+            if sibling.selected != Some(selected) {
+                sibling.selected = Some(selected);
+                self.update_chat_message(sibling)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// 获取会话的最近消息
     pub fn get_latest_messages_by_session(
         &self, session_id: u64, limit: usize,
     ) -> Result<Vec<ChatMessage>, StoreError> {
-        let mut sorted_messages = self.get_messages_by_session(session_id)?;
+        let mut path = self.get_active_path(session_id)?;
 
-        sorted_messages.sort_by(|a, b| b.id.cmp(&a.id));
+        // 未选中的对比回复不进入上下文
+        path.retain(|m| m.selected != Some(false));
 
         // 限制返回数量
-        if sorted_messages.len() > limit {
-            sorted_messages.truncate(limit);
-        }
-
-        sorted_messages.sort_by(|a, b| a.id.cmp(&b.id));
+        path.drain(..path.len().saturating_sub(limit));
 
-        Ok(sorted_messages)
+        Ok(path)
     }
 
     /// 根据会话里面的一条消息,查找指定条数的消息
@@ -276,9 +332,10 @@ mod tests {
     use super::*;
     use crate::{
         ChatInput,
-        models::{ChatMessage, ChatSession, MessageStatus, Role},
+        models::{ChatMessage, ChatSession, FinishReason, MessageStatus, Role},
     };
     use chrono::Utc;
+    use std::collections::HashMap;
     use tempfile::tempdir;
 
     #[test]
@@ -292,7 +349,11 @@ mod tests {
             id: 1,
             agent_id: 200,
             topic: "测试聊天会话".to_string(),
+            topic_edited: false,
             input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: None,
             created_at: 0, // 将被自动设置
             updated_at: None,
         };
@@ -317,13 +378,29 @@ mod tests {
         // 测试更新
         let mut updated_session = fetched.clone();
         updated_session.topic = "更新后的会话主题".to_string();
+        updated_session.variables.insert("language".to_string(), "中文".to_string());
         let update_result = store.update_chat_session(updated_session);
         assert!(update_result.is_ok());
 
         let fetched_after_update = store.get_chat_session(1).unwrap().unwrap();
         assert_eq!(fetched_after_update.topic, "更新后的会话主题");
+        assert_eq!(fetched_after_update.variables.get("language").unwrap(), "中文");
         assert!(fetched_after_update.updated_at.is_some());
 
+        // 用户修改过的标题不被自动生成的标题覆盖
+        assert!(fetched_after_update.topic_edited);
+        assert!(store.set_generated_topic(1, "生成的标题".to_string(), true).unwrap().is_none());
+        let generated = store.set_generated_topic(1, "生成的标题".to_string(), false).unwrap();
+        assert_eq!(
+            generated.map(|s| (s.topic, s.topic_edited)),
+            Some(("生成的标题".to_string(), false))
+        );
+        // 只修改其他字段时仍是生成的标题
+        let mut session = store.get_chat_session(1).unwrap().unwrap();
+        session.variables.clear();
+        store.update_chat_session(session).unwrap();
+        assert!(!store.get_chat_session(1).unwrap().unwrap().topic_edited);
+
         // 测试删除
         let delete_result = store.delete_chat_session(1);
         assert!(delete_result.is_ok());
@@ -341,7 +418,11 @@ mod tests {
             id: 1,
             agent_id: 200,
             topic: "测试会话".to_string(),
+            topic_edited: false,
             input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: None,
             created_at: 0,
             updated_at: None,
         };
@@ -355,6 +436,7 @@ mod tests {
             reasoning_content: None,
             content: "你好，这是一条测试消息".to_string(),
             status: MessageStatus::Sending,
+            error: None,
             cost: None,
             feedback: 0,
             prompt_tokens: None,
@@ -363,6 +445,18 @@ mod tests {
             created_at: 0, // 将被自动设置
             tools: None,
             attachments: None,
+            parent_id: None,
+            agent_id: None,
+            model: None,
+            selected: None,
+            structured: None,
+            fee: None,
+            first_token: None,
+            first_content: None,
+            tokens_per_second: None,
+            reasoning_summary: None,
+            finish_reason: None,
+            forked_from: None,
         };
 
         // 测试添加
@@ -383,12 +477,14 @@ mod tests {
         let mut updated_message = fetched.clone();
         updated_message.content = "已更新的消息内容".to_string();
         updated_message.status = MessageStatus::Success;
+        updated_message.finish_reason = Some(FinishReason::Length);
         let update_result = store.update_chat_message(updated_message);
         assert!(update_result.is_ok());
 
         let fetched_after_update = store.get_chat_message(1).unwrap().unwrap();
         assert_eq!(fetched_after_update.content, "已更新的消息内容");
         assert_eq!(fetched_after_update.status, MessageStatus::Success);
+        assert_eq!(fetched_after_update.finish_reason, Some(FinishReason::Length));
 
         // 添加更多消息用于测试获取最近消息
         for i in 2..6 {
@@ -399,6 +495,7 @@ mod tests {
                 reasoning_content: None,
                 content: format!("测试消息 {}", i),
                 status: MessageStatus::Success,
+                error: None,
                 cost: Some(i as i64),
                 feedback: 0,
                 prompt_tokens: Some(i as u32),
@@ -407,6 +504,18 @@ mod tests {
                 created_at: Utc::now().timestamp() + i as i64, // 递增的时间戳
                 tools: None,
                 attachments: None,
+                parent_id: None,
+                agent_id: None,
+                model: None,
+                selected: None,
+                structured: None,
+                fee: None,
+                first_token: None,
+                first_content: None,
+                tokens_per_second: None,
+                reasoning_summary: None,
+                finish_reason: None,
+                forked_from: None,
             };
             store.add_chat_message(msg).unwrap();
         }
@@ -439,6 +548,7 @@ mod tests {
             reasoning_content: None,
             content: "新的测试消息".to_string(),
             status: MessageStatus::Sending,
+            error: None,
             cost: None,
             feedback: 0,
             prompt_tokens: None,
@@ -447,6 +557,18 @@ mod tests {
             created_at: Utc::now().timestamp(),
             tools: None,
             attachments: None,
+            parent_id: None,
+            agent_id: None,
+            model: None,
+            selected: None,
+            structured: None,
+            fee: None,
+            first_token: None,
+            first_content: None,
+            tokens_per_second: None,
+            reasoning_summary: None,
+            finish_reason: None,
+            forked_from: None,
         };
         store.add_chat_message(msg).unwrap();
 
@@ -461,6 +583,100 @@ mod tests {
         assert_eq!(store.get_messages_by_session(1).unwrap().len(), 0);
     }
 
+    #[test]
+    fn test_select_compare_message() {
+        // 创建临时测试目录
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+
+        let session = ChatSession {
+            id: 1,
+            agent_id: 200,
+            topic: "对比会话".to_string(),
+            topic_edited: false,
+            input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: None,
+            created_at: 0,
+            updated_at: None,
+        };
+        store.add_chat_session(session).unwrap();
+
+        // 一条用户消息和三条对比回复, 默认选中第一条
+        let mut user = ChatMessage::new_user(1, "同一个问题".to_string(), None);
+        user.id = 100;
+        user.status = MessageStatus::Success;
+        store.add_chat_message(user).unwrap();
+
+        for i in 0..3u64 {
+            let mut reply = ChatMessage::new_assistant(101 + i, 1);
+            reply.content = format!("回复 {}", i);
+            reply.status = MessageStatus::Success;
+            reply.parent_id = Some(100);
+            reply.agent_id = Some(200 + i);
+            reply.selected = Some(i == 0);
+            store.add_chat_message(reply).unwrap();
+        }
+
+        let history = store.get_latest_messages_by_session(1, 10).unwrap();
+        assert_eq!(history.iter().map(|m| m.id).collect::<Vec<_>>(), vec![100, 101]);
+
+        // 选中第三条回复
+        store.select_chat_message(103).unwrap();
+        assert_eq!(store.get_chat_message(101).unwrap().unwrap().selected, Some(false));
+        assert_eq!(store.get_chat_message(102).unwrap().unwrap().selected, Some(false));
+        assert_eq!(store.get_chat_message(103).unwrap().unwrap().selected, Some(true));
+
+        let history = store.get_latest_messages_by_session(1, 10).unwrap();
+        assert_eq!(history.iter().map(|m| m.id).collect::<Vec<_>>(), vec![100, 103]);
+
+        // 按消息查询时返回全部对比回复, 用于界面展示
+        let list = store.get_latest_messages_by_session_and_message(1, u64::MAX, 10).unwrap();
+        assert_eq!(list.len(), 4);
+
+        // 用户消息没有上级消息, 不能被选中
+        assert!(store.select_chat_message(100).is_err());
+        assert!(store.select_chat_message(999).is_err());
+    }
+
+    #[test]
+    fn test_generated_message_ids() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+
+        let session = ChatSession {
+            id: 1,
+            agent_id: 1,
+            topic: "消息ID".to_string(),
+            topic_edited: false,
+            input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: None,
+            created_at: 0,
+            updated_at: None,
+        };
+        store.add_chat_session(session).unwrap();
+
+        // 占用当前时间之后的ID, 生成的ID需要跳过
+        let now = Utc::now().timestamp_millis() as u64;
+        for id in now..now + 20 {
+            store.add_chat_message(ChatMessage::new_assistant(id, 1)).unwrap();
+        }
+
+        let mut ids = (0..20)
+            .map(|_| store.add_chat_message(ChatMessage::new_assistant(0, 1)).unwrap().id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 20);
+        assert_eq!(store.get_messages_by_session(1).unwrap().len(), 40);
+
+        // 指定的ID已存在时仍然返回错误
+        assert!(store.add_chat_message(ChatMessage::new_assistant(now, 1)).is_err());
+    }
+
     #[test]
     fn test_chat_message_with_nonexistent_session() {
         // 创建临时测试目录
@@ -475,6 +691,7 @@ mod tests {
             reasoning_content: None,
             content: "测试消息".to_string(),
             status: MessageStatus::Success,
+            error: None,
             cost: None,
             feedback: 0,
             prompt_tokens: None,
@@ -483,6 +700,18 @@ mod tests {
             created_at: 0,
             tools: None,
             attachments: None,
+            parent_id: None,
+            agent_id: None,
+            model: None,
+            selected: None,
+            structured: None,
+            fee: None,
+            first_token: None,
+            first_content: None,
+            tokens_per_second: None,
+            reasoning_summary: None,
+            finish_reason: None,
+            forked_from: None,
         };
 
         // 测试添加 - 应该失败
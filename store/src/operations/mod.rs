@@ -1,5 +1,7 @@
 pub mod agent;
+pub mod branch;
 pub mod chat;
 pub mod provider;
 pub mod settings;
 pub mod tool;
+pub mod usage;
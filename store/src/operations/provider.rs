@@ -59,7 +59,7 @@ impl Store {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::Model;
+    use crate::models::{MODEL_TAG_STRUCTURED_OUTPUT, Model, ModelPrice, ThinkTags};
     use chrono::Utc;
     use tempfile::tempdir;
 
@@ -71,10 +71,20 @@ mod tests {
 
         // 准备测试数据
         let models = vec![
-            Model { name: "gpt-3.5-turbo".to_string(), tags: vec!["chat".to_string()] },
+            Model {
+                name: "gpt-3.5-turbo".to_string(),
+                tags: vec!["chat".to_string()],
+                price: None,
+                think_tags: None,
+            },
             Model {
                 name: "gpt-4".to_string(),
                 tags: vec!["chat".to_string(), "advanced".to_string()],
+                price: Some(ModelPrice { input: 2.0, output: 8.0, cached_input: Some(0.5) }),
+                think_tags: Some(ThinkTags {
+                    open: "<reasoning>".to_string(),
+                    close: "</reasoning>".to_string(),
+                }),
             },
         ];
 
@@ -85,6 +95,11 @@ mod tests {
             url: "https://api.openai.com/v1".to_string(),
             api_key: Some("sk-test-key".to_string()),
             models: Some(models),
+            connect_timeout: 0,
+            first_token_timeout: 0,
+            idle_timeout: 0,
+            think_tags: Some(ThinkTags::default()),
+            mock: None,
             created_at: Some(Utc::now().timestamp()),
             updated_at: None,
         };
@@ -98,6 +113,21 @@ mod tests {
         assert_eq!(fetched.name, "OpenAI");
         assert_eq!(fetched.api_category, "openai");
         assert_eq!(fetched.models.as_ref().unwrap().len(), 2);
+        assert!(fetched.model("gpt-4").unwrap().has_tag("advanced"));
+        assert!(!fetched.model("gpt-3.5-turbo").unwrap().has_tag(MODEL_TAG_STRUCTURED_OUTPUT));
+        assert!(fetched.model("gpt-5").is_none());
+
+        // 模型的思考标签覆盖提供商的设置
+        assert_eq!(fetched.think_tags("gpt-4").unwrap().open, "<reasoning>");
+        assert_eq!(fetched.think_tags("gpt-3.5-turbo"), Some(&ThinkTags::default()));
+        assert_eq!(fetched.think_tags("gpt-5").unwrap().close, "</think>");
+
+        // 100万输入tokens中20万命中缓存, 50万输出tokens
+        let price = fetched.model("gpt-4").unwrap().price.clone().unwrap();
+        assert!((price.fee(1_000_000, 200_000, 500_000) - (1.6 + 0.1 + 4.0)).abs() < 1e-9);
+        // 没有缓存价格时按输入价格计算
+        let price = ModelPrice { cached_input: None, ..price };
+        assert!((price.fee(1_000_000, 200_000, 0) - 2.0).abs() < 1e-9);
 
         // 测试获取所有
         let all_providers = store.get_all_providers().unwrap();
@@ -139,6 +169,11 @@ mod tests {
             url: "https://example.com".to_string(),
             api_key: None,
             models: None,
+            connect_timeout: 0,
+            first_token_timeout: 0,
+            idle_timeout: 0,
+            think_tags: None,
+            mock: None,
             created_at: Some(Utc::now().timestamp()),
             updated_at: None,
         };
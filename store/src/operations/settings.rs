@@ -21,7 +21,7 @@ impl Store {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::{Search, SearchType, Settings};
+    use crate::models::{ProviderModel, QueuePolicy, Search, SearchType, Settings};
     use tempfile::tempdir;
 
     #[test]
@@ -42,7 +42,12 @@ mod tests {
             mode: 1,
             result_count: 5,
         };
-        let settings = Settings { search: search.clone(), transcriptions: None };
+        let settings = Settings {
+            search: search.clone(),
+            transcriptions: None,
+            title_model: Some(ProviderModel { id: 1, name: "gpt-4o-mini".to_string() }),
+            queue_policy: QueuePolicy::Reject,
+        };
         // 设置 settings
         let set_result = store.set_settings(settings.clone());
         assert!(set_result.is_ok());
@@ -58,5 +63,7 @@ mod tests {
         assert_eq!(retrieved_settings.search.mode, 1);
         assert_eq!(retrieved_settings.search.result_count, 5);
         assert!(retrieved_settings.transcriptions.is_none());
+        assert_eq!(retrieved_settings.title_model.unwrap().name, "gpt-4o-mini");
+        assert_eq!(retrieved_settings.queue_policy, QueuePolicy::Reject);
     }
 }
@@ -1,6 +1,7 @@
 use crate::Store;
 use crate::error::StoreError;
-use crate::models::{Tool, ToolCategory};
+use crate::models::{McpOAuthToken, Tool, ToolCategory};
+use bonsaidb::core::keyvalue::*;
 use bonsaidb::core::schema::SerializedCollection;
 
 impl Store {
@@ -146,6 +147,31 @@ impl Store {
         }
         Ok(())
     }
+
+    // ==== MCP OAuth 令牌 操作 ====
+
+    /// 获取 MCP 服务的 OAuth 令牌
+    pub fn get_mcp_oauth_token(&self, url: &str) -> Result<Option<McpOAuthToken>, StoreError> {
+        self.db
+            .get_key(format!("mcp.oauth.{url}"))
+            .query()?
+            .map(|value| value.deserialize::<McpOAuthToken>().map_err(|e| e.into()))
+            .transpose()
+    }
+
+    /// 保存 MCP 服务的 OAuth 令牌
+    pub fn set_mcp_oauth_token(&self, url: &str, token: &McpOAuthToken) -> Result<(), StoreError> {
+        self.db
+            .set_key(format!("mcp.oauth.{url}"), token)
+            .execute()
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    /// 删除 MCP 服务的 OAuth 令牌, 取消授权
+    pub fn delete_mcp_oauth_token(&self, url: &str) -> Result<(), StoreError> {
+        self.db.delete_key(format!("mcp.oauth.{url}")).map(|_| ()).map_err(|e| e.into())
+    }
 }
 
 #[cfg(test)]
@@ -226,8 +252,14 @@ mod tests {
                     description: "参数1".to_string(),
                     required: true,
                     test_value: Some("test".to_string()),
+                    enum_values: None,
+                    items: None,
+                    properties: None,
                 }]),
                 code: "function test() { return 'test'; }".to_string(),
+                timeout: 0,
+                memory_limit: 0,
+                allow_hosts: Vec::new(),
             }),
             created_at: Utc::now().timestamp(),
             updated_at: None,
@@ -293,6 +325,9 @@ mod tests {
             data: ToolData::JavsScript(ToolJavaScript {
                 param: None,
                 code: "function test() {}".to_string(),
+                timeout: 0,
+                memory_limit: 0,
+                allow_hosts: Vec::new(),
             }),
             created_at: Utc::now().timestamp(),
             updated_at: None,
@@ -306,6 +341,8 @@ mod tests {
             description: Some("工具2描述".to_string()),
             data: ToolData::McpSse(ToolMcpSse {
                 url: "http://example.com".to_string(),
+                headers: Default::default(),
+                auth: None,
                 // tools: vec![McpTool {
                 //     name: "工具2".to_string(),
                 //     description: "工具2描述".to_string(),
@@ -331,6 +368,9 @@ mod tests {
             data: ToolData::JavsScript(ToolJavaScript {
                 param: None,
                 code: "function test() {}".to_string(),
+                timeout: 0,
+                memory_limit: 0,
+                allow_hosts: Vec::new(),
             }),
             created_at: Utc::now().timestamp(),
             updated_at: None,
@@ -370,6 +410,8 @@ mod tests {
             description: Some("工具2描述".to_string()),
             data: ToolData::McpSse(ToolMcpSse {
                 url: "http://example.com".to_string(),
+                headers: Default::default(),
+                auth: None,
                 // tools: vec![McpTool {
                 //     name: "工具2".to_string(),
                 //     description: "工具2描述".to_string(),
@@ -391,4 +433,90 @@ mod tests {
         let deserialized: Tool = serde_json::from_str(&serialized).unwrap();
         println!("Deserialized Tool: {:?}", deserialized);
     }
+
+    #[test]
+    fn test_param_schema() {
+        let param = |name: &str, param_type: &str, required: bool| Param {
+            name: name.to_string(),
+            param_type: param_type.to_string(),
+            description: format!("{name} 描述"),
+            required,
+            test_value: None,
+            enum_values: None,
+            items: None,
+            properties: None,
+        };
+
+        let mut unit = param("unit", "string", false);
+        unit.enum_values = Some(vec!["celsius".into(), "fahrenheit".into()]);
+        let mut days = param("days", "integer", false);
+        days.enum_values = Some(vec!["1".into(), "3".into()]);
+        let mut cities = param("cities", "array", true);
+        cities.items = Some(Box::new(param("city", "string", false)));
+        let mut options = param("options", "object", false);
+        options.properties = Some(vec![unit, days]);
+
+        let schema = Param::object_schema(&[cities, options, param("raw", "array", false)]);
+        assert_eq!(
+            schema,
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "cities": {
+                        "type": "array",
+                        "description": "cities 描述",
+                        "items": { "type": "string", "description": "city 描述" },
+                    },
+                    "options": {
+                        "type": "object",
+                        "description": "options 描述",
+                        "properties": {
+                            "unit": {
+                                "type": "string",
+                                "description": "unit 描述",
+                                "enum": ["celsius", "fahrenheit"],
+                            },
+                            // 可选值转换为参数类型
+                            "days": { "type": "integer", "description": "days 描述", "enum": [1, 3] },
+                        },
+                        "required": [],
+                    },
+                    // 没有定义元素时不限制类型
+                    "raw": { "type": "array", "description": "raw 描述", "items": {} },
+                },
+                "required": ["cities"],
+            })
+        );
+
+        // 旧数据没有新增字段也能读取
+        let old =
+            r#"{"name":"q","type":"string","description":"","required":true,"testValue":null}"#;
+        let old: Param = serde_json::from_str(old).unwrap();
+        assert_eq!(old.schema(), serde_json::json!({ "type": "string" }));
+    }
+
+    #[test]
+    fn test_mcp_oauth_token() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+        let url = "https://mcp.example.com/mcp";
+        assert!(store.get_mcp_oauth_token(url).unwrap().is_none());
+
+        let token = McpOAuthToken {
+            access_token: "access".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(Utc::now().timestamp_millis() + 3_600_000),
+            token_endpoint: "https://auth.example.com/token".to_string(),
+            client_id: "client".to_string(),
+            client_secret: None,
+        };
+        store.set_mcp_oauth_token(url, &token).unwrap();
+        let fetched = store.get_mcp_oauth_token(url).unwrap().unwrap();
+        assert_eq!(fetched.access_token, "access");
+        assert_eq!(fetched.refresh_token.as_deref(), Some("refresh"));
+        assert!(store.get_mcp_oauth_token("https://mcp.example.com/sse").unwrap().is_none());
+
+        store.delete_mcp_oauth_token(url).unwrap();
+        assert!(store.get_mcp_oauth_token(url).unwrap().is_none());
+    }
 }
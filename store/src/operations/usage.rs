@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use bonsaidb::core::schema::SerializedView;
+
+use crate::Store;
+use crate::error::StoreError;
+use crate::models::{
+    DAY_MILLIS, SLOT_MILLIS, UsageBySlot, UsagePeriod, UsageQuery, UsageRow, UsageStats,
+};
+
+impl Store {
+    /// 按条件汇总助手回复的用量, 每行对应(时间, 模型提供商, 模型, 智能体)
+    ///
+    /// 时间按客户端时区的本地日期汇总, 没有记录智能体和模型的旧回复不在统计中
+    pub fn get_usage_stats(&self, query: &UsageQuery) -> Result<Vec<UsageRow>, StoreError> {
+        let offset = query.utc_offset * 60_000;
+        // 本地日期, 从 1970-01-01 开始的天数
+        let day = |time: i64| (time + offset).div_euclid(DAY_MILLIS);
+        // 本地日期零点所在的时段
+        let slot = |day: i64| ((day * DAY_MILLIS - offset).max(0) / SLOT_MILLIS) as u64;
+        let start = query.start.map_or(0, |time| slot(day(time)));
+        let end = query.end.map_or(u64::MAX, |time| slot(day(time) + 1));
+
+        let groups = UsageBySlot::entries(&self.db)
+            .with_key_range((start, 0, String::new(), 0)..(end, 0, String::new(), 0))
+            .reduce_grouped()
+            .map_err(|e| StoreError::Operator(format!("query usage stats {e}")))?;
+
+        let mut rows: BTreeMap<(i64, u64, String, u64), UsageStats> = BTreeMap::new();
+        for group in groups {
+            let (slot, provider, model, agent) = group.key;
+
+            if query.provider.is_some_and(|id| id != provider)
+                || query.model.as_ref().is_some_and(|name| *name != model)
+                || query.agent.is_some_and(|id| id != agent)
+            {
+                continue;
+            }
+
+            let day = day(slot as i64 * SLOT_MILLIS);
+            let period = match query.period {
+                UsagePeriod::Day => day,
+                // 1970-01-01 是周四, 对齐到所在周的周一
+                UsagePeriod::Week => (day + 3).div_euclid(7) * 7 - 3,
+            };
+
+            *rows.entry((period * DAY_MILLIS - offset, provider, model, agent)).or_default() +=
+                &group.value;
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|((period, provider, model, agent), stats)| UsageRow {
+                period,
+                provider,
+                model,
+                agent,
+                average_cost: stats.average_cost(),
+                error_rate: stats.error_rate(),
+                stats,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ChatInput,
+        models::{ChatMessage, ChatSession, MessageStatus, ProviderModel, ToolResult, ToolStatus},
+    };
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    /// 2025-06-02 周一 00:00 UTC
+    const MONDAY: i64 = 1_748_822_400_000;
+
+    fn reply(id: u64, agent: u64, model: &str, day: i64, status: MessageStatus) -> ChatMessage {
+        let mut message = ChatMessage::new_assistant(id, 1);
+        message.status = status;
+        message.agent_id = Some(agent);
+        message.model = Some(ProviderModel { id: 1, name: model.to_string() });
+        message.prompt_tokens = Some(100);
+        message.completion_tokens = Some(50);
+        message.total_tokens = Some(150);
+        message.fee = Some(0.01);
+        message.cost = Some(1000);
+        message.created_at = MONDAY + day * DAY_MILLIS + 3_600_000;
+        message
+    }
+
+    #[test]
+    fn test_usage_stats() {
+        // 创建临时测试目录
+        let temp_dir = tempdir().unwrap();
+        let store = Store::open(temp_dir.path()).unwrap();
+
+        let session = ChatSession {
+            id: 1,
+            agent_id: 10,
+            topic: "用量统计".to_string(),
+            topic_edited: false,
+            input: ChatInput::default(),
+            variables: HashMap::new(),
+            active_leaf: None,
+            forked_from: None,
+            created_at: 0,
+            updated_at: None,
+        };
+        store.add_chat_session(session).unwrap();
+
+        // 周一两条回复, 其中一条失败并调用了工具
+        store.add_chat_message(reply(1, 10, "gpt-4o", 0, MessageStatus::Success)).unwrap();
+        let mut failed = reply(2, 10, "gpt-4o", 0, MessageStatus::Failed);
+        failed.cost = Some(3000);
+        failed.tools = Some(vec![ToolResult {
+            id: "call_1".to_string(),
+            name: "search".to_string(),
+            arguments: "{}".to_string(),
+            result: String::new(),
+            status: ToolStatus::Failed,
+            error: Some("timeout".to_string()),
+            cost: Some(10),
+        }]);
+        store.add_chat_message(failed).unwrap();
+
+        // 周二另一个智能体的回复, 以及还没有结束和没有模型的消息不统计
+        store.add_chat_message(reply(3, 20, "gpt-4o", 1, MessageStatus::Success)).unwrap();
+        store.add_chat_message(reply(4, 10, "gpt-4o", 1, MessageStatus::Processing)).unwrap();
+        let mut unknown = reply(5, 10, "gpt-4o", 1, MessageStatus::Success);
+        unknown.model = None;
+        store.add_chat_message(unknown).unwrap();
+
+        let rows = store.get_usage_stats(&UsageQuery::default()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].period, MONDAY);
+        assert_eq!(rows[0].agent, 10);
+        assert_eq!(rows[0].stats.replies, 2);
+        assert_eq!(rows[0].stats.errors, 1);
+        assert_eq!(rows[0].stats.total_tokens, 300);
+        assert_eq!(rows[0].stats.tool_calls, 1);
+        assert_eq!(rows[0].stats.tool_errors, 1);
+        assert!((rows[0].stats.fee - 0.02).abs() < 1e-9);
+        assert_eq!(rows[0].average_cost, 2000.0);
+        assert_eq!(rows[0].error_rate, 0.5);
+        assert_eq!(rows[1].period, MONDAY + DAY_MILLIS);
+        assert_eq!(rows[1].agent, 20);
+
+        // 消息结束后统计随之更新
+        let mut processing = store.get_chat_message(4).unwrap().unwrap();
+        processing.status = MessageStatus::Success;
+        store.update_chat_message(processing).unwrap();
+
+        // 按周汇总并过滤智能体
+        let query = UsageQuery { agent: Some(10), period: UsagePeriod::Week, ..Default::default() };
+        let rows = store.get_usage_stats(&query).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].period, MONDAY);
+        assert_eq!(rows[0].stats.replies, 3);
+        assert_eq!(rows[0].stats.prompt_tokens, 300);
+
+        // 按客户端时区分天, UTC-2 时周一凌晨1点的回复属于本地的周日
+        let query = UsageQuery { utc_offset: -120, ..Default::default() };
+        let rows = store.get_usage_stats(&query).unwrap();
+        assert_eq!(rows[0].period, MONDAY - DAY_MILLIS + 7_200_000);
+        assert_eq!(rows[0].stats.replies, 2);
+
+        // 时间范围为本地的周一, 只包含周二凌晨的回复
+        let query = UsageQuery {
+            start: Some(MONDAY + DAY_MILLIS),
+            end: Some(MONDAY + DAY_MILLIS),
+            utc_offset: -120,
+            ..Default::default()
+        };
+        let rows = store.get_usage_stats(&query).unwrap();
+        assert!(rows.iter().all(|row| row.period == MONDAY + 7_200_000));
+        assert_eq!(rows.iter().map(|row| row.stats.replies).sum::<u64>(), 2);
+
+        // 时间范围只包含周二
+        let query = UsageQuery {
+            start: Some(MONDAY + DAY_MILLIS),
+            end: Some(MONDAY + DAY_MILLIS),
+            ..Default::default()
+        };
+        let rows = store.get_usage_stats(&query).unwrap();
+        assert_eq!(rows.iter().map(|row| row.stats.replies).sum::<u64>(), 2);
+
+        // 删除消息后不再统计
+        store.delete_chat_message(3).unwrap();
+        let query = UsageQuery { agent: Some(20), ..Default::default() };
+        assert!(store.get_usage_stats(&query).unwrap().is_empty());
+    }
+}
@@ -1,283 +1,439 @@
-use deno_core::*;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::Duration,
+};
+
+use deno_core::{
+    Extension, JsRuntime, OpDecl, OpState, PollEventLoopOptions, RuntimeOptions, op2, serde_v8, v8,
+};
+use deno_error::JsErrorBox;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// 默认执行超时
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 默认内存上限, 单位MB
+pub const DEFAULT_MEMORY_LIMIT: u64 = 128;
+/// 最多保留的日志行数
+const MAX_LOGS: usize = 1000;
+/// 超出内存上限后额外给出的堆空间, 只够终止执行时展开调用栈
+const OOM_HEADROOM: usize = 4 * 1024 * 1024;
+
+/// 提供 console 和 fetch, 在用户代码之前执行
+const BOOTSTRAP: &str = r#"
+((ops) => {
+  const format = (args) => args.map((arg) => {
+    if (typeof arg === 'string') return arg;
+    if (arg instanceof Error) return arg.stack || String(arg);
+    try {
+      return JSON.stringify(arg);
+    } catch {
+      return String(arg);
+    }
+  }).join(' ');
+
+  globalThis.console = Object.fromEntries(['log', 'info', 'debug', 'warn', 'error']
+    .map((level) => [level, (...args) => ops.op_console(level, format(args))]));
+
+  globalThis.fetch = async (input, init = {}) => {
+    const body = init.body === undefined || init.body === null || typeof init.body === 'string'
+      ? init.body ?? undefined
+      : JSON.stringify(init.body);
+    const response = await ops.op_fetch({
+      url: String(input),
+      method: init.method,
+      headers: init.headers,
+      body,
+    });
+    return {
+      ok: response.status >= 200 && response.status < 300,
+      status: response.status,
+      statusText: response.statusText,
+      url: response.url,
+      headers: response.headers,
+      text: async () => response.body,
+      json: async () => JSON.parse(response.body),
+    };
+  };
+})(Deno.core.ops);
+"#;
+
+/// JS 工具的运行限制
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    /// 执行超时, 包括等待 fetch 的时间
+    pub timeout: Duration,
+    /// 内存上限, 单位MB
+    pub memory_limit: u64,
+    /// 允许 fetch 访问的域名, 包括其子域名, `*` 允许所有域名
+    pub allow_hosts: Vec<String>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            allow_hosts: Vec::new(),
+        }
+    }
+}
+
+impl Sandbox {
+    /// 是否允许访问该地址
+    pub fn allows(&self, url: &reqwest::Url) -> bool {
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        self.allow_hosts.iter().any(|allow| {
+            let allow = allow.trim().trim_start_matches("*.").to_lowercase();
+            allow == "*" || host == allow || host.ends_with(&format!(".{allow}"))
+        })
+    }
+}
+
+/// 执行结果, 失败时也返回已经输出的日志
+#[derive(Debug)]
+pub struct JsOutput {
+    pub result: Result<serde_json::Value, Error>,
+    /// console 输出, warn 和 error 带级别前缀
+    pub logs: Vec<String>,
+}
 
-// 请求配置
 #[derive(Debug, Deserialize)]
-struct RequestConfig {
+struct FetchRequest {
     url: String,
     method: Option<String>,
-    headers: Option<serde_json::Value>,
+    headers: Option<HashMap<String, String>>,
     body: Option<String>,
-    timeout: Option<u64>, // 超时时间（毫秒）
 }
 
-// 响应结构
 #[derive(Debug, Serialize)]
-struct ResponseData {
-    ok: bool,
+struct FetchResponse {
     status: u16,
-    headers: serde_json::Value,
-    data: serde_json::Value,
+    #[serde(rename = "statusText")]
+    status_text: String,
+    url: String,
+    headers: HashMap<String, String>,
+    body: String,
 }
 
-// 错误响应
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-    ok: bool,
-    error: String,
-    status: Option<u16>,
+/// fetch 使用的客户端, 重定向也按白名单检查
+#[derive(Clone)]
+struct Fetcher {
+    sandbox: Arc<Sandbox>,
+    client: reqwest::Client,
 }
 
+#[derive(Default)]
+struct Logs(Vec<String>);
+
 #[op2(async)]
 #[serde]
 async fn op_fetch(
-    #[serde] config: RequestConfig,
-) -> Result<serde_json::Value, deno_error::JsErrorBox> {
-    // 创建请求客户端
-    let client = reqwest::Client::new();
-
-    // 构建请求
-    let mut request_builder = match config.method {
-        Some(method) => client.request(
-            reqwest::Method::from_bytes(method.as_bytes()).unwrap_or(reqwest::Method::GET),
-            &config.url,
-        ),
-        None => client.get(&config.url),
-    };
-
-    // 添加请求头
-    if let Some(headers) = config.headers {
-        if let Some(headers_obj) = headers.as_object() {
-            for (key, value) in headers_obj {
-                if let Some(value_str) = value.as_str() {
-                    request_builder = request_builder.header(key, value_str);
-                }
-            }
-        }
-    }
-
-    // 添加请求体
-    if let Some(body) = config.body {
-        request_builder = request_builder.body(body);
+    state: Rc<RefCell<OpState>>, #[serde] request: FetchRequest,
+) -> Result<FetchResponse, JsErrorBox> {
+    let fetcher = state.borrow().borrow::<Fetcher>().clone();
+
+    let url = reqwest::Url::parse(&request.url)
+        .map_err(|e| JsErrorBox::type_error(format!("Invalid URL {}: {e}", request.url)))?;
+    if !fetcher.sandbox.allows(&url) {
+        return Err(JsErrorBox::generic(format!("Network access to {url} is not allowed")));
     }
 
-    // 设置超时
-    let timeout = config.timeout.unwrap_or(30000);
-    request_builder = request_builder.timeout(std::time::Duration::from_millis(timeout));
+    let method = request.method.as_deref().unwrap_or("GET").to_uppercase();
+    let method = reqwest::Method::from_bytes(method.as_bytes())
+        .map_err(|e| JsErrorBox::type_error(format!("Invalid method {method}: {e}")))?;
 
-    // 发送请求
-    let response = match request_builder.send().await {
-        Ok(resp) => resp,
-        Err(e) => {
-            let error_resp =
-                ErrorResponse { ok: false, error: format!("请求失败: {}", e), status: None };
-            return Ok(serde_json::to_value(error_resp).unwrap());
-        }
-    };
+    let mut builder = fetcher.client.request(method, url);
+    for (name, value) in request.headers.unwrap_or_default() {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
 
-    // 获取响应状态和头信息
-    let status_code = response.status();
-    let status = response.status().as_u16();
+    let response = builder.send().await.map_err(|e| JsErrorBox::generic(e.to_string()))?;
+    let status = response.status();
+    let url = response.url().to_string();
     let headers = response
         .headers()
         .iter()
         .map(|(name, value)| {
-            (
-                name.as_str().to_string(),
-                serde_json::Value::String(value.to_str().unwrap_or("").to_string()),
-            )
+            (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned())
         })
-        .collect::<serde_json::Map<String, serde_json::Value>>();
-
-    // 获取响应数据
-    let data = match response.text().await {
-        Ok(text) => {
-            // 尝试解析为 JSON
-            serde_json::from_str(&text).unwrap_or_else(|_| {
-                // 如果不是 JSON，返回文本
-                serde_json::Value::String(text)
-            })
-        }
+        .collect();
+    let body = response.text().await.map_err(|e| JsErrorBox::generic(e.to_string()))?;
+
+    Ok(FetchResponse {
+        status: status.as_u16(),
+        status_text: status.canonical_reason().unwrap_or_default().to_string(),
+        url,
+        headers,
+        body,
+    })
+}
+
+#[op2(fast)]
+fn op_console(state: &mut OpState, #[string] level: &str, #[string] message: &str) {
+    let logs = &mut state.borrow_mut::<Logs>().0;
+    if logs.len() >= MAX_LOGS {
+        return;
+    }
+    logs.push(match level {
+        "warn" | "error" => format!("[{level}] {message}"),
+        _ => message.to_string(),
+    });
+}
+
+/// 在独立线程中执行 JS 工具代码, 以 params 调用其中的 `toolFunction`, 返回值作为结果
+///
+/// V8 运行时不能跨线程, 每次调用创建新的运行时, 互不影响
+pub async fn run(code: String, params: serde_json::Value, sandbox: Sandbox) -> JsOutput {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let output = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime.block_on(execute(code, params, sandbox)),
+            Err(e) => JsOutput { result: Err(Error::JavaScript(e.to_string())), logs: Vec::new() },
+        };
+        let _ = sender.send(output);
+    });
+
+    receiver.await.unwrap_or_else(|_| JsOutput {
+        result: Err(Error::JavaScript("JavaScript runtime exited unexpectedly".to_string())),
+        logs: Vec::new(),
+    })
+}
+
+async fn execute(code: String, params: serde_json::Value, sandbox: Sandbox) -> JsOutput {
+    const OP_FETCH: OpDecl = op_fetch();
+    const OP_CONSOLE: OpDecl = op_console();
+
+    let timeout = sandbox.timeout;
+    let memory_limit = sandbox.memory_limit;
+    let sandbox = Arc::new(sandbox);
+
+    let redirect = {
+        let sandbox = sandbox.clone();
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if sandbox.allows(attempt.url()) {
+                attempt.follow()
+            } else {
+                let error = format!("Network access to {} is not allowed", attempt.url());
+                attempt.error(error)
+            }
+        })
+    };
+    let client = match reqwest::Client::builder().redirect(redirect).timeout(timeout).build() {
+        Ok(client) => client,
         Err(e) => {
-            let error_resp = ErrorResponse {
-                ok: false,
-                error: format!("读取响应失败: {}", e),
-                status: Some(status),
-            };
-            return Ok(serde_json::to_value(error_resp).unwrap());
+            return JsOutput { result: Err(Error::JavaScript(e.to_string())), logs: Vec::new() };
         }
     };
 
-    // 构建响应
-    let response_data = ResponseData {
-        ok: status_code.is_success(),
-        status,
-        headers: serde_json::Value::Object(headers),
-        data,
+    let extension = Extension {
+        name: "causal_js",
+        ops: Cow::Borrowed(&[OP_FETCH, OP_CONSOLE]),
+        ..Default::default()
     };
+    let create_params =
+        v8::CreateParams::default().heap_limits(0, memory_limit as usize * 1024 * 1024);
+    let mut runtime = JsRuntime::new(RuntimeOptions {
+        extensions: vec![extension],
+        create_params: Some(create_params),
+        ..Default::default()
+    });
+    {
+        let state = runtime.op_state();
+        let mut state = state.borrow_mut();
+        state.put(Fetcher { sandbox, client });
+        state.put(Logs::default());
+    }
 
-    Ok(serde_json::to_value(response_data).unwrap())
-}
+    // 超出内存或时间时终止执行, 同步的死循环也能中断
+    let handle = runtime.v8_isolate().thread_safe_handle();
+    let out_of_memory = Arc::new(AtomicBool::new(false));
+    runtime.add_near_heap_limit_callback({
+        let handle = handle.clone();
+        let out_of_memory = out_of_memory.clone();
+        move |current, _initial| {
+            out_of_memory.store(true, Ordering::SeqCst);
+            handle.terminate_execution();
+            current + OOM_HEADROOM
+        }
+    });
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (finished, watchdog) = mpsc::channel::<()>();
+    std::thread::spawn({
+        let timed_out = timed_out.clone();
+        move || {
+            if watchdog.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                timed_out.store(true, Ordering::SeqCst);
+                handle.terminate_execution();
+            }
+        }
+    });
+
+    let result = tokio::time::timeout(timeout, evaluate(&mut runtime, code, &params))
+        .await
+        .unwrap_or_else(|_| {
+            timed_out.store(true, Ordering::SeqCst);
+            Err(Error::Timeout(timeout.as_millis() as u64))
+        });
+    drop(finished);
+
+    let result = if out_of_memory.load(Ordering::SeqCst) {
+        Err(Error::OutOfMemory(memory_limit))
+    } else if timed_out.load(Ordering::SeqCst) {
+        Err(Error::Timeout(timeout.as_millis() as u64))
+    } else {
+        result
+    };
 
-#[derive(Serialize)]
-struct User {
-    name: String,
-    age: u32,
+    let logs = runtime.op_state().borrow_mut().take::<Logs>().0;
+    JsOutput { result, logs }
 }
 
-// #[deno_core::op2]
-// async fn op_fetch(#[serde] url: String) -> Result<String, deno_core::error::AnyError> {
-//     let response = reqwest::get(&url).await?;
-//     let text = response.text().await?;
-//     Ok(text)
-// }
+async fn evaluate(
+    runtime: &mut JsRuntime, code: String, params: &serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    runtime.execute_script("<bootstrap>", BOOTSTRAP).map_err(js_error)?;
+    runtime.execute_script("<tool>", code).map_err(js_error)?;
+
+    // 兼容以 main 作为入口的代码
+    let entry = format!(
+        r#"(() => {{
+  const entry = typeof toolFunction === 'function' ? toolFunction
+    : typeof main === 'function' ? main : undefined;
+  if (!entry) throw new Error('toolFunction(params) is not defined');
+  return entry(JSON.parse({}));
+}})()"#,
+        serde_json::to_string(&params.to_string())?
+    );
+    let value = runtime.execute_script("<entry>", entry).map_err(js_error)?;
+
+    let value = runtime.resolve(value);
+    let value = runtime
+        .with_event_loop_promise(value, PollEventLoopOptions::default())
+        .await
+        .map_err(js_error)?;
 
-#[op2(fast)]
-fn op_log(state: &mut OpState, #[string] message: String) -> Result<(), deno_error::JsErrorBox> {
-    let handler = state.try_borrow::<Arc<String>>();
-
-    // 自定义日志输出格式
-    println!("[Rust Log] [{:?}] {}", handler, message);
-    Ok(())
+    let scope = &mut runtime.handle_scope();
+    let value = v8::Local::new(scope, value);
+    serde_v8::from_v8::<serde_json::Value>(scope, value).map_err(js_error)
 }
 
-#[op2(async)]
-async fn op_sum(
-    state: Rc<RefCell<OpState>>, #[serde] nums: Vec<f64>,
-) -> Result<f64, deno_error::JsErrorBox> {
-    let state = state.borrow();
-    let handler = state.try_borrow::<Arc<String>>();
-
-    println!("handle: {:?}", handler);
-
-    // Sum inputs
-    let sum = nums.iter().fold(0.0, |a, v| a + v);
-    // return as a Result<f64, OpError>
-    Ok(sum)
+fn js_error(e: impl std::fmt::Display) -> Error {
+    Error::JavaScript(e.to_string())
 }
 
-pub async fn js_run() -> Result<String, deno_error::JsErrorBox> {
-    const DECL: OpDecl = op_sum();
-    const DECL_OP_LOG: OpDecl = op_log();
-    const DECL_OP_FETCH: OpDecl = op_fetch();
-
-    let ext = Extension {
-        name: "my_ext",
-        ops: std::borrow::Cow::Borrowed(&[DECL, DECL_OP_LOG, DECL_OP_FETCH]),
-        middleware_fn: Some(Box::new(|op| {
-            // println!("op: {:?}", op.name);
-            match op.name {
-                "op_print" => op.disable(),
-                _ => op,
-            }
-        })),
-        op_state_fn: Some(Box::new(|state| {
-            let handler = Arc::new("hello".to_string());
-            state.put(handler);
-        })),
-        ..Default::default()
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut runtime =
-        JsRuntime::new(RuntimeOptions { extensions: vec![ext], ..Default::default() });
+    fn sandbox(timeout: Duration) -> Sandbox {
+        Sandbox { timeout, ..Default::default() }
+    }
 
-    let user = User { name: "Bob".to_string(), age: 42 };
-    {
-        let scope = &mut runtime.handle_scope();
+    #[tokio::test]
+    async fn test_run_tool_function() {
+        let code = r#"
+            async function toolFunction(params) {
+                console.log('sum', params.values);
+                console.warn({ count: params.values.length });
+                const sum = await Promise.resolve(params.values.reduce((a, b) => a + b, 0));
+                return { name: params.name, sum };
+            }
+        "#;
+        let params = serde_json::json!({ "name": "test", "values": [1, 2, 3] });
+        let output = run(code.to_string(), params, Sandbox::default()).await;
 
-        // 使用 serde_v8 序列化 Rust 结构体到 JS 对象
-        let key = v8::String::new(scope, "args").unwrap();
-        let value = serde_v8::to_v8(scope, user).unwrap();
+        assert_eq!(output.result.unwrap(), serde_json::json!({ "name": "test", "sum": 6 }));
+        assert_eq!(output.logs, vec!["sum [1,2,3]", "[warn] {\"count\":3}"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_errors() {
+        // 抛出的错误和之前的日志都返回
+        let code = "function toolFunction() { console.log('before'); throw new Error('boom'); }";
+        let output = run(code.to_string(), serde_json::json!({}), Sandbox::default()).await;
+        assert!(output.result.unwrap_err().to_string().contains("boom"));
+        assert_eq!(output.logs, vec!["before"]);
+
+        // 没有入口函数
+        let output =
+            run("const a = 1;".to_string(), serde_json::json!({}), Sandbox::default()).await;
+        assert!(output.result.unwrap_err().to_string().contains("toolFunction"));
+
+        // 同步死循环和永不完成的 Promise 都会超时
+        let code = "function toolFunction() { while (true) {} }";
+        let output =
+            run(code.to_string(), serde_json::json!({}), sandbox(Duration::from_millis(200))).await;
+        assert!(matches!(output.result, Err(Error::Timeout(200))));
+
+        let code = "function toolFunction() { return new Promise(() => {}); }";
+        let output =
+            run(code.to_string(), serde_json::json!({}), sandbox(Duration::from_millis(200))).await;
+        assert!(matches!(output.result, Err(Error::Timeout(200))));
+    }
 
-        let context = scope.get_current_context();
-        let global = context.global(scope);
-        global.set(scope, key.into(), value);
+    #[tokio::test]
+    async fn test_out_of_memory() {
+        // 持续分配直到超出内存上限, 终止执行而不是扩大堆
+        let code = r#"
+            function toolFunction() {
+                console.log('start');
+                const chunks = [];
+                while (true) chunks.push(new Array(1024 * 1024).fill(chunks.length));
+            }
+        "#;
+        let sandbox = Sandbox { memory_limit: 32, ..sandbox(Duration::from_secs(10)) };
+        let output = run(code.to_string(), serde_json::json!({}), sandbox).await;
+        assert!(matches!(output.result, Err(Error::OutOfMemory(32))));
+        assert_eq!(output.logs, vec!["start"]);
+
+        // 之后的执行不受影响
+        let code = "function toolFunction() { return 1; }";
+        let output = run(code.to_string(), serde_json::json!({}), Sandbox::default()).await;
+        assert_eq!(output.result.unwrap(), serde_json::json!(1));
     }
 
-    runtime
-        .execute_script(
-            "<SYSTEM>",
-            r#"
-            globalThis.fetch = async function(url, options = {}) {
-                const config = {
-                    url,
-                    method: options.method || 'GET',
-                    headers: options.headers,
-                    body: options.body,
-                    timeout: options.timeout,
-                };
-                
+    #[tokio::test]
+    async fn test_fetch_allowlist() {
+        let code = r#"
+            async function toolFunction(params) {
                 try {
-                    const response = await Deno.core.ops.op_fetch(config);
-                    if (!response.ok) {
-                        throw new Error(response.error || '请求失败');
-                    }
-                    return response;
+                    await fetch(params.url);
+                    return 'fetched';
                 } catch (error) {
-                    throw new Error(`请求异常: ${error.message}`);
+                    return error.message;
                 }
-            };
-            
-            console.log = function(...args) {
-                const message = args.map(arg => 
-                    typeof arg === 'object' ? JSON.stringify(arg) : String(arg)
-                ).join(' ');
-                Deno.core.ops.op_log(message);
-            };
-        "#,
-        )
-        .unwrap();
-
-    // 执行JavaScript
-    runtime
-        .execute_script(
-            "define_async_function.js",
-            r#"
-            async function main(args, args2) {
-
-                let ret = await Deno.core.ops.op_sum([1, 2, 3, 4, 5]);
-
-                const ressponse = await fetch('https://www.baidu.com', {
-                    timeout: 5000,
-                    headers: {
-                        'User-Agent': 'client',
-                    },
-                });
-                console.log('GET Response:', ressponse);
-
-                console.log('args', args);
-                console.log('args', args2);
-                return  ret;
             }
-
-            // Deno.core.print("I'm broken");
-            // console.log('args', `${args.age}`);
-            // main(11)
-            "#,
-        )
-        .unwrap();
-
-    let result_val =
-        runtime.execute_script("<execute>", r#"main(`${args.age}`, `${args.name}`)"#).unwrap();
-
-    runtime.run_event_loop(Default::default()).await.unwrap();
-
-    let scope = &mut runtime.handle_scope();
-
-    if result_val.open(scope).is_promise() {
-        let result_val = v8::Local::new(scope, result_val);
-        let result_val = v8::Local::<v8::Promise>::try_from(result_val).unwrap();
-        let result_val = result_val.result(scope);
-
-        let result_val = serde_v8::from_v8::<serde_json::Value>(scope, result_val).unwrap();
-        let result_val = serde_json::to_string(&result_val).unwrap();
-
-        println!("async Result: {}", result_val);
-        Ok(result_val)
-    } else {
-        let result_val = v8::Local::<v8::Value>::new(scope, result_val);
-        let result_val = serde_v8::from_v8::<serde_json::Value>(scope, result_val).unwrap();
-        let result_val = serde_json::to_string(&result_val).unwrap();
-        println!("sync Result: {}", result_val);
-        Ok(result_val)
+        "#;
+        let params = serde_json::json!({ "url": "http://127.0.0.1:9/" });
+        let output = run(code.to_string(), params, Sandbox::default()).await;
+        assert!(output.result.unwrap().as_str().unwrap().contains("not allowed"));
+
+        let sandbox =
+            Sandbox { allow_hosts: vec!["*.example.com".to_string()], ..Default::default() };
+        assert!(sandbox.allows(&"https://api.example.com/v1".parse().unwrap()));
+        assert!(sandbox.allows(&"https://example.com".parse().unwrap()));
+        assert!(!sandbox.allows(&"https://example.com.evil.net".parse().unwrap()));
+        assert!(!sandbox.allows(&"file:///etc/passwd".parse().unwrap()));
     }
 }
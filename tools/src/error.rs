@@ -8,4 +8,13 @@ pub enum Error {
 
     #[error("json error: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error("JavaScript error: {0}")]
+    JavaScript(String),
+
+    #[error("JavaScript timeout after {0} ms")]
+    Timeout(u64),
+
+    #[error("JavaScript memory limit of {0} MB exceeded")]
+    OutOfMemory(u64),
 }
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use mcp_core::transport::{ClientSseTransport, ClientSseTransportBuilder};
 use mcp_core::{client::ClientBuilder, protocol::RequestOptions};
 
+pub mod deno;
 mod error;
 pub use error::Error;
 